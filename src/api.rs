@@ -0,0 +1,210 @@
+//! A small HTTP API that lets other tools trigger generations. The results are posted
+//! to the configured channel.
+use crate::{
+    command::{GenerationOptions, GenerationParameters},
+    config::{ButtonProfiles, Configuration},
+    exilent::issuer,
+    store::Store,
+    util::{self, DiscordInteraction},
+};
+use anyhow::Context;
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    routing::post,
+    Json, Router,
+};
+use serenity::{
+    http::Http,
+    model::{prelude::ChannelId, user::User},
+};
+use stable_diffusion_a1111_webui_client as sd;
+use std::{net::SocketAddr, sync::Arc};
+
+#[derive(Clone)]
+pub struct ApiState {
+    pub http: Arc<Http>,
+    pub client: Arc<sd::Client>,
+    pub models: Arc<Vec<sd::Model>>,
+    pub store: Arc<Store>,
+}
+
+pub async fn serve(state: ApiState) -> anyhow::Result<()> {
+    let api = &Configuration::get().api;
+    let Some(address) = &api.address else {
+        return Ok(());
+    };
+    let address: SocketAddr = address.parse().context("invalid api.address")?;
+    if api.token.is_none() {
+        anyhow::bail!("api.token must be set to enable the API");
+    }
+    if api.channel_id.is_none() {
+        anyhow::bail!("api.channel_id must be set to enable the API");
+    }
+
+    let app = Router::new()
+        .route("/generate", post(generate))
+        .with_state(state);
+
+    tracing::info!("Serving the API on {address}");
+    axum::Server::bind(&address)
+        .serve(app.into_make_service())
+        .await?;
+
+    Ok(())
+}
+
+/// Accepts a JSON [GenerationOptions] and queues the generation, returning as soon as
+/// the request has been validated
+async fn generate(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Json(options): Json<GenerationOptions>,
+) -> (StatusCode, String) {
+    let api = &Configuration::get().api;
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    let authorized = match (token, api.token.as_deref()) {
+        (Some(token), Some(expected)) => constant_time_eq(token.as_bytes(), expected.as_bytes()),
+        _ => false,
+    };
+    if !authorized {
+        return (StatusCode::UNAUTHORIZED, "invalid token".to_string());
+    }
+
+    match queue_generation(state, options).await {
+        Ok(()) => (StatusCode::ACCEPTED, "generation queued".to_string()),
+        Err(err) => (StatusCode::BAD_REQUEST, err.to_string()),
+    }
+}
+
+/// Compares the tokens without short-circuiting, so that response times don't reveal how
+/// much of a guessed token was correct
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+async fn queue_generation(state: ApiState, options: GenerationOptions) -> anyhow::Result<()> {
+    util::require_not_shutting_down()?;
+    validate_options(&options)?;
+
+    let channel_id = ChannelId(
+        Configuration::get()
+            .api
+            .channel_id
+            .context("no API channel configured")?,
+    );
+    let guild_id = channel_id
+        .to_channel(&state.http)
+        .await?
+        .guild()
+        .context("the API channel must be in a server")?
+        .guild_id;
+    // API generations are attributed to the bot itself
+    let user = User::from(state.http.get_current_user().await?);
+
+    let params = GenerationParameters::from_options(
+        user.id,
+        guild_id,
+        options,
+        &state.store,
+        &state.models,
+        true,
+        true,
+    )
+    .await?;
+
+    let interaction = util::ChannelInteraction::new(channel_id, guild_id, user);
+    interaction
+        .create(&state.http, "API request received, processing...")
+        .await?;
+
+    tokio::spawn(async move {
+        let ApiState {
+            http,
+            client,
+            models,
+            store,
+        } = &state;
+        let base = params.base_generation();
+        util::run_and_report_error(&interaction, http, async {
+            issuer::generation_task(
+                (client, models),
+                util::spawn_generation(params.generate(client)),
+                store,
+                http,
+                (&interaction, None, ButtonProfiles::DEFAULT),
+                (&base.prompt, base.negative_prompt.as_deref()),
+                (
+                    params.image_generation(),
+                    base.eta,
+                    (base.denoising_strength, params.hires_denoising_strength()),
+                    None,
+                ),
+            )
+            .await?;
+            Ok(())
+        })
+        .await;
+    });
+
+    Ok(())
+}
+
+/// Discord enforces the limits for commands, but API requests need to be checked here
+fn validate_options(options: &GenerationOptions) -> anyhow::Result<()> {
+    let limits = &Configuration::get().limits;
+    fn check<T: PartialOrd + std::fmt::Display>(
+        name: &str,
+        value: Option<T>,
+        min: T,
+        max: T,
+    ) -> anyhow::Result<()> {
+        match value {
+            Some(value) if value < min || value > max => {
+                anyhow::bail!("{name} must be between {min} and {max}")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    check(
+        "count",
+        options.count.map(|v| v as usize),
+        limits.count_min,
+        limits.count_max,
+    )?;
+    check("width", options.width, limits.width_min, limits.width_max)?;
+    check(
+        "height",
+        options.height,
+        limits.height_min,
+        limits.height_max,
+    )?;
+    check(
+        "guidance_scale",
+        options.guidance_scale,
+        limits.guidance_scale_min,
+        limits.guidance_scale_max,
+    )?;
+    check(
+        "steps",
+        options.steps.map(|v| v as usize),
+        limits.steps_min,
+        limits.steps_max,
+    )?;
+    check("seed", options.seed, -1, i64::MAX)?;
+    check("denoising_strength", options.denoising_strength, 0.0, 1.0)?;
+    check(
+        "hires_denoising_strength",
+        options.hires_denoising_strength,
+        0.0,
+        1.0,
+    )?;
+    check("eta", options.eta, 0.0, 1.0)?;
+    check("lora_weight", options.lora_weight, -2.0, 2.0)?;
+
+    Ok(())
+}
@@ -1,4 +1,4 @@
-use std::pin::Pin;
+use std::{collections::HashMap, fmt::Display, pin::Pin};
 
 use crate::{
     config::Configuration,
@@ -6,6 +6,7 @@ use crate::{
     store::{self, Store},
     util,
 };
+use anyhow::Context;
 use futures::Future;
 use itertools::Itertools;
 use serenity::{
@@ -17,6 +18,53 @@ use serenity::{
 };
 use stable_diffusion_a1111_webui_client as sd;
 
+/// Discord allows at most 25 options per command or subcommand, so with enough models, the
+/// model categories added by [populate_generate_options] can push a command over the limit and
+/// fail its registration. In that case, the categories are swapped for a single model option
+/// that's filled in by autocomplete. Call this with the command's options once it's been built.
+pub fn fit_model_options(options: Option<&mut serde_json::Value>) {
+    const MAX_OPTIONS: usize = 25;
+
+    let Some(options) = options.and_then(|o| o.as_array_mut()) else {
+        return;
+    };
+    if options.len() <= MAX_OPTIONS {
+        return;
+    }
+
+    // `model`, `model2`, `model3`, ...
+    let is_model_option = |option: &serde_json::Value| {
+        matches!(
+            option
+                .get("name")
+                .and_then(|n| n.as_str())
+                .and_then(|n| n.strip_prefix(constant::value::MODEL)),
+            Some(suffix) if suffix.chars().all(|c| c.is_ascii_digit())
+        )
+    };
+    let Some(position) = options.iter().position(is_model_option) else {
+        return;
+    };
+    options.retain(|o| !is_model_option(o));
+    options.insert(position, {
+        let mut opt = CreateApplicationCommandOption::default();
+        opt.name(constant::value::MODEL)
+            .description("The model to use (start typing to search)")
+            .kind(CommandOptionType::String)
+            .set_autocomplete(true)
+            .required(false);
+        serenity::json::hashmap_to_json_map(opt.0).into()
+    });
+    tracing::warn!("too many models to list as choices; falling back to autocomplete for models");
+
+    if options.len() > MAX_OPTIONS {
+        tracing::error!(
+            "a command has {} options, more than Discord's limit of {MAX_OPTIONS}; its registration will fail",
+            options.len()
+        );
+    }
+}
+
 pub fn populate_generate_options(
     mut add_option: impl FnMut(CreateApplicationCommandOption),
     models: &[sd::Model],
@@ -28,9 +76,12 @@ pub fn populate_generate_options(
         add_option({
             let mut opt = CreateApplicationCommandOption::default();
             opt.name(constant::value::PROMPT)
-                .description("The prompt to draw")
+                .description(
+                    "The prompt to draw (if left out with an image, one is derived from the image)",
+                )
                 .kind(CommandOptionType::String)
-                .required(true);
+                .set_autocomplete(true)
+                .required(false);
             opt
         });
     }
@@ -45,8 +96,11 @@ pub fn populate_generate_options(
     add_option({
         let mut opt = CreateApplicationCommandOption::default();
         opt.name(constant::value::SEED)
-            .description("The seed to use")
+            .description(
+                "The seed to use; leave out or use -1 for a fresh random seed (never inherited)",
+            )
             .kind(CommandOptionType::Integer)
+            .min_int_value(-1)
             .required(false);
         opt
     });
@@ -80,6 +134,20 @@ pub fn populate_generate_options(
             .required(false);
         opt
     });
+    // Wirehead doesn't get this to stay within Discord's option limit, so its images are
+    // sized by width and height alone.
+    if with_prompt {
+        add_option({
+            let mut opt = CreateApplicationCommandOption::default();
+            opt.name(constant::value::MEGAPIXELS)
+                .description("The area of the image, with the width and height only setting its aspect ratio")
+                .kind(CommandOptionType::Number)
+                .min_number_value(0.1)
+                .max_number_value(limits.megapixels_max())
+                .required(false);
+            opt
+        });
+    }
     add_option({
         let mut opt = CreateApplicationCommandOption::default();
         opt.name(constant::value::GUIDANCE_SCALE)
@@ -100,14 +168,37 @@ pub fn populate_generate_options(
             .required(false);
         opt
     });
-    add_option({
-        let mut opt = CreateApplicationCommandOption::default();
-        opt.name(constant::value::TILING)
-            .description("Whether or not the image should be tiled at the edges")
-            .kind(CommandOptionType::Boolean)
-            .required(false);
-        opt
-    });
+    // Wirehead doesn't get this to stay within Discord's option limit; its steps and face
+    // restoration can be set directly instead.
+    if with_prompt {
+        add_option({
+            let mut opt = CreateApplicationCommandOption::default();
+            opt.name(constant::value::QUALITY)
+                .description(
+                    "The quality preset to use; explicitly-specified steps take precedence",
+                )
+                .kind(CommandOptionType::String)
+                .required(false);
+
+            for name in Configuration::get().quality_presets.0.keys().sorted() {
+                opt.add_string_choice(name, name);
+            }
+
+            opt
+        });
+    }
+    // Wirehead doesn't get this to stay within Discord's option limit; it still uses the
+    // tiling of the user's last generation.
+    if with_prompt {
+        add_option({
+            let mut opt = CreateApplicationCommandOption::default();
+            opt.name(constant::value::TILING)
+                .description("Whether or not the image should be tiled at the edges")
+                .kind(CommandOptionType::Boolean)
+                .required(false);
+            opt
+        });
+    }
     add_option({
         let mut opt = CreateApplicationCommandOption::default();
         opt.name(constant::value::RESTORE_FACES)
@@ -119,13 +210,27 @@ pub fn populate_generate_options(
     add_option({
         let mut opt = CreateApplicationCommandOption::default();
         opt.name(constant::value::DENOISING_STRENGTH)
-            .description("The amount of denoising to apply (0 is no change, 1 is complete remake)")
+            .description("How much to change the image being painted over (0 is no change, 1 is complete remake)")
             .kind(CommandOptionType::Number)
             .min_number_value(0.0)
             .max_number_value(1.0)
             .required(false);
         opt
     });
+    // Wirehead doesn't get this to stay within Discord's option limit; it still uses the
+    // eta of the user's last generation.
+    if with_prompt {
+        add_option({
+            let mut opt = CreateApplicationCommandOption::default();
+            opt.name(constant::value::ETA)
+                .description("The amount of noise added by ancestral samplers (0 is deterministic)")
+                .kind(CommandOptionType::Number)
+                .min_number_value(0.0)
+                .max_number_value(1.0)
+                .required(false);
+            opt
+        });
+    }
     add_option({
         let mut opt = CreateApplicationCommandOption::default();
         opt.name(constant::value::SAMPLER)
@@ -139,8 +244,53 @@ pub fn populate_generate_options(
 
         opt
     });
+    // Wirehead is already close to Discord's option limit, so it doesn't get these;
+    // LoRAs can be added to its prefix instead.
+    if with_prompt {
+        add_option({
+            let mut opt = CreateApplicationCommandOption::default();
+            opt.name(constant::value::LORA)
+                .description("The LoRAs to apply, separated by commas (use `name:weight` to set a weight for one)")
+                .kind(CommandOptionType::String)
+                .set_autocomplete(true)
+                .required(false);
+            opt
+        });
+        add_option({
+            let mut opt = CreateApplicationCommandOption::default();
+            opt.name(constant::value::LORA_WEIGHT)
+                .description("The weight to apply the LoRAs with (defaults to 1)")
+                .kind(CommandOptionType::Number)
+                .min_number_value(-2.0)
+                .max_number_value(2.0)
+                .required(false);
+            opt
+        });
+    }
 
-    for (idx, chunk) in models
+    // the random choice goes after the models so that their categories are unaffected. It's
+    // only offered if there's room for it in the last category, as a category of its own
+    // could push the command over Discord's option limit. The model autocomplete and the API
+    // accept it regardless.
+    let random_choice = (!models
+        .len()
+        .is_multiple_of(constant::value::MODEL_CHUNK_COUNT))
+    .then_some((
+        "Random (weighted by your usage)",
+        constant::value::MODEL_RANDOM,
+    ));
+    let model_choices: Vec<(&str, &str)> = models
+        .iter()
+        .map(|model| {
+            let hash_short = match model.hash_short.as_ref() {
+                Some(hash) => hash,
+                None => panic!("The model '{}' was found without a hash while adding options; this shouldn't be possible. Please report the issue!", model.name),
+            };
+            (model.name.as_str(), hash_short.as_str())
+        })
+        .chain(random_choice)
+        .collect();
+    for (idx, chunk) in model_choices
         .chunks(constant::value::MODEL_CHUNK_COUNT)
         .enumerate()
     {
@@ -156,12 +306,8 @@ pub fn populate_generate_options(
             .kind(CommandOptionType::String)
             .required(false);
 
-            for model in chunk {
-                let hash_short = match model.hash_short.as_ref() {
-                    Some(hash) => hash,
-                    None => panic!("The model '{}' was found without a hash while adding options; this shouldn't be possible. Please report the issue!", model.name),
-                };
-                opt.add_string_choice(&model.name, hash_short);
+            for (name, value) in chunk {
+                opt.add_string_choice(name, value);
             }
 
             opt
@@ -171,7 +317,7 @@ pub fn populate_generate_options(
     add_option({
         let mut opt = CreateApplicationCommandOption::default();
         opt.name(constant::value::IMAGE_URL)
-            .description("The URL of the image to paint over")
+            .description("The URL of the image to paint over, or the ID of an image used before")
             .kind(CommandOptionType::String);
         opt
     });
@@ -194,13 +340,153 @@ pub fn populate_generate_options(
 
         opt
     });
+    if with_prompt {
+        add_option({
+            let mut opt = CreateApplicationCommandOption::default();
+            opt.name(constant::value::HIRES_DENOISING_STRENGTH)
+                .description(
+                    "The amount of denoising for hires fix's upscaling pass (text-to-image only)",
+                )
+                .kind(CommandOptionType::Number)
+                .min_number_value(0.0)
+                .max_number_value(1.0)
+                .required(false);
+            opt
+        });
+        add_option({
+            let mut opt = CreateApplicationCommandOption::default();
+            opt.name(constant::value::OUTPAINT_DIRECTION)
+                .description(
+                    "Extend the image in this direction to fill the requested width/height",
+                )
+                .kind(CommandOptionType::String);
+
+            for value in util::OUTPAINT_DIRECTIONS {
+                opt.add_string_choice(value, value);
+            }
+
+            opt
+        });
+    }
 }
 
 #[derive(Clone)]
 pub enum GenerationParameters {
-    Text(sd::TextToImageGenerationRequest),
+    /// The request and, if hires fix is enabled, the denoising strength of its upscaling pass
+    Text(sd::TextToImageGenerationRequest, Option<f32>),
     Image(sd::ImageToImageGenerationRequest, String),
 }
+/// The values specified for a generation, before any defaults are applied.
+///
+/// These are usually read from command options, but can also be deserialized from
+/// other sources (e.g. the API).
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GenerationOptions {
+    pub prompt: Option<String>,
+    pub negative_prompt: Option<String>,
+    pub seed: Option<i64>,
+    pub count: Option<u32>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub guidance_scale: Option<f64>,
+    pub denoising_strength: Option<f64>,
+    pub hires_denoising_strength: Option<f64>,
+    pub eta: Option<f64>,
+    pub quality: Option<String>,
+    pub steps: Option<u32>,
+    pub tiling: Option<bool>,
+    pub restore_faces: Option<bool>,
+    pub sampler: Option<String>,
+    /// the hash of the model to use
+    pub model: Option<String>,
+    pub lora: Option<String>,
+    pub lora_weight: Option<f64>,
+    pub image_url: Option<String>,
+    pub resize_mode: Option<String>,
+    pub outpaint_direction: Option<String>,
+    /// the area to generate at, in megapixels, overriding the width and height while
+    /// keeping their aspect ratio
+    pub megapixels: Option<f64>,
+    /// whether to skip prepending the model's keyword, regardless of the configuration
+    pub no_auto_keyword: Option<bool>,
+}
+impl GenerationOptions {
+    pub fn from_command_options(
+        options: &[CommandDataOption],
+        models: &[sd::Model],
+    ) -> anyhow::Result<Self> {
+        use util::{
+            get_value, get_values_starting_with, value_to_bool, value_to_int, value_to_number,
+            value_to_string,
+        };
+
+        let model_params: Vec<_> = get_values_starting_with(options, constant::value::MODEL)
+            .flat_map(value_to_string)
+            .collect();
+        if model_params.len() > 1 {
+            anyhow::bail!(
+                "More than one model was specified: {}",
+                model_params
+                    .iter()
+                    .map(|hash| util::model_hash_to_name(models, hash.as_str()))
+                    .join(", ")
+            );
+        }
+
+        Ok(Self {
+            prompt: get_value(options, constant::value::PROMPT).and_then(value_to_string),
+            negative_prompt: get_value(options, constant::value::NEGATIVE_PROMPT)
+                .and_then(value_to_string),
+            seed: get_value(options, constant::value::SEED).and_then(value_to_int),
+            count: get_value(options, constant::value::COUNT)
+                .and_then(value_to_int)
+                .map(|v| v as u32),
+            width: get_value(options, constant::value::WIDTH)
+                .and_then(value_to_int)
+                .map(|v| v as u32),
+            height: get_value(options, constant::value::HEIGHT)
+                .and_then(value_to_int)
+                .map(|v| v as u32),
+            guidance_scale: get_value(options, constant::value::GUIDANCE_SCALE)
+                .and_then(value_to_number),
+            denoising_strength: get_value(options, constant::value::DENOISING_STRENGTH)
+                .and_then(value_to_number),
+            hires_denoising_strength: get_value(options, constant::value::HIRES_DENOISING_STRENGTH)
+                .and_then(value_to_number),
+            eta: get_value(options, constant::value::ETA).and_then(value_to_number),
+            quality: get_value(options, constant::value::QUALITY).and_then(value_to_string),
+            steps: get_value(options, constant::value::STEPS)
+                .and_then(value_to_int)
+                .map(|v| v as u32),
+            tiling: get_value(options, constant::value::TILING).and_then(value_to_bool),
+            restore_faces: get_value(options, constant::value::RESTORE_FACES)
+                .and_then(value_to_bool),
+            sampler: get_value(options, constant::value::SAMPLER).and_then(value_to_string),
+            model: model_params.into_iter().next(),
+            lora: get_value(options, constant::value::LORA).and_then(value_to_string),
+            lora_weight: get_value(options, constant::value::LORA_WEIGHT).and_then(value_to_number),
+            image_url: util::get_image_url(options),
+            resize_mode: get_value(options, constant::value::RESIZE_MODE).and_then(value_to_string),
+            outpaint_direction: get_value(options, constant::value::OUTPAINT_DIRECTION)
+                .and_then(value_to_string),
+            megapixels: get_value(options, constant::value::MEGAPIXELS).and_then(value_to_number),
+            no_auto_keyword: get_value(options, constant::value::NO_AUTO_KEYWORD)
+                .and_then(value_to_bool),
+        })
+    }
+
+    /// The number of images these options ask for, falling back to the configured default
+    pub fn batch_count(&self) -> u32 {
+        let limits = &Configuration::get().limits;
+        self.count.unwrap_or(
+            limits
+                .default_count
+                .clamp(limits.count_min, limits.count_max) as u32,
+        )
+    }
+}
+
 impl GenerationParameters {
     pub async fn load(
         user_id: UserId,
@@ -211,98 +497,186 @@ impl GenerationParameters {
         use_last_generation_for_size: bool,
         enforce_prompt: bool,
     ) -> anyhow::Result<GenerationParameters> {
-        use util::{
-            find_model_by_hash, get_value, get_values_starting_with, value_to_bool, value_to_int,
-            value_to_number, value_to_string,
-        };
+        Self::from_options(
+            user_id,
+            guild_id,
+            GenerationOptions::from_command_options(options, models)?,
+            store,
+            models,
+            use_last_generation_for_size,
+            enforce_prompt,
+        )
+        .await
+    }
 
-        let prompt = get_value(options, constant::value::PROMPT).and_then(value_to_string);
-        let prompt = if let Some(prompt) = prompt {
+    pub async fn from_options(
+        user_id: UserId,
+        guild_id: GuildId,
+        options: GenerationOptions,
+        store: &Store,
+        models: &[sd::Model],
+        use_last_generation_for_size: bool,
+        enforce_prompt: bool,
+    ) -> anyhow::Result<GenerationParameters> {
+        let batch_count = Some(options.batch_count());
+        let prompt = if let Some(prompt) = options.prompt {
             prompt
         } else if enforce_prompt {
-            anyhow::bail!("expected prompt");
+            anyhow::bail!("A prompt is required.");
         } else {
             String::new()
         };
 
-        let negative_prompt =
-            get_value(options, constant::value::NEGATIVE_PROMPT).and_then(value_to_string);
+        let negative_prompt = options.negative_prompt;
 
-        let seed = get_value(options, constant::value::SEED).and_then(value_to_int);
+        let general = &Configuration::get().general;
+        util::check_banned_terms(user_id, &prompt)?;
+        if let Some(negative_prompt) = &negative_prompt {
+            util::check_banned_terms(user_id, negative_prompt)?;
+        }
+        let (prompt, negative_prompt) = if general.normalize_prompt {
+            let normalize = |prompt: &str| {
+                if general.lowercase_prompt {
+                    util::normalize_prompt(&prompt.to_lowercase())
+                } else {
+                    util::normalize_prompt(prompt)
+                }
+            };
+            (
+                normalize(&prompt),
+                negative_prompt.as_deref().map(normalize),
+            )
+        } else {
+            (prompt, negative_prompt)
+        };
 
-        let batch_count = get_value(options, constant::value::COUNT)
-            .and_then(value_to_int)
-            .map(|v| v as u32);
+        // The LoRAs are injected into the prompt itself so that they're stored with it
+        // and reproduced on retry.
+        let prompt = match options.lora {
+            Some(loras) => util::inject_loras(&prompt, &loras, options.lora_weight.unwrap_or(1.0))?,
+            None => prompt,
+        };
+
+        // like the LoRAs, the server's affixes are stored with the prompt so that retries
+        // reproduce them
+        let prompt = util::wrap_prompt(
+            &prompt,
+            general.prompt_prefix.as_deref(),
+            general.prompt_suffix.as_deref(),
+        );
+        let negative_prompt = Some(util::wrap_prompt(
+            negative_prompt.as_deref().unwrap_or_default(),
+            general.negative_prompt_prefix.as_deref(),
+            general.negative_prompt_suffix.as_deref(),
+        ))
+        .filter(|p| !p.is_empty());
+
+        // seeds are never carried over from past generations; a negative seed is the web UI's
+        // way of asking for a random one, so it's treated the same as not specifying one
+        let seed = options.seed.filter(|seed| *seed >= 0);
 
         let last_generation = store.get_last_generation_for_user(user_id, guild_id)?;
         let last_generation = last_generation.as_ref();
+        let limits = &Configuration::get().limits;
 
-        let mut width = get_value(options, constant::value::WIDTH)
-            .and_then(value_to_int)
-            .map(|v| v as u32 / 64 * 64);
-
-        let mut height = get_value(options, constant::value::HEIGHT)
-            .and_then(value_to_int)
-            .map(|v| v as u32 / 64 * 64);
+        let mut width = options.width.map(|v| v / 64 * 64);
+        let mut height = options.height.map(|v| v / 64 * 64);
 
         if use_last_generation_for_size {
             width = width.or_else(|| last_generation.map(|g| g.width));
             height = height.or_else(|| last_generation.map(|g| g.height));
         }
 
-        let cfg_scale = get_value(options, constant::value::GUIDANCE_SCALE)
-            .and_then(value_to_number)
+        let cfg_scale = options
+            .guidance_scale
             .map(|v| v as f32)
-            .or_else(|| last_generation.map(|g| g.cfg_scale));
+            .or_else(|| last_generation.map(|g| g.cfg_scale))
+            .or(Some(limits.default_cfg_scale));
+
+        // only image generations have a meaningful strength to carry over
+        let denoising_strength = options.denoising_strength.map(|v| v as f32).or_else(|| {
+            last_generation
+                .filter(|g| g.image_generation.is_some())
+                .map(|g| g.denoising_strength)
+        });
 
-        let denoising_strength = get_value(options, constant::value::DENOISING_STRENGTH)
-            .and_then(value_to_number)
+        let hires_denoising_strength = options
+            .hires_denoising_strength
             .map(|v| v as f32)
-            .or_else(|| last_generation.map(|g| g.denoising_strength));
+            .or_else(|| last_generation.and_then(|g| g.hires_denoising_strength))
+            .unwrap_or(limits.default_hires_denoising_strength);
 
-        let steps = get_value(options, constant::value::STEPS)
-            .and_then(value_to_int)
-            .map(|v| v as u32)
-            .or_else(|| last_generation.map(|g| g.steps));
+        let eta = options
+            .eta
+            .map(|v| v as f32)
+            .or_else(|| last_generation.and_then(|g| g.eta));
+
+        let quality_preset = options
+            .quality
+            .map(|name| {
+                Configuration::get()
+                    .quality_presets
+                    .0
+                    .get(&name)
+                    .cloned()
+                    .with_context(|| format!("unknown quality preset `{name}`"))
+            })
+            .transpose()?;
+
+        // presets and API requests aren't held to the command option's maximum
+        let megapixels = options
+            .megapixels
+            .or_else(|| quality_preset.as_ref()?.megapixels);
+        if matches!(megapixels, Some(mp) if !(mp.is_finite() && mp > 0.0)) {
+            anyhow::bail!("The megapixel target must be positive.");
+        }
+        let megapixels = megapixels.map(|mp| mp.min(limits.megapixels_max()));
+
+        // presets scale the default step count rather than the last generation's, so that
+        // using the same preset repeatedly doesn't compound
+        let steps = options.steps.or_else(|| {
+            Some(match &quality_preset {
+                Some(preset) => preset.apply_to_steps(limits.default_steps),
+                None => last_generation.map_or(limits.default_steps, |g| g.steps),
+            })
+        });
 
-        let tiling = get_value(options, constant::value::TILING)
-            .and_then(value_to_bool)
-            .or_else(|| last_generation.map(|g| g.tiling));
+        let tiling = options.tiling.or_else(|| last_generation.map(|g| g.tiling));
 
-        let restore_faces = get_value(options, constant::value::RESTORE_FACES)
-            .and_then(value_to_bool)
+        let restore_faces = options
+            .restore_faces
+            .or_else(|| quality_preset.as_ref()?.restore_faces)
             .or_else(|| last_generation.map(|g| g.restore_faces));
 
-        let sampler = get_value(options, constant::value::SAMPLER)
-            .and_then(value_to_string)
+        let sampler = options
+            .sampler
             .and_then(|v| sd::Sampler::try_from(v.as_str()).ok())
-            .or_else(|| last_generation.map(|g| g.sampler));
+            .or_else(|| last_generation.map(|g| g.sampler))
+            .or_else(|| {
+                let config = Configuration::get();
+                sd::Sampler::try_from(config.general.default_sampler.as_deref()?).ok()
+            });
 
         let model = {
-            let model_params: Vec<_> = get_values_starting_with(options, constant::value::MODEL)
-                .flat_map(value_to_string)
-                .collect();
-            if model_params.len() > 1 {
-                anyhow::bail!(
-                    "More than one model was specified: {}",
-                    model_params
-                        .iter()
-                        .map(|hash| util::model_hash_to_name(models, hash.as_str()))
-                        .join(", ")
-                );
-            }
-
-            let model_hash = model_params
-                .first()
-                .or_else(|| last_generation.map(|g| &g.model_hash));
-
-            let model = model_hash.and_then(|hash| Some(find_model_by_hash(models, hash)?.1));
+            let config = Configuration::get();
+            let model_hash = match options.model {
+                Some(hash) if hash == constant::value::MODEL_RANDOM => {
+                    Some(random_model_hash(store, user_id, guild_id, models)?)
+                }
+                hash => hash
+                    .or_else(|| last_generation.map(|g| g.model_hash.clone()))
+                    .or_else(|| config.general.default_model_hash.clone()),
+            };
+
+            let model =
+                model_hash.and_then(|hash| Some(util::find_model_by_hash(models, &hash)?.1));
             match model {
                 Some(model) => model,
                 None => anyhow::bail!("No model was specified for this request, and you have no past generations to draw upon for a choice of model. Please try again with a model specified."),
             }
         };
 
+        let prepend_keyword = !options.no_auto_keyword.unwrap_or(false);
         let mut base = sd::BaseGenerationRequest {
             prompt,
             negative_prompt,
@@ -313,6 +687,7 @@ impl GenerationParameters {
             height,
             cfg_scale,
             denoising_strength,
+            eta,
             steps,
             tiling,
             restore_faces,
@@ -321,12 +696,10 @@ impl GenerationParameters {
             ..Default::default()
         };
 
-        let url = util::get_image_url(options);
-        let params = if let Some(url) = url {
-            let bytes = reqwest::get(&url).await?.bytes().await?;
-            let image = image::load_from_memory(&bytes)?;
-            let resize_mode = util::get_value(options, constant::value::RESIZE_MODE)
-                .and_then(util::value_to_string)
+        let params = if let Some(url) = options.image_url {
+            let (_, url, image) = util::resolve_upload(store, user_id, guild_id, &url).await?;
+            let resize_mode = options
+                .resize_mode
                 .and_then(|s| sd::ResizeMode::try_from(s.as_str()).ok())
                 .unwrap_or_default();
 
@@ -337,28 +710,89 @@ impl GenerationParameters {
                 base.height = Some(image.height());
             }
 
-            util::fixup_base_generation_request(&mut base);
+            apply_megapixels(&mut base, megapixels);
+            util::fixup_base_generation_request(&mut base, prepend_keyword);
+
+            // when outpainting, the image is padded out to the requested size and only the
+            // new region is painted in
+            let (image, mask, resize_mode) = match options.outpaint_direction {
+                Some(direction) => {
+                    let size = base
+                        .width
+                        .zip(base.height)
+                        .context("no size for outpainting")?;
+                    let (image, mask) = util::pad_for_outpainting(&image, size, &direction)?;
+                    (image, Some(mask), sd::ResizeMode::Resize)
+                }
+                None => (image, None, resize_mode),
+            };
 
             Self::Image(
                 sd::ImageToImageGenerationRequest {
                     base,
                     images: vec![image],
                     resize_mode: Some(resize_mode),
+                    inpainting_fill_mode: mask.as_ref().map(|_| sd::InpaintingFillMode::Fill),
+                    mask,
                     ..Default::default()
                 },
                 url,
             )
         } else {
-            util::fixup_base_generation_request(&mut base);
-            Self::Text(sd::TextToImageGenerationRequest {
-                base,
-                ..Default::default()
-            })
+            if options.outpaint_direction.is_some() {
+                anyhow::bail!(
+                    "`{}` requires an image to outpaint.",
+                    constant::value::OUTPAINT_DIRECTION
+                );
+            }
+            base.width = base.width.or(Some(limits.default_width));
+            base.height = base.height.or(Some(limits.default_height));
+            // the denoising strength only applies to image generations; hires fix has its own
+            base.denoising_strength = None;
+            apply_megapixels(&mut base, megapixels);
+            util::fixup_base_generation_request(&mut base, prepend_keyword);
+            let enable_hr = quality_preset.and_then(|p| p.hires_fix);
+            Self::Text(
+                sd::TextToImageGenerationRequest {
+                    base,
+                    enable_hr,
+                    ..Default::default()
+                },
+                enable_hr
+                    .unwrap_or(false)
+                    .then_some(hires_denoising_strength),
+            )
         };
 
         Ok(params)
     }
 
+    /// A note to show if a denoising strength was requested for a generation without an image,
+    /// where it has no effect
+    pub fn denoising_strength_note(options: &GenerationOptions) -> Option<String> {
+        (options.denoising_strength.is_some() && options.image_url.is_none()).then(|| {
+            format!(
+                "`{}` only applies when painting over an image, so it was ignored. For hires fix, use `{}` instead.",
+                constant::value::DENOISING_STRENGTH,
+                constant::value::HIRES_DENOISING_STRENGTH,
+            )
+        })
+    }
+
+    /// A warning to show if the requested resolution doesn't suit the model
+    pub fn resolution_warning(&self) -> Option<String> {
+        let base = self.base_generation();
+        util::resolution_warning(base.model.as_ref()?, base.width?, base.height?)
+    }
+
+    /// The denoising strength of hires fix's upscaling pass, if hires fix is enabled
+    pub fn hires_denoising_strength(&self) -> Option<f32> {
+        match self {
+            GenerationParameters::Text(_, hires_denoising_strength) => *hires_denoising_strength,
+            GenerationParameters::Image(..) => None,
+        }
+    }
+
     pub fn image_params(&self) -> Option<(&str, sd::ResizeMode)> {
         match self {
             GenerationParameters::Image(image, url) => Some((url.as_str(), image.resize_mode?)),
@@ -372,32 +806,134 @@ impl GenerationParameters {
                 init_image: image.images.first()?.clone(),
                 init_url: url.clone(),
                 resize_mode: image.resize_mode?,
+                mask: image.mask.clone(),
             }),
             _ => None,
         }
     }
 
+    /// The fully-resolved settings of this request, for display to the user
+    pub fn settings(&self) -> Vec<(&'static str, String)> {
+        fn display<T: Display>(value: &Option<T>) -> Option<String> {
+            value.as_ref().map(|v| v.to_string())
+        }
+
+        let base = self.base_generation();
+        let (image_url, resize_mode) = self.image_params().unzip();
+        let (hires_fix, hires_denoising_strength) = match self {
+            GenerationParameters::Text(t, hires_denoising_strength) => {
+                (t.enable_hr, *hires_denoising_strength)
+            }
+            GenerationParameters::Image(..) => (None, None),
+        };
+        [
+            (
+                "Prompt",
+                Some(base.prompt.clone()).filter(|p| !p.is_empty()),
+            ),
+            ("Image URL", display(&image_url)),
+            ("Negative prompt", display(&base.negative_prompt)),
+            ("Seed", display(&base.seed)),
+            ("Count", display(&base.batch_count)),
+            ("Width", display(&base.width)),
+            ("Height", display(&base.height)),
+            ("Guidance scale", display(&base.cfg_scale)),
+            ("Denoising strength", display(&base.denoising_strength)),
+            ("Eta", display(&base.eta)),
+            ("Resize mode", display(&resize_mode)),
+            ("Steps", display(&base.steps)),
+            ("Tiling", display(&base.tiling)),
+            ("Restore faces", display(&base.restore_faces)),
+            ("Hires fix", display(&hires_fix)),
+            (
+                "Hires denoising strength",
+                display(&hires_denoising_strength),
+            ),
+            ("Sampler", display(&base.sampler)),
+            (
+                "Model",
+                display(&base.model.as_ref().map(|m| m.name.as_str())),
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| Some((key, value?)))
+        .collect()
+    }
+
     pub fn base_generation(&self) -> &sd::BaseGenerationRequest {
         match self {
-            GenerationParameters::Text(t) => &t.base,
+            GenerationParameters::Text(t, _) => &t.base,
             GenerationParameters::Image(i, _) => &i.base,
         }
     }
 
     pub fn base_generation_mut(&mut self) -> &mut sd::BaseGenerationRequest {
         match self {
-            GenerationParameters::Text(t) => &mut t.base,
+            GenerationParameters::Text(t, _) => &mut t.base,
             GenerationParameters::Image(i, _) => &mut i.base,
         }
     }
 
+    /// The request to the backend; it should be run through [util::with_generation_permit]
+    /// or [util::spawn_generation]
     pub fn generate(
         &self,
         client: &sd::Client,
     ) -> Pin<Box<dyn Future<Output = sd::Result<sd::GenerationResult>> + Send + Sync>> {
         match self {
-            GenerationParameters::Text(t) => Box::pin(client.generate_from_text(t)),
+            GenerationParameters::Text(t, hires_denoising_strength) => Box::pin(
+                client.generate_from_text(&util::with_hires_denoising_strength(
+                    t,
+                    *hires_denoising_strength,
+                )),
+            ),
             GenerationParameters::Image(i, _) => Box::pin(client.generate_from_image_and_text(i)),
         }
     }
 }
+
+/// Resizes the request to the megapixel target, if there is one, keeping the aspect ratio
+/// of its current width and height
+fn apply_megapixels(base: &mut sd::BaseGenerationRequest, megapixels: Option<f64>) {
+    let Some(((width, height), megapixels)) = base.width.zip(base.height).zip(megapixels) else {
+        return;
+    };
+    if width == 0 || height == 0 {
+        return;
+    }
+    let (width, height) = util::megapixel_resolution(megapixels, width as f64 / height as f64, 64);
+    base.width = Some(width);
+    base.height = Some(height);
+}
+
+/// Picks a model at random, weighted by how often the user has used each one. Every model gets
+/// one extra use, so that unused models can still come up and users without history get a
+/// uniform choice.
+fn random_model_hash(
+    store: &Store,
+    user_id: UserId,
+    guild_id: GuildId,
+    models: &[sd::Model],
+) -> anyhow::Result<String> {
+    use rand::distributions::{Distribution, WeightedIndex};
+
+    let usage: HashMap<String, u64> = store
+        .get_model_usage_counts(guild_id)?
+        .remove(&user_id)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|usage| (usage.model_hash.clone(), usage.total()))
+        .collect();
+    let hashes: Vec<&String> = models
+        .iter()
+        .filter_map(|m| m.hash_short.as_ref())
+        .collect();
+    let index = WeightedIndex::new(
+        hashes
+            .iter()
+            .map(|hash| usage.get(*hash).copied().unwrap_or_default() + 1),
+    )
+    .context("There are no models to choose from.")?;
+
+    Ok(hashes[index.sample(&mut rand::thread_rng())].clone())
+}
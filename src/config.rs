@@ -1,15 +1,19 @@
 use anyhow::Context;
-use once_cell::sync::OnceCell;
+use arc_swap::ArcSwap;
+use once_cell::sync::{Lazy, OnceCell};
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
+use serenity::model::prelude::{GuildId, ReactionType};
 use std::{
     collections::{HashMap, HashSet},
     io::BufRead,
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
-use crate::constant;
+use crate::{constant, sd};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Authentication {
     pub discord_token: Option<String>,
     pub sd_url: String,
@@ -27,7 +31,7 @@ impl Default for Authentication {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct Models {
     #[serde(default)]
     pub allowlist: HashSet<String>,
@@ -35,23 +39,142 @@ pub struct Models {
     pub blocklist: HashSet<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct General {
     pub deepdanbooru_tag_allowlist: Option<PathBuf>,
+    /// the most tags to keep from a DeepDanbooru interrogation, keeping the most confident
+    #[serde(default)]
+    pub deepdanbooru_max_tags: Option<usize>,
+    /// whether generating from a CLIP interrogation shuffles its comma-separated phrases by
+    /// default, as is always done for DeepDanbooru's tags; the other order is a separate button
+    #[serde(default)]
+    pub shuffle_clip_tags: bool,
     pub automatically_prepend_keyword: bool,
     pub models: Models,
+    /// the model to use for users who haven't specified one and have no past generations
+    #[serde(default)]
+    pub default_model_hash: Option<String>,
+    /// the sampler to use for users who haven't specified one and have no past generations;
+    /// if unset, the web UI's default is used
+    #[serde(default)]
+    pub default_sampler: Option<String>,
+    /// whether to trim prompts and remove duplicate comma-separated tags from them
+    #[serde(default)]
+    pub normalize_prompt: bool,
+    /// whether to lowercase prompts as part of normalization
+    #[serde(default)]
+    pub lowercase_prompt: bool,
+    /// added to the start of every prompt, e.g. for a house baseline of quality tags
+    #[serde(default)]
+    pub prompt_prefix: Option<String>,
+    /// added to the end of every prompt
+    #[serde(default)]
+    pub prompt_suffix: Option<String>,
+    /// added to the start of every negative prompt
+    #[serde(default)]
+    pub negative_prompt_prefix: Option<String>,
+    /// added to the end of every negative prompt
+    #[serde(default)]
+    pub negative_prompt_suffix: Option<String>,
+    /// the number of generations (including Wirehead's) that can be sent to the backend at once
+    #[serde(default = "General::default_max_concurrent_generations")]
+    pub max_concurrent_generations: usize,
+    /// how long a generation can go without progressing before it's interrupted; 0 to disable
+    #[serde(default = "General::default_generation_timeout_seconds")]
+    pub generation_timeout_seconds: u64,
+    /// the resolution each model was trained at, by short hash; models that aren't listed
+    /// are assumed to be 1024 if their name mentions XL, and 512 otherwise
+    #[serde(default)]
+    pub model_native_resolutions: HashMap<String, u32>,
+    /// words or phrases that prompts (including negative prompts, Wirehead's prefixes and
+    /// suffixes, and downloaded tag files) may not contain; matched case-insensitively
+    /// against whole words, so that `ass` doesn't match `class`
+    #[serde(default)]
+    pub banned_terms: Vec<String>,
+    /// the number of users per row in the `/exilent stats` collage
+    #[serde(default = "General::default_leaderboard_columns")]
+    pub leaderboard_columns: usize,
+    /// shown at the top of `/exilent help`, e.g. to welcome new users or link to server rules
+    #[serde(default)]
+    pub help_message: Option<String>,
+    /// whether to show the parameters of generations in an embed, rather than as a command
+    #[serde(default)]
+    pub use_embeds: bool,
+    /// stamped onto generated images before they're posted
+    #[serde(default)]
+    pub watermark: Watermark,
+    /// replacements for the watermark in individual servers, by server ID
+    #[serde(default)]
+    pub guild_watermarks: HashMap<String, Watermark>,
+    /// a URL that's sent a JSON POST for every completed generation, for external dashboards
+    #[serde(default)]
+    pub completion_webhook: Option<String>,
+    /// users who can use the administrator commands. These commands affect every server, so
+    /// server administrators can't use them unless they're listed here
+    #[serde(default)]
+    pub admin_user_ids: Vec<u64>,
+}
+impl General {
+    /// Whether generating from the interrogator's results shuffles them by default
+    pub fn shuffle_interrogation_tags(&self, interrogator: sd::Interrogator) -> bool {
+        match interrogator {
+            sd::Interrogator::Clip => self.shuffle_clip_tags,
+            sd::Interrogator::DeepDanbooru => true,
+        }
+    }
+
+    /// The watermark to use for generations in the given server
+    pub fn watermark_for(&self, guild_id: Option<GuildId>) -> &Watermark {
+        guild_id
+            .and_then(|id| self.guild_watermarks.get(&id.to_string()))
+            .unwrap_or(&self.watermark)
+    }
+
+    fn default_max_concurrent_generations() -> usize {
+        1
+    }
+
+    fn default_generation_timeout_seconds() -> u64 {
+        300
+    }
+
+    fn default_leaderboard_columns() -> usize {
+        4
+    }
 }
 impl Default for General {
     fn default() -> Self {
         Self {
             deepdanbooru_tag_allowlist: Some(constant::resource::danbooru_sanitized_path()),
+            deepdanbooru_max_tags: None,
+            shuffle_clip_tags: false,
             automatically_prepend_keyword: true,
             models: Default::default(),
+            default_model_hash: None,
+            default_sampler: None,
+            normalize_prompt: false,
+            lowercase_prompt: false,
+            prompt_prefix: None,
+            prompt_suffix: None,
+            negative_prompt_prefix: None,
+            negative_prompt_suffix: None,
+            max_concurrent_generations: Self::default_max_concurrent_generations(),
+            generation_timeout_seconds: Self::default_generation_timeout_seconds(),
+            model_native_resolutions: HashMap::new(),
+            banned_terms: vec![],
+            leaderboard_columns: Self::default_leaderboard_columns(),
+            help_message: None,
+            use_embeds: false,
+            watermark: Default::default(),
+            guild_watermarks: HashMap::new(),
+            completion_webhook: None,
+            admin_user_ids: vec![],
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
 pub struct Commands {
     pub paint: String,
     pub postprocess: String,
@@ -59,17 +182,48 @@ pub struct Commands {
     pub exilent: String,
     pub png_info: String,
     pub wirehead: String,
+    pub compare: String,
+
+    /// added to the start of every command name, so that several instances of the bot
+    /// can share a server (e.g. `test_` turns `/paint` into `/test_paint`)
+    pub prefix: String,
+    /// added to the end of every command name (e.g. `_test` turns `/paint` into `/paint_test`)
+    pub suffix: String,
 }
 impl Commands {
-    pub fn all(&self) -> HashSet<&str> {
-        HashSet::from_iter([
-            self.paint.as_str(),
-            self.postprocess.as_str(),
-            self.interrogate.as_str(),
-            self.exilent.as_str(),
-            self.png_info.as_str(),
-            self.wirehead.as_str(),
-        ])
+    /// The names of all commands, as registered with Discord
+    pub fn all(&self) -> HashSet<String> {
+        [
+            &self.paint,
+            &self.postprocess,
+            &self.interrogate,
+            &self.exilent,
+            &self.png_info,
+            &self.wirehead,
+            &self.compare,
+        ]
+        .into_iter()
+        .map(|name| self.full_name(name))
+        .collect()
+    }
+
+    /// The names of the commands that can't be used in DMs, as they need a server to store
+    /// and look up generations in
+    pub fn guild_only(&self) -> HashSet<String> {
+        [
+            &self.paint,
+            &self.interrogate,
+            &self.wirehead,
+            &self.compare,
+        ]
+        .into_iter()
+        .map(|name| self.full_name(name))
+        .collect()
+    }
+
+    /// Applies the prefix and suffix to a command name
+    pub fn full_name(&self, name: &str) -> String {
+        format!("{}{name}{}", self.prefix, self.suffix)
     }
 }
 impl Default for Commands {
@@ -81,11 +235,14 @@ impl Default for Commands {
             exilent: "exilent".to_string(),
             png_info: "pnginfo".to_string(),
             wirehead: "wirehead".to_string(),
+            compare: "compare".to_string(),
+            prefix: String::new(),
+            suffix: String::new(),
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Emojis {
     pub retry: String,
     pub retry_with_options: String,
@@ -94,6 +251,8 @@ pub struct Emojis {
     pub interrogate_with_clip: String,
     pub interrogate_with_deepdanbooru: String,
     pub interrogate_generate: String,
+    #[serde(default = "Emojis::default_upres")]
+    pub upres: String,
 }
 impl Default for Emojis {
     fn default() -> Self {
@@ -105,11 +264,29 @@ impl Default for Emojis {
             interrogate_with_clip: "📋".to_string(),
             interrogate_with_deepdanbooru: "🧊".to_string(),
             interrogate_generate: "🎲".to_string(),
+            upres: Self::default_upres(),
         }
     }
 }
+impl Emojis {
+    fn default_upres() -> String {
+        "🔍".to_string()
+    }
 
-#[derive(Serialize, Deserialize, Debug)]
+    /// Parses the emoji selected by `get`, falling back to its default if the
+    /// configured value isn't a valid emoji
+    pub fn reaction_type(&self, get: impl Fn(&Self) -> &String) -> ReactionType {
+        let value = get(self);
+        value.parse().unwrap_or_else(|_| {
+            tracing::warn!("The emoji `{value}` could not be parsed; falling back to the default.");
+            get(&Self::default())
+                .parse()
+                .expect("default emojis should always be valid")
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Limits {
     pub count_min: usize,
     pub count_max: usize,
@@ -125,6 +302,81 @@ pub struct Limits {
 
     pub steps_min: usize,
     pub steps_max: usize,
+
+    /// the maximum length of prompts entered in modals (at most 4000)
+    #[serde(default = "Limits::default_prompt_length_max")]
+    pub prompt_length_max: usize,
+
+    /// the number of images to generate when no count is given
+    #[serde(default = "Limits::default_default_count")]
+    pub default_count: usize,
+
+    /// the values used for new users, who have no past generations to draw upon
+    #[serde(default = "Limits::default_default_cfg_scale")]
+    pub default_cfg_scale: f32,
+    #[serde(default = "Limits::default_default_steps")]
+    pub default_steps: u32,
+    #[serde(default = "Limits::default_default_width")]
+    pub default_width: u32,
+    #[serde(default = "Limits::default_default_height")]
+    pub default_height: u32,
+    /// the denoising strength of hires fix's upscaling pass; unrelated to image-to-image's
+    #[serde(default = "Limits::default_default_hires_denoising_strength")]
+    pub default_hires_denoising_strength: f32,
+
+    /// the number of images each user may generate per day/week; administrators are exempt
+    #[serde(default)]
+    pub daily_quota: Option<usize>,
+    #[serde(default)]
+    pub weekly_quota: Option<usize>,
+
+    /// the largest tag file (in bytes) and the most tags that Wirehead will accept from a URL
+    #[serde(default = "Limits::default_tags_url_size_max")]
+    pub tags_url_size_max: usize,
+    #[serde(default = "Limits::default_tags_url_count_max")]
+    pub tags_url_count_max: usize,
+}
+impl Limits {
+    /// The largest megapixel target, which is the area of the largest image allowed
+    pub fn megapixels_max(&self) -> f64 {
+        self.width_max as f64 * self.height_max as f64 / (1024.0 * 1024.0)
+    }
+
+    fn default_prompt_length_max() -> usize {
+        constant::value::MODAL_INPUT_LENGTH_MAX
+    }
+
+    fn default_default_count() -> usize {
+        1
+    }
+
+    fn default_default_cfg_scale() -> f32 {
+        7.0
+    }
+
+    fn default_default_steps() -> u32 {
+        20
+    }
+
+    fn default_default_width() -> u32 {
+        512
+    }
+
+    fn default_default_height() -> u32 {
+        512
+    }
+
+    fn default_default_hires_denoising_strength() -> f32 {
+        0.5
+    }
+
+    fn default_tags_url_size_max() -> usize {
+        1024 * 1024
+    }
+
+    fn default_tags_url_count_max() -> usize {
+        10_000
+    }
 }
 impl Default for Limits {
     fn default() -> Self {
@@ -139,28 +391,326 @@ impl Default for Limits {
             guidance_scale_max: 20.0,
             steps_min: 5,
             steps_max: 100,
+            prompt_length_max: Self::default_prompt_length_max(),
+            default_count: Self::default_default_count(),
+            default_cfg_scale: Self::default_default_cfg_scale(),
+            default_steps: Self::default_default_steps(),
+            default_width: Self::default_default_width(),
+            default_height: Self::default_default_height(),
+            default_hires_denoising_strength: Self::default_default_hires_denoising_strength(),
+            daily_quota: None,
+            weekly_quota: None,
+            tags_url_size_max: Self::default_tags_url_size_max(),
+            tags_url_count_max: Self::default_tags_url_count_max(),
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Progress {
     /// the factor to scale progress images by to reduce upload size
     pub scale_factor: f32,
 
     /// time in milliseonds to wait between progress updates
     pub update_ms: u64,
+
+    /// whether to show in-progress images; if disabled, only the percentage and ETA are shown
+    #[serde(default = "Progress::default_show_preview")]
+    pub show_preview: bool,
+
+    /// (experimental) whether to accumulate in-progress images into an animated preview that
+    /// is updated every few frames, instead of replacing the image on every update
+    #[serde(default)]
+    pub animated_preview: bool,
+
+    /// whether to attach a thumbnail of the image being painted over alongside the preview
+    #[serde(default)]
+    pub show_init_image: bool,
+}
+impl Progress {
+    fn default_show_preview() -> bool {
+        true
+    }
 }
 impl Default for Progress {
     fn default() -> Self {
         Self {
             scale_factor: 0.5,
             update_ms: 250,
+            show_preview: Self::default_show_preview(),
+            animated_preview: false,
+            show_init_image: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Api {
+    /// the address to serve the API on (e.g. `127.0.0.1:8080`); the API is disabled if unset
+    pub address: Option<String>,
+    /// the bearer token that API requests must present
+    pub token: Option<String>,
+    /// the channel that API generations are posted to
+    pub channel_id: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Storage {
+    /// the most generations (and, separately, uploaded images) kept for each user; older ones
+    /// are deleted as new ones come in. Quotas are recorded separately, so deleted
+    /// generations still count towards them.
+    pub max_generations_per_user: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Wirehead {
+    /// time in milliseconds to wait between checks for new generations to make
+    pub generation_cooldown_ms: u64,
+    /// the separator to join tags (and the prefix and suffix) with when building prompts
+    pub tag_separator: String,
+    /// the minimum time in seconds between posts of the best result so far
+    pub best_result_interval_seconds: u64,
+    /// how much the best fitness has to improve by (beyond the last posted) to be posted again
+    pub best_result_min_fitness_delta: usize,
+    /// the prompt to use when a candidate's tags decode to nothing and there's no prefix or
+    /// suffix, so that the backend is never sent an empty prompt
+    pub empty_prompt_fallback: String,
+    /// what to do when a session is started in a channel that already has one
+    pub on_duplicate_start: DuplicateSessionAction,
+}
+impl Default for Wirehead {
+    fn default() -> Self {
+        Self {
+            generation_cooldown_ms: 500,
+            tag_separator: ", ".to_string(),
+            best_result_interval_seconds: 30,
+            best_result_min_fitness_delta: 0,
+            empty_prompt_fallback: "an image".to_string(),
+            on_duplicate_start: DuplicateSessionAction::default(),
+        }
+    }
+}
+
+/// What to do when a Wirehead session is started in a channel that already has one
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateSessionAction {
+    /// leave the running session alone, offering a button to stop it and start the new one
+    #[default]
+    Refuse,
+    /// stop the running session and start the new one straight away
+    Restart,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct QualityPreset {
+    /// the factor to scale the default number of steps by
+    pub steps_multiplier: Option<f32>,
+
+    /// the maximum number of steps to use after scaling
+    pub steps_max: Option<u32>,
+
+    /// whether or not faces should be restored; if not set, the usual value is used
+    pub restore_faces: Option<bool>,
+
+    /// whether or not hires fix should be enabled (text-to-image only)
+    pub hires_fix: Option<bool>,
+
+    /// the area to generate at, in megapixels; the width and height then only set the
+    /// aspect ratio (e.g. 1.0 for SDXL's 1024x1024)
+    pub megapixels: Option<f64>,
+}
+impl QualityPreset {
+    pub fn apply_to_steps(&self, steps: u32) -> u32 {
+        let limits = &Configuration::get().limits;
+        let steps = self
+            .steps_multiplier
+            .map(|m| ((steps as f32) * m).round() as u32)
+            .unwrap_or(steps);
+        let steps = self.steps_max.map(|max| steps.min(max)).unwrap_or(steps);
+        steps.clamp(limits.steps_min as u32, limits.steps_max as u32)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(transparent)]
+pub struct QualityPresets(pub HashMap<String, QualityPreset>);
+impl Default for QualityPresets {
+    fn default() -> Self {
+        Self(HashMap::from_iter([
+            (
+                "draft".to_string(),
+                QualityPreset {
+                    steps_multiplier: Some(0.5),
+                    steps_max: Some(15),
+                    restore_faces: Some(false),
+                    hires_fix: Some(false),
+                    megapixels: None,
+                },
+            ),
+            ("normal".to_string(), QualityPreset::default()),
+            (
+                "high".to_string(),
+                QualityPreset {
+                    steps_multiplier: Some(1.5),
+                    steps_max: None,
+                    restore_faces: None,
+                    hires_fix: Some(true),
+                    megapixels: None,
+                },
+            ),
+        ]))
+    }
+}
+
+/// The corner of the image that a watermark is placed in
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum WatermarkPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    #[default]
+    BottomRight,
+}
+
+/// A mark stamped onto generated images as they're posted; the stored images are unmarked,
+/// so that marks don't build up when generations are painted over
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Watermark {
+    pub enabled: bool,
+    /// the text to stamp
+    pub text: Option<String>,
+    /// an image to stamp, used instead of the text if both are set
+    pub image: Option<PathBuf>,
+    pub position: WatermarkPosition,
+    /// the width of a border to draw around the image; 0 for none
+    pub border_width: u32,
+    /// the colour of the text and border, as RGB
+    pub color: Option<[u8; 3]>,
+}
+
+/// A button shown beneath a generation
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum GenerationButton {
+    Retry,
+    RetryWithOptions,
+    Remix,
+    Upscale,
+    Upres,
+    InterrogateClip,
+    InterrogateDeepDanbooru,
+    CopySeed,
+    Label,
+}
+impl GenerationButton {
+    pub const ALL: [Self; 9] = [
+        Self::Retry,
+        Self::RetryWithOptions,
+        Self::Remix,
+        Self::Upscale,
+        Self::Upres,
+        Self::InterrogateClip,
+        Self::InterrogateDeepDanbooru,
+        Self::CopySeed,
+        Self::Label,
+    ];
+
+    /// Whether the button works outside of a server; the others act on the server that
+    /// the interaction came from, so they're left off results sent to DMs
+    pub fn works_in_dms(self) -> bool {
+        matches!(self, Self::Upscale | Self::CopySeed)
+    }
+}
+
+/// Named sets of the buttons shown beneath generations, in the order they're shown
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(transparent)]
+pub struct ButtonProfiles(pub HashMap<String, Vec<GenerationButton>>);
+impl ButtonProfiles {
+    /// Used for generations that don't have a more specific profile
+    pub const DEFAULT: &'static str = "default";
+    /// Used for generations sent To Exilent from Wirehead
+    pub const WIREHEAD: &'static str = "wirehead";
+
+    /// The buttons of the named profile, or every button if it isn't configured
+    pub fn get(&self, name: &str) -> &[GenerationButton] {
+        self.0
+            .get(name)
+            .map(|buttons| buttons.as_slice())
+            .unwrap_or(&GenerationButton::ALL)
+    }
+}
+impl Default for ButtonProfiles {
+    fn default() -> Self {
+        Self(HashMap::from_iter([
+            (Self::DEFAULT.to_string(), GenerationButton::ALL.to_vec()),
+            (
+                Self::WIREHEAD.to_string(),
+                GenerationButton::ALL
+                    .into_iter()
+                    .filter(|b| {
+                        !matches!(
+                            b,
+                            GenerationButton::InterrogateClip
+                                | GenerationButton::InterrogateDeepDanbooru
+                        )
+                    })
+                    .collect(),
+            ),
+        ]))
+    }
+}
+
+/// The translation of a command's or option's name and description into one locale
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Localization {
+    pub name: Option<String>,
+    pub description: Option<String>,
+}
+
+/// Translations of the commands and options, keyed by Discord locale (e.g. `de`, `pt-BR`)
+/// and then by the name the command or option is registered with
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(transparent)]
+pub struct Localizations(pub HashMap<String, HashMap<String, Localization>>);
+impl Localizations {
+    /// Returns the `name_localizations` and `description_localizations` to register for
+    /// the command or option with the given name, if any translations are configured
+    pub fn fields_for(&self, name: &str) -> Vec<(&'static str, serde_json::Value)> {
+        let mut names = serde_json::Map::new();
+        let mut descriptions = serde_json::Map::new();
+        for (locale, localization) in self
+            .0
+            .iter()
+            .filter_map(|(locale, entries)| Some((locale, entries.get(name)?)))
+        {
+            if let Some(name) = &localization.name {
+                names.insert(locale.clone(), name.clone().into());
+            }
+            if let Some(description) = &localization.description {
+                descriptions.insert(locale.clone(), description.clone().into());
+            }
         }
+
+        [
+            ("name_localizations", names),
+            ("description_localizations", descriptions),
+        ]
+        .into_iter()
+        .filter(|(_, map)| !map.is_empty())
+        .map(|(key, map)| (key, serde_json::Value::Object(map)))
+        .collect()
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct Configuration {
     pub authentication: Authentication,
     pub general: General,
@@ -168,6 +718,18 @@ pub struct Configuration {
     pub emojis: Emojis,
     pub limits: Limits,
     pub progress: Progress,
+    #[serde(default)]
+    pub quality_presets: QualityPresets,
+    #[serde(default)]
+    pub api: Api,
+    #[serde(default)]
+    pub storage: Storage,
+    #[serde(default)]
+    pub wirehead: Wirehead,
+    #[serde(default)]
+    pub localization: Localizations,
+    #[serde(default)]
+    pub buttons: ButtonProfiles,
 
     #[serde(skip)]
     runtime: ConfigurationRuntime,
@@ -175,15 +737,110 @@ pub struct Configuration {
 impl Configuration {
     const FILENAME: &'static str = "config.toml";
 
+    /// The sections whose numeric and boolean values can be changed at runtime
+    const RUNTIME_SECTIONS: [&'static str; 2] = ["limits", "progress"];
+
     pub fn init() -> anyhow::Result<()> {
+        let mut config = Self::load()?;
+        config.validate()?;
+        config.load_runtime()?;
+
         CONFIGURATION
-            .set(Self::load()?)
+            .set(ArcSwap::from_pointee(config))
             .ok()
             .context("config already set")
     }
 
-    pub fn get() -> &'static Self {
-        CONFIGURATION.wait()
+    pub fn get() -> Arc<Self> {
+        CONFIGURATION.wait().load_full()
+    }
+
+    /// Returns the keys (as `section.key`) that can be changed at runtime, and their values
+    pub fn runtime_values(&self) -> anyhow::Result<Vec<(String, toml::Value)>> {
+        let mut values = vec![];
+        for section in Self::RUNTIME_SECTIONS {
+            let toml::Value::Table(table) = self.section_to_toml(section)? else {
+                anyhow::bail!("`{section}` is not a table");
+            };
+            values.extend(
+                table
+                    .into_iter()
+                    .filter(|(_, v)| {
+                        matches!(
+                            v,
+                            toml::Value::Integer(_)
+                                | toml::Value::Float(_)
+                                | toml::Value::Boolean(_)
+                        )
+                    })
+                    .map(|(k, v)| (format!("{section}.{k}"), v)),
+            );
+        }
+        Ok(values)
+    }
+
+    /// Changes a value returned by [Self::runtime_values] and saves the configuration
+    pub fn set_runtime_value(key: &str, value: &str) -> anyhow::Result<()> {
+        let _lock = UPDATE_LOCK.lock();
+
+        let config = Self::get().with_runtime_value(key, value)?;
+        config.save()?;
+        CONFIGURATION.wait().store(Arc::new(config));
+        Ok(())
+    }
+
+    /// Checks that [Self::set_runtime_value] would accept the change, without making it
+    pub fn check_runtime_value(key: &str, value: &str) -> anyhow::Result<()> {
+        Self::get().with_runtime_value(key, value).map(|_| ())
+    }
+
+    fn with_runtime_value(&self, key: &str, value: &str) -> anyhow::Result<Self> {
+        let (section, field) = key
+            .split_once('.')
+            .filter(|(section, _)| Self::RUNTIME_SECTIONS.contains(section))
+            .with_context(|| format!("`{key}` cannot be changed at runtime"))?;
+
+        let mut config = self.clone();
+        let mut table = config.section_to_toml(section)?;
+        let entry = table
+            .get_mut(field)
+            .with_context(|| format!("`{key}` is not a known key"))?;
+        *entry = match entry {
+            toml::Value::Integer(_) => toml::Value::Integer(value.parse()?),
+            toml::Value::Float(_) => toml::Value::Float(value.parse()?),
+            toml::Value::Boolean(_) => toml::Value::Boolean(value.parse()?),
+            _ => anyhow::bail!("`{key}` cannot be changed at runtime"),
+        };
+        match section {
+            "limits" => config.limits = table.try_into()?,
+            "progress" => config.progress = table.try_into()?,
+            _ => unreachable!(),
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn section_to_toml(&self, section: &str) -> anyhow::Result<toml::Value> {
+        Ok(match section {
+            "limits" => toml::Value::try_from(&self.limits)?,
+            "progress" => toml::Value::try_from(&self.progress)?,
+            _ => anyhow::bail!("unknown section `{section}`"),
+        })
+    }
+
+    /// Re-reads the DeepDanbooru tag allowlist and the tag lists, returning the names of
+    /// the tag lists
+    pub fn reload_tags() -> anyhow::Result<Vec<String>> {
+        let _lock = UPDATE_LOCK.lock();
+
+        let mut config = (*Self::get()).clone();
+        config.load_runtime()?;
+        let mut names: Vec<_> = config.tags().keys().cloned().collect();
+        names.sort();
+
+        CONFIGURATION.wait().store(Arc::new(config));
+        Ok(names)
     }
 
     pub fn deepdanbooru_tag_whitelist(&self) -> Option<&Tags> {
@@ -195,16 +852,210 @@ impl Configuration {
     }
 
     fn load() -> anyhow::Result<Self> {
-        let mut config = if let Ok(file) = std::fs::read_to_string(Self::FILENAME) {
+        Ok(if let Ok(file) = std::fs::read_to_string(Self::FILENAME) {
             toml::from_str(&file)?
         } else {
             let config = Self::default();
             config.save()?;
             config
-        };
+        })
+    }
+
+    /// Checks the configuration for problems that would otherwise only surface later,
+    /// reporting all of them at once
+    fn validate(&self) -> anyhow::Result<()> {
+        let mut problems = vec![];
 
-        config.runtime = ConfigurationRuntime {
-            deepdanbooru_tag_allowlist: config
+        if let Err(err) = reqwest::Url::parse(&self.authentication.sd_url) {
+            problems.push(format!(
+                "`authentication.sd_url` (`{}`) is not a valid URL: {err}",
+                self.authentication.sd_url
+            ));
+        }
+
+        for name in self.commands.all() {
+            let valid_chars = name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+            if name.is_empty() || name.len() > 32 || !valid_chars {
+                problems.push(format!(
+                    "the command name `{name}` must be 1-32 lowercase letters, digits, `-` or `_`"
+                ));
+            }
+        }
+
+        let emojis = &self.emojis;
+        for (key, value) in [
+            ("retry", &emojis.retry),
+            ("retry_with_options", &emojis.retry_with_options),
+            ("remix", &emojis.remix),
+            ("upscale", &emojis.upscale),
+            ("interrogate_with_clip", &emojis.interrogate_with_clip),
+            (
+                "interrogate_with_deepdanbooru",
+                &emojis.interrogate_with_deepdanbooru,
+            ),
+            ("interrogate_generate", &emojis.interrogate_generate),
+            ("upres", &emojis.upres),
+        ] {
+            if value.parse::<ReactionType>().is_err() {
+                problems.push(format!("`emojis.{key}` (`{value}`) is not a valid emoji"));
+            }
+        }
+
+        let limits = &self.limits;
+        for (name, min, max) in [
+            ("count", limits.count_min as f64, limits.count_max as f64),
+            ("width", limits.width_min as f64, limits.width_max as f64),
+            ("height", limits.height_min as f64, limits.height_max as f64),
+            (
+                "guidance_scale",
+                limits.guidance_scale_min,
+                limits.guidance_scale_max,
+            ),
+            ("steps", limits.steps_min as f64, limits.steps_max as f64),
+        ] {
+            if min > max {
+                problems.push(format!(
+                    "`limits.{name}_min` ({min}) is greater than `limits.{name}_max` ({max})"
+                ));
+            }
+        }
+        if limits.prompt_length_max > constant::value::MODAL_INPUT_LENGTH_MAX {
+            problems.push(format!(
+                "`limits.prompt_length_max` ({}) is greater than {}",
+                limits.prompt_length_max,
+                constant::value::MODAL_INPUT_LENGTH_MAX
+            ));
+        }
+        if !(limits.count_min..=limits.count_max).contains(&limits.default_count) {
+            problems.push(format!(
+                "`limits.default_count` ({}) is not between `limits.count_min` ({}) and `limits.count_max` ({})",
+                limits.default_count, limits.count_min, limits.count_max
+            ));
+        }
+        if let Some(url) = &self.general.completion_webhook {
+            if reqwest::Url::parse(url).is_err() {
+                problems.push(format!(
+                    "`general.completion_webhook` (`{url}`) is not a valid URL"
+                ));
+            }
+        }
+        if self.wirehead.empty_prompt_fallback.trim().is_empty() {
+            problems.push("`wirehead.empty_prompt_fallback` is empty".to_string());
+        }
+        if let Some(sampler) = &self.general.default_sampler {
+            if sd::Sampler::try_from(sampler.as_str()).is_err() {
+                problems.push(format!(
+                    "`general.default_sampler` (`{sampler}`) is not one of {}",
+                    sd::Sampler::VALUES
+                        .iter()
+                        .map(|s| format!("`{s}`"))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ));
+            }
+        }
+        for (key, watermark) in
+            std::iter::once(("general.watermark".to_string(), &self.general.watermark))
+                .chain(
+                    self.general
+                        .guild_watermarks
+                        .iter()
+                        .map(|(id, w)| (format!("general.guild_watermarks.{id}"), w)),
+                )
+                .filter(|(_, w)| w.enabled)
+        {
+            match &watermark.image {
+                Some(path) if !path.is_file() => problems.push(format!(
+                    "`{key}.image` (`{}`) does not exist",
+                    path.display()
+                )),
+                None if watermark.text.is_none() && watermark.border_width == 0 => problems.push(
+                    format!("`{key}` is enabled, but has no text, image or border"),
+                ),
+                _ => {}
+            }
+        }
+        if self.storage.max_generations_per_user == Some(0) {
+            problems.push("`storage.max_generations_per_user` must be at least 1".to_string());
+        }
+        if self.general.leaderboard_columns == 0 {
+            problems.push("`general.leaderboard_columns` must be at least 1".to_string());
+        }
+        if !(0.0..=1.0).contains(&limits.default_hires_denoising_strength) {
+            problems.push(format!(
+                "`limits.default_hires_denoising_strength` ({}) is not between 0 and 1",
+                limits.default_hires_denoising_strength
+            ));
+        }
+
+        if let Some(path) = &self.general.deepdanbooru_tag_allowlist {
+            if !path.is_file() {
+                problems.push(format!(
+                    "`general.deepdanbooru_tag_allowlist` (`{}`) does not exist",
+                    path.display()
+                ));
+            }
+        }
+        // Discord rejects messages with repeated buttons, as their IDs would clash
+        for (name, buttons) in &self.buttons.0 {
+            if buttons.iter().collect::<HashSet<_>>().len() != buttons.len() {
+                problems.push(format!(
+                    "`buttons.{name}` contains the same button more than once"
+                ));
+            }
+        }
+        // Discord applies the same rules to localized names and descriptions as the originals
+        for (locale, entries) in &self.localization.0 {
+            for (key, localization) in entries {
+                if let Some(name) = &localization.name {
+                    if name.is_empty()
+                        || name.chars().count() > 32
+                        || name.chars().any(|c| c.is_uppercase() || c.is_whitespace())
+                    {
+                        problems.push(format!(
+                            "`localization.{locale}.{key}.name` (`{name}`) must be 1-32 lowercase characters without spaces"
+                        ));
+                    }
+                }
+                if let Some(description) = &localization.description {
+                    if description.is_empty() || description.chars().count() > 100 {
+                        problems.push(format!(
+                            "`localization.{locale}.{key}.description` must be 1-100 characters"
+                        ));
+                    }
+                }
+            }
+        }
+
+        let tags_dir = constant::resource::tags_dir();
+        if !tags_dir.is_dir() {
+            problems.push(format!(
+                "the tags directory (`{}`) does not exist",
+                tags_dir.display()
+            ));
+        }
+
+        if !problems.is_empty() {
+            anyhow::bail!(
+                "The configuration in `{}` has the following problems:\n{}",
+                Self::FILENAME,
+                problems
+                    .into_iter()
+                    .map(|p| format!("- {p}"))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Reads the files referenced by the configuration
+    fn load_runtime(&mut self) -> anyhow::Result<()> {
+        self.runtime = ConfigurationRuntime {
+            deepdanbooru_tag_allowlist: self
                 .general
                 .deepdanbooru_tag_allowlist
                 .as_deref()
@@ -226,7 +1077,7 @@ impl Configuration {
                 .collect::<Result<HashMap<_, _>, _>>()?,
         };
 
-        Ok(config)
+        Ok(())
     }
 
     fn save(&self) -> anyhow::Result<()> {
@@ -236,20 +1087,29 @@ impl Configuration {
         )?)
     }
 }
-static CONFIGURATION: OnceCell<Configuration> = OnceCell::new();
+static CONFIGURATION: OnceCell<ArcSwap<Configuration>> = OnceCell::new();
+/// Serialises updates to the configuration so that concurrent changes aren't lost
+static UPDATE_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
 
 pub type Tags = HashSet<String>;
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default)]
 struct ConfigurationRuntime {
     pub deepdanbooru_tag_allowlist: Option<Tags>,
     pub tags: HashMap<String, Tags>,
 }
 
 fn read_tags_from_file(path: &Path) -> anyhow::Result<Tags> {
-    Ok(std::io::BufReader::new(std::fs::File::open(path)?)
+    Ok(parse_tags(std::io::BufReader::new(std::fs::File::open(
+        path,
+    )?)))
+}
+
+/// Parses a newline-separated tag list, skipping blank lines and `//` comments.
+pub fn parse_tags(reader: impl BufRead) -> Tags {
+    reader
         .lines()
         .map_while(Result::ok)
-        .filter(|l| !l.starts_with("//"))
-        .collect())
+        .filter(|l| !l.starts_with("//") && !l.trim().is_empty())
+        .collect()
 }
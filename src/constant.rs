@@ -6,6 +6,7 @@ pub mod value {
     pub const COUNT: &str = "count";
     pub const WIDTH: &str = "width";
     pub const HEIGHT: &str = "height";
+    pub const MEGAPIXELS: &str = "megapixels";
     pub const GUIDANCE_SCALE: &str = "guidance_scale";
     pub const STEPS: &str = "steps";
     pub const TILING: &str = "tiling";
@@ -13,11 +14,29 @@ pub mod value {
     pub const SAMPLER: &str = "sampler";
     pub const MODEL: &str = "model";
     pub const DENOISING_STRENGTH: &str = "denoising_strength";
+    pub const HIRES_DENOISING_STRENGTH: &str = "hires_denoising_strength";
+    pub const ETA: &str = "eta";
+    pub const LORA: &str = "lora";
+    pub const LORA_WEIGHT: &str = "lora_weight";
+    pub const QUALITY: &str = "quality";
+    pub const DRY_RUN: &str = "dry_run";
+    pub const DM: &str = "dm";
+    pub const NO_AUTO_KEYWORD: &str = "no_auto_keyword";
+    pub const CONFIG_KEY: &str = "key";
+    pub const CONFIG_VALUE: &str = "value";
+    pub const QUERY: &str = "query";
+    pub const LABEL: &str = "label";
+    pub const EVERYONE: &str = "everyone";
+    pub const PAGE: &str = "page";
+    pub const FORMAT: &str = "format";
+    pub const ID: &str = "id";
 
     pub const WIDTH_HEIGHT: &str = "width_height";
-    pub const GUIDANCE_SCALE_DENOISING_STRENGTH: &str = "guidance_scale_denoising_strength";
+    pub const GUIDANCE_SCALE_DENOISING_STRENGTH_STEPS: &str =
+        "guidance_scale_denoising_strength_steps";
 
     pub const RESIZE_MODE: &str = "resize_mode";
+    pub const OUTPAINT_DIRECTION: &str = "outpaint_direction";
 
     pub const UPSCALER_1: &str = "upscaler_1";
     pub const UPSCALER_2: &str = "upscaler_2";
@@ -32,15 +51,33 @@ pub mod value {
     pub const IMAGE_ATTACHMENT: &str = "image_attachment";
     pub const INTERROGATOR: &str = "interrogator";
 
+    pub const GENERATION_1: &str = "generation_1";
+    pub const GENERATION_2: &str = "generation_2";
+    pub const FILTERED: &str = "filtered";
+
     pub const TAGS: &str = "tags";
+    pub const EXTRA_TAGS: &str = "extra_tags";
+    pub const OPERATORS: &str = "operators";
+    pub const GA_SEED: &str = "ga_seed";
+    pub const PREVIEW_STEPS: &str = "preview_steps";
+    pub const PREVIEW_GUIDANCE_SCALE: &str = "preview_guidance_scale";
     pub const HIDE_PROMPT: &str = "hide_prompt";
     pub const TO_EXILENT_ENABLED: &str = "to_exilent_enabled";
     pub const TO_EXILENT_CHANNEL: &str = "to_exilent_channel";
     pub const PREFIX: &str = "prefix";
     pub const SUFFIX: &str = "suffix";
+    pub const INCLUDE_PARAMETERS: &str = "include_parameters";
 
     /// Discord allows for a maximum of 25 options in a choice
     pub const MODEL_CHUNK_COUNT: usize = 25;
+    /// Discord allows for a maximum of 25 options in a select menu
+    pub const SELECT_MENU_OPTION_COUNT_MAX: usize = 25;
+    /// The special model choice that picks a model at random
+    pub const MODEL_RANDOM: &str = "random";
+    /// Discord allows for a maximum of 4000 characters in a modal's text input
+    pub const MODAL_INPUT_LENGTH_MAX: usize = 4000;
+    /// Prompts longer than this are edited in a multi-line text input
+    pub const MODAL_SHORT_INPUT_LENGTH: usize = 100;
 }
 
 /// resource
@@ -63,6 +100,10 @@ pub mod resource {
         tags_dir().join("danbooru_sanitized.txt")
     }
 
+    pub fn font_path() -> PathBuf {
+        assets_dir().join("fonts").join("DejaVuSans.ttf")
+    }
+
     pub fn write_assets() -> anyhow::Result<()> {
         fn write_file(path: PathBuf, contents: Vec<u8>) -> anyhow::Result<()> {
             if !path.exists() {
@@ -1,5 +1,6 @@
 use crate::wirehead::simulation::TextGenome;
 use anyhow::Context;
+use serenity::model::id::InteractionId;
 use std::fmt::Display;
 
 const SEPARATOR: &str = "#";
@@ -7,6 +8,8 @@ const SEPARATOR: &str = "#";
 const GENERATION_PREFIX: &str = "gen";
 const INTERROGATION_PREFIX: &str = "int";
 const WIREHEAD_PREFIX: &str = "wh";
+const RECENT_PREFIX: &str = "rec";
+const TASK_PREFIX: &str = "task";
 
 macro_rules! implement_custom_id_component {
     ($name:ident, $(($member:ident, $const:ident, $segment:literal)),*) => {
@@ -66,7 +69,11 @@ implement_custom_id_component!(
         InterrogateDeepDanbooru,
         GENERATION_INTERROGATE_DEEPDANBOORU,
         "interrogate_dd"
-    )
+    ),
+    (ShowSeed, GENERATION_SHOW_SEED, "show_seed"),
+    (HiResRegen, GENERATION_HI_RES_REGEN, "hires_regen"),
+    (Label, GENERATION_LABEL, "label"),
+    (LabelResponse, GENERATION_LABEL_RESPONSE, "label_response")
 );
 impl Generation {
     pub fn to_id(self, id: i64) -> CustomId {
@@ -77,6 +84,16 @@ impl Generation {
 implement_custom_id_component!(
     Interrogation,
     (Generate, INTERROGATION_GENERATE, "generate"),
+    (
+        GenerateShuffled,
+        INTERROGATION_GENERATE_SHUFFLED,
+        "generate_shuffled"
+    ),
+    (
+        GenerateInOrder,
+        INTERROGATION_GENERATE_IN_ORDER,
+        "generate_in_order"
+    ),
     (
         ReinterrogateWithClip,
         INTERROGATION_REINTERROGATE_CLIP,
@@ -86,6 +103,12 @@ implement_custom_id_component!(
         ReinterrogateWithDeepDanbooru,
         INTERROGATION_REINTERROGATE_DD,
         "reint_dd"
+    ),
+    (SendToWirehead, INTERROGATION_SEND_TO_WIREHEAD, "to_wh"),
+    (
+        SendToWireheadResponse,
+        INTERROGATION_SEND_TO_WIREHEAD_RESPONSE,
+        "to_wh_response"
     )
 );
 impl Interrogation {
@@ -94,6 +117,31 @@ impl Interrogation {
     }
 }
 
+implement_custom_id_component!(
+    Recent,
+    (Previous, RECENT_PREVIOUS, "prev"),
+    (Next, RECENT_NEXT, "next")
+);
+impl Recent {
+    /// `page` is the page the button is shown on
+    pub fn to_id(self, page: i64) -> CustomId {
+        CustomId::Recent { page, value: self }
+    }
+}
+
+implement_custom_id_component!(
+    Task,
+    (Cancel, TASK_CANCEL, "cancel"),
+    (RestartWirehead, TASK_RESTART_WIREHEAD, "restart_wh"),
+    (ConfirmConfig, TASK_CONFIRM_CONFIG, "confirm_config")
+);
+impl Task {
+    /// `id` is the ID of the interaction that requested the task
+    pub fn to_id(self, id: InteractionId) -> CustomId {
+        CustomId::Task { id, value: self }
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct Wirehead {
     pub value: WireheadValue,
@@ -125,7 +173,12 @@ implement_custom_id_component!(
     (Zero, WIREHEAD_ZERO, "z"),
     (Positive1, WIREHEAD_POSITIVE1, "p1"),
     (Positive2, WIREHEAD_POSITIVE2, "p2"),
-    (ToExilent, WIREHEAD_TO_EXILENT, "to_exilent")
+    (ToExilent, WIREHEAD_TO_EXILENT, "to_exilent"),
+    (
+        ToExilentNewSeed,
+        WIREHEAD_TO_EXILENT_NEW_SEED,
+        "to_exilent_ns"
+    )
 );
 impl WireheadValue {
     pub fn to_id(self, id: TextGenome, seed: i64) -> CustomId {
@@ -142,7 +195,7 @@ impl WireheadValue {
             WireheadValue::Zero => 0,
             WireheadValue::Positive1 => 1,
             WireheadValue::Positive2 => 2,
-            WireheadValue::ToExilent { .. } => unreachable!(),
+            WireheadValue::ToExilent | WireheadValue::ToExilentNewSeed => unreachable!(),
         }
     }
 }
@@ -151,6 +204,8 @@ pub enum CustomId {
     Generation { id: i64, value: Generation },
     Interrogation { id: i64, value: Interrogation },
     Wirehead { genome: TextGenome, value: Wirehead },
+    Recent { page: i64, value: Recent },
+    Task { id: InteractionId, value: Task },
 }
 impl TryFrom<&str> for CustomId {
     type Error = anyhow::Error;
@@ -171,9 +226,17 @@ impl TryFrom<&str> for CustomId {
                 value: Interrogation::try_from(cmd)?,
             },
             WIREHEAD_PREFIX => Self::Wirehead {
-                genome: hex_to_genome(id),
+                genome: hex_to_genome(id)?,
                 value: Wirehead::try_from(cmd)?,
             },
+            RECENT_PREFIX => Self::Recent {
+                page: id.parse()?,
+                value: Recent::try_from(cmd)?,
+            },
+            TASK_PREFIX => Self::Task {
+                id: InteractionId(id.parse()?),
+                value: Task::try_from(cmd)?,
+            },
             _ => anyhow::bail!("invalid custom id prefix: {prefix}"),
         })
     }
@@ -210,6 +273,12 @@ impl Display for CustomId {
                     wirehead
                 )
             }
+            CustomId::Recent { page, value } => {
+                write!(f, "{RECENT_PREFIX}{SEPARATOR}{page}{SEPARATOR}{value}")
+            }
+            CustomId::Task { id, value } => {
+                write!(f, "{TASK_PREFIX}{SEPARATOR}{id}{SEPARATOR}{value}")
+            }
         }
     }
 }
@@ -218,6 +287,14 @@ fn genome_to_hex(genome: TextGenome) -> String {
     hex::encode(bytemuck::cast_slice::<u16, u8>(genome.as_slice()))
 }
 
-fn hex_to_genome(hex: &str) -> TextGenome {
-    bytemuck::cast_slice::<u8, u16>(&hex::decode(hex).unwrap()).into()
+fn hex_to_genome(hex: &str) -> anyhow::Result<TextGenome> {
+    let bytes = hex::decode(hex)?;
+    if bytes.len() % 2 != 0 {
+        anyhow::bail!("genome has an odd number of bytes");
+    }
+    // read the genes individually, as the decoded bytes may not be aligned for a direct cast
+    Ok(bytes
+        .chunks_exact(2)
+        .map(|c| u16::from_ne_bytes([c[0], c[1]]))
+        .collect())
 }
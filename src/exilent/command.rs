@@ -1,27 +1,52 @@
 use super::issuer;
 use crate::{
     command,
-    config::Configuration,
-    constant, store,
+    config::{ButtonProfiles, Configuration},
+    constant, custom_id as cid, store,
     util::{self, DiscordInteraction},
 };
 use anyhow::Context;
+use futures::StreamExt;
 use itertools::Itertools;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
 use serenity::{
+    builder::CreateEmbed,
     http::Http,
     model::prelude::{
-        command::{Command, CommandOptionType},
-        interaction::application_command::ApplicationCommandInteraction,
+        command::{Command, CommandOption, CommandOptionType},
+        interaction::{
+            application_command::ApplicationCommandInteraction,
+            autocomplete::AutocompleteInteraction,
+        },
         *,
     },
+    prelude::Mentionable,
 };
 use stable_diffusion_a1111_webui_client as sd;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// The display names of users, as of when they were looked up. Entries older than
+/// [USER_NAME_CACHE_TTL] are ignored, and removed whenever a new name is added.
+pub type UserNameCache = Mutex<HashMap<(GuildId, UserId), (String, Instant)>>;
+
+/// How long a cached user name is used before it's looked up again
+const USER_NAME_CACHE_TTL: Duration = Duration::from_secs(10 * 60);
+/// How many user names are looked up at once
+const USER_NAME_LOOKUP_CONCURRENCY: usize = 8;
 
 pub async fn register(http: &Http, models: &[sd::Model]) -> anyhow::Result<()> {
     Command::create_global_application_command(http, |command| {
         command
-            .name(&Configuration::get().commands.paint)
-            .description("Paints your dreams");
+            .name({
+                let commands = &Configuration::get().commands;
+                commands.full_name(&commands.paint)
+            })
+            .description("Paints your dreams")
+            .dm_permission(false);
 
         command::populate_generate_options(
             |opt| {
@@ -31,21 +56,37 @@ pub async fn register(http: &Http, models: &[sd::Model]) -> anyhow::Result<()> {
             true,
         );
         command
+            .create_option(|o| {
+                o.name(constant::value::NO_AUTO_KEYWORD)
+                    .description("Don't prepend the model's keyword to the prompt for this request")
+                    .kind(CommandOptionType::Boolean)
+                    .required(false)
+            })
+            .create_option(|o| {
+                o.name(constant::value::DRY_RUN)
+                    .description("Show the fully-resolved settings instead of generating")
+                    .kind(CommandOptionType::Boolean)
+                    .required(false)
+            });
+        command::fit_model_options(command.0.get_mut("options"));
+        util::localize_command(command)
     })
     .await?;
 
     Command::create_global_application_command(http, |command| {
         command
-            .name(&Configuration::get().commands.postprocess)
+            .name({
+                let commands = &Configuration::get().commands;
+                commands.full_name(&commands.postprocess)
+            })
             .description("Postprocesses an image");
 
         command
             .create_option(|option| {
                 let opt = option
                     .name(constant::value::UPSCALER_1)
-                    .description("The first upscaler")
-                    .kind(CommandOptionType::String)
-                    .required(true);
+                    .description("The first upscaler (default: none)")
+                    .kind(CommandOptionType::String);
 
                 for value in sd::Upscaler::VALUES {
                     opt.add_string_choice(value, value);
@@ -56,9 +97,8 @@ pub async fn register(http: &Http, models: &[sd::Model]) -> anyhow::Result<()> {
             .create_option(|option| {
                 let opt = option
                     .name(constant::value::UPSCALER_2)
-                    .description("The second upscaler")
-                    .kind(CommandOptionType::String)
-                    .required(true);
+                    .description("The second upscaler (default: none)")
+                    .kind(CommandOptionType::String);
 
                 for value in sd::Upscaler::VALUES {
                     opt.add_string_choice(value, value);
@@ -69,11 +109,10 @@ pub async fn register(http: &Http, models: &[sd::Model]) -> anyhow::Result<()> {
             .create_option(|option| {
                 option
                     .name(constant::value::SCALE_FACTOR)
-                    .description("The factor by which to upscale the image")
+                    .description("The factor by which to upscale the image (default: 1)")
                     .kind(CommandOptionType::Number)
                     .min_number_value(1.0)
                     .max_number_value(3.0)
-                    .required(true)
             })
             .create_option(|option| {
                 option
@@ -126,14 +165,19 @@ pub async fn register(http: &Http, models: &[sd::Model]) -> anyhow::Result<()> {
                     .name(constant::value::UPSCALE_FIRST)
                     .description("Should the upscaler be applied before other postprocessing?")
                     .kind(CommandOptionType::Boolean)
-            })
+            });
+        util::localize_command(command)
     })
     .await?;
 
     Command::create_global_application_command(http, |command| {
         command
-            .name(&Configuration::get().commands.interrogate)
+            .name({
+                let commands = &Configuration::get().commands;
+                commands.full_name(&commands.interrogate)
+            })
             .description("Interrogates an image to produce a caption")
+            .dm_permission(false)
             .create_option(|option| {
                 let opt = option
                     .name(constant::value::INTERROGATOR)
@@ -150,7 +194,7 @@ pub async fn register(http: &Http, models: &[sd::Model]) -> anyhow::Result<()> {
             .create_option(|option| {
                 option
                     .name(constant::value::IMAGE_URL)
-                    .description("The URL of the image to interrogate")
+                    .description("The URL of the image to interrogate, or the ID of an image used before")
                     .kind(CommandOptionType::String)
             })
             .create_option(|option| {
@@ -159,12 +203,24 @@ pub async fn register(http: &Http, models: &[sd::Model]) -> anyhow::Result<()> {
                     .description("The image to interrogate")
                     .kind(CommandOptionType::Attachment)
             })
+            .create_option(|option| {
+                option
+                    .name(constant::value::FILTERED)
+                    .description(
+                        "Whether or not to filter DeepDanbooru tags through the allowlist (default: true)",
+                    )
+                    .kind(CommandOptionType::Boolean)
+            });
+        util::localize_command(command)
     })
     .await?;
 
     Command::create_global_application_command(http, |command| {
         command
-            .name(&Configuration::get().commands.exilent)
+            .name({
+                let commands = &Configuration::get().commands;
+                commands.full_name(&commands.exilent)
+            })
             .description("Meta-commands for Exilent")
             .create_option(|option| {
                 option
@@ -177,13 +233,159 @@ pub async fn register(http: &Http, models: &[sd::Model]) -> anyhow::Result<()> {
                     .name("stats")
                     .description("Output some statistics")
                     .kind(CommandOptionType::SubCommand)
+                    .create_sub_option(|o| {
+                        o.name(constant::value::FORMAT)
+                            .description("How to show the statistics (default: text)")
+                            .kind(CommandOptionType::String)
+                            .add_string_choice("Text", "text")
+                            .add_string_choice(
+                                "Collage of each user's latest generation",
+                                "collage",
+                            )
+                    })
+            })
+            .create_option(|option| {
+                option
+                    .name("help")
+                    .description("Show the available commands, limits and models")
+                    .kind(CommandOptionType::SubCommand)
             })
+            .create_option(|option| {
+                option
+                    .name("status")
+                    .description("Show what the backend is working on, updating for a minute")
+                    .kind(CommandOptionType::SubCommand)
+            })
+            .create_option(|option| {
+                option
+                    .name("params")
+                    .description("Show a generation's parameters in the web UI's format")
+                    .kind(CommandOptionType::SubCommand)
+                    .create_sub_option(|o| {
+                        o.name(constant::value::ID)
+                            .description("The ID of the generation")
+                            .kind(CommandOptionType::Integer)
+                            .required(true)
+                    })
+            })
+            .create_option(|option| {
+                option
+                    .name("search")
+                    .description("Search your past generations by prompt or label")
+                    .kind(CommandOptionType::SubCommand)
+                    .create_sub_option(|o| {
+                        o.name(constant::value::QUERY)
+                            .description("The text to search for in prompts")
+                            .kind(CommandOptionType::String)
+                    })
+                    .create_sub_option(|o| {
+                        o.name(constant::value::LABEL)
+                            .description("Only show generations with this label")
+                            .kind(CommandOptionType::String)
+                    })
+                    .create_sub_option(|o| {
+                        o.name(constant::value::EVERYONE)
+                            .description("Whether to search everyone's generations in this server")
+                            .kind(CommandOptionType::Boolean)
+                    })
+                    .create_sub_option(|o| {
+                        o.name(constant::value::PAGE)
+                            .description("The page of results to show")
+                            .kind(CommandOptionType::Integer)
+                            .min_int_value(1)
+                    })
+            })
+            .create_option(|option| {
+                option
+                    .name("settings")
+                    .description("View or change your settings")
+                    .kind(CommandOptionType::SubCommand)
+                    .create_sub_option(|o| {
+                        o.name(constant::value::DM)
+                            .description("Whether to send the results of /paint to your DMs instead of the channel")
+                            .kind(CommandOptionType::Boolean)
+                    })
+            })
+            .create_option(|option| {
+                option
+                    .name("recent")
+                    .description("Show the latest generations from everyone in this server")
+                    .kind(CommandOptionType::SubCommand)
+            })
+            .create_option(|option| {
+                option
+                    .name("modelpreview")
+                    .description("Show a model's preview image, if it has one")
+                    .kind(CommandOptionType::SubCommand)
+                    .create_sub_option(|o| {
+                        o.name(constant::value::MODEL)
+                            .description("The model to preview")
+                            .kind(CommandOptionType::String)
+                            .set_autocomplete(true)
+                            .required(true)
+                    })
+            })
+            .create_option(|option| {
+                option
+                    .name("selftest")
+                    .description(
+                        "Check that the backend reproduces an image from its seed (administrators only)",
+                    )
+                    .kind(CommandOptionType::SubCommand)
+            })
+            .create_option(|option| {
+                option
+                    .name("auditlog")
+                    .description("Show the latest administrator actions (administrators only)")
+                    .kind(CommandOptionType::SubCommand)
+            })
+            .create_option(|option| {
+                option
+                    .name("reloadtags")
+                    .description(
+                        "Re-read the tag lists and DeepDanbooru allowlist (administrators only)",
+                    )
+                    .kind(CommandOptionType::SubCommand)
+            })
+            .create_option(|option| {
+                option
+                    .name("config")
+                    .description("View or change the configuration (administrators only)")
+                    .kind(CommandOptionType::SubCommandGroup)
+                    .create_sub_option(|o| {
+                        o.name("get")
+                            .description("Show the values that can be changed")
+                            .kind(CommandOptionType::SubCommand)
+                    })
+                    .create_sub_option(|o| {
+                        o.name("set")
+                            .description("Change a value")
+                            .kind(CommandOptionType::SubCommand)
+                            .create_sub_option(|o| {
+                                o.name(constant::value::CONFIG_KEY)
+                                    .description("The key to change")
+                                    .kind(CommandOptionType::String)
+                                    .set_autocomplete(true)
+                                    .required(true)
+                            })
+                            .create_sub_option(|o| {
+                                o.name(constant::value::CONFIG_VALUE)
+                                    .description("The new value")
+                                    .kind(CommandOptionType::String)
+                                    .required(true)
+                            })
+                    })
+            });
+        util::localize_command(command)
     })
     .await?;
 
     Command::create_global_application_command(http, |command| {
         command
-            .name(&Configuration::get().commands.png_info)
+            .name({
+                let commands = &Configuration::get().commands;
+                commands.full_name(&commands.png_info)
+            })
             .description("Retrieves the embedded PNG info of an image")
             .create_option(|option| {
                 option
@@ -196,7 +398,34 @@ pub async fn register(http: &Http, models: &[sd::Model]) -> anyhow::Result<()> {
                     .name(constant::value::IMAGE_ATTACHMENT)
                     .description("The image to read")
                     .kind(CommandOptionType::Attachment)
+            });
+        util::localize_command(command)
+    })
+    .await?;
+
+    Command::create_global_application_command(http, |command| {
+        command
+            .name({
+                let commands = &Configuration::get().commands;
+                commands.full_name(&commands.compare)
+            })
+            .description("Compares two generations side-by-side")
+            .dm_permission(false)
+            .create_option(|option| {
+                option
+                    .name(constant::value::GENERATION_1)
+                    .description("The ID of the first generation")
+                    .kind(CommandOptionType::Integer)
+                    .required(true)
             })
+            .create_option(|option| {
+                option
+                    .name(constant::value::GENERATION_2)
+                    .description("The ID of the second generation")
+                    .kind(CommandOptionType::Integer)
+                    .required(true)
+            });
+        util::localize_command(command)
     })
     .await?;
 
@@ -207,16 +436,310 @@ pub async fn exilent(
     client: &sd::Client,
     models: &[sd::Model],
     store: &store::Store,
+    user_names: &UserNameCache,
     http: &Http,
     cmd: ApplicationCommandInteraction,
 ) {
     match cmd.data.options[0].name.as_str() {
         "embeddings" => embeddings(client, http, cmd).await,
-        "stats" => stats(models, store, http, cmd).await,
+        "stats" => stats(models, store, user_names, http, cmd).await,
+        "status" => status(client, http, cmd).await,
+        "help" => help(models, http, cmd).await,
+        "search" => search(store, http, cmd).await,
+        "recent" => recent(store, http, cmd).await,
+        "params" => params(store, http, cmd).await,
+        "settings" => settings(store, http, cmd).await,
+        "config" => config(http, cmd).await,
+        "reloadtags" => reload_tags(store, http, cmd).await,
+        "selftest" => self_test(client, models, store, http, cmd).await,
+        "auditlog" => audit_log(store, http, cmd).await,
+        "modelpreview" => model_preview(models, http, cmd).await,
         _ => unreachable!(),
     }
 }
 
+/// A change made with `/exilent config set` that hasn't been confirmed yet
+struct PendingConfigChange {
+    interaction_id: InteractionId,
+    key: String,
+    value: String,
+}
+
+/// The most recent unconfirmed change by each user; older ones can no longer be confirmed
+static PENDING_CONFIG_CHANGES: Lazy<Mutex<HashMap<UserId, PendingConfigChange>>> =
+    Lazy::new(Default::default);
+
+async fn config(http: &Http, cmd: ApplicationCommandInteraction) {
+    cmd.create(http, "Processing configuration request...")
+        .await
+        .unwrap();
+
+    util::run_and_report_error(&cmd, http, async {
+        util::require_admin(&cmd, "view or change the configuration")?;
+
+        let subcommand = cmd.data.options[0]
+            .options
+            .first()
+            .context("no subcommand")?;
+        match subcommand.name.as_str() {
+            "get" => cmd.edit(http, &describe_configuration()?).await,
+            "set" => {
+                let key = util::get_value(&subcommand.options, constant::value::CONFIG_KEY)
+                    .and_then(util::value_to_string)
+                    .context("no key specified")?;
+                let value = util::get_value(&subcommand.options, constant::value::CONFIG_VALUE)
+                    .and_then(util::value_to_string)
+                    .context("no value specified")?;
+                Configuration::check_runtime_value(&key, &value)?;
+                let current = Configuration::get()
+                    .runtime_values()?
+                    .into_iter()
+                    .find(|(k, _)| *k == key)
+                    .map(|(_, v)| v.to_string())
+                    .unwrap_or_default();
+
+                PENDING_CONFIG_CHANGES.lock().insert(
+                    cmd.user.id,
+                    PendingConfigChange {
+                        interaction_id: cmd.id,
+                        key: key.clone(),
+                        value: value.clone(),
+                    },
+                );
+                cmd.get_interaction_message(http)
+                    .await?
+                    .edit(http, |m| {
+                        m.content(format!(
+                            "Change `{key}` from {current} to {value}? This affects every server."
+                        ))
+                        .components(|c| {
+                            c.create_action_row(|r| {
+                                r.create_button(|b| {
+                                    b.label("Confirm")
+                                        .style(component::ButtonStyle::Danger)
+                                        .custom_id(cid::Task::ConfirmConfig.to_id(cmd.id))
+                                })
+                            })
+                        })
+                    })
+                    .await?;
+                Ok(())
+            }
+            _ => unreachable!(),
+        }
+    })
+    .await;
+}
+
+/// Applies the change requested by the `/exilent config set` interaction `id`, if it's
+/// still the user's most recent one
+pub(super) fn apply_pending_config(
+    store: &store::Store,
+    interaction: &dyn DiscordInteraction,
+    id: InteractionId,
+) -> anyhow::Result<()> {
+    util::require_admin(interaction, "change the configuration")?;
+
+    let user_id = interaction.user().id;
+    let pending = {
+        let mut pending_changes = PENDING_CONFIG_CHANGES.lock();
+        if pending_changes
+            .get(&user_id)
+            .is_none_or(|p| p.interaction_id != id)
+        {
+            anyhow::bail!(
+                "This change has already been confirmed, or isn't yours to confirm, or a newer one has taken its place."
+            );
+        }
+        pending_changes.remove(&user_id).unwrap()
+    };
+
+    Configuration::set_runtime_value(&pending.key, &pending.value)?;
+    store.insert_audit_log(
+        user_id,
+        "config set",
+        &format!("{} = {}", pending.key, pending.value),
+    )?;
+    Ok(())
+}
+
+/// Lists the values that can be changed with `/exilent config set`
+pub(super) fn describe_configuration() -> anyhow::Result<String> {
+    Ok(format!(
+        "Configuration (changes to limits will be reflected in command options after a restart):\n{}",
+        Configuration::get()
+            .runtime_values()?
+            .into_iter()
+            .map(|(key, value)| format!("- `{key}`: {value}"))
+            .join("\n")
+    ))
+}
+
+/// Generates the same image twice and compares the results, to catch backends that don't
+/// reproduce images from their seeds (e.g. after an upgrade, or with nondeterministic settings)
+async fn self_test(
+    client: &sd::Client,
+    models: &[sd::Model],
+    store: &store::Store,
+    http: &Http,
+    cmd: ApplicationCommandInteraction,
+) {
+    const PROMPT: &str = "a lighthouse on a cliff at sunset";
+    const SEED: i64 = 1234;
+    // the mean difference per channel (out of 255) that's still considered a match; some
+    // backends have tiny variations between runs that aren't visible
+    const TOLERANCE: f64 = 1.0;
+
+    cmd.create(http, "Running self-test...").await.unwrap();
+
+    util::run_and_report_error(&cmd, http, async {
+        util::require_admin(&cmd, "run the self-test")?;
+
+        let request = {
+            let config = Configuration::get();
+            let limits = &config.limits;
+            sd::TextToImageGenerationRequest {
+                base: sd::BaseGenerationRequest {
+                    prompt: PROMPT.to_string(),
+                    seed: Some(SEED),
+                    batch_size: Some(1),
+                    batch_count: Some(1),
+                    width: Some(limits.default_width),
+                    height: Some(limits.default_height),
+                    cfg_scale: Some(limits.default_cfg_scale),
+                    steps: Some(limits.default_steps),
+                    model: config
+                        .general
+                        .default_model_hash
+                        .as_deref()
+                        .and_then(|hash| util::find_model_by_hash(models, hash))
+                        .map(|(_, model)| model),
+                    ..Default::default()
+                },
+                ..Default::default()
+            }
+        };
+
+        let mut images = vec![];
+        for run in 1..=2 {
+            cmd.edit(http, &format!("Self-test: generating image {run} of 2..."))
+                .await?;
+            let result = util::with_generation_permit(client.generate_from_text(&request)).await?;
+            images.push(
+                result
+                    .pngs
+                    .into_iter()
+                    .next()
+                    .context("the backend returned no image")?,
+            );
+        }
+
+        let difference = if images[0] == images[1] {
+            None
+        } else {
+            let (first, second) = (images[0].clone(), images[1].clone());
+            Some(
+                util::run_image_task(move || {
+                    util::mean_pixel_difference(
+                        &image::load_from_memory(&first)?,
+                        &image::load_from_memory(&second)?,
+                    )
+                })
+                .await?,
+            )
+        };
+        store.insert_audit_log(
+            cmd.user.id,
+            "selftest",
+            &match difference {
+                None => "identical".to_string(),
+                Some(difference) => format!("mean difference {difference:.3}"),
+            },
+        )?;
+        let content = match difference {
+            None => "Self-test passed: both images are byte-identical.".to_string(),
+            Some(difference) if difference <= TOLERANCE => format!(
+                "Self-test passed: the images differ by {difference:.3} on average (out of 255), within the tolerance of {TOLERANCE}."
+            ),
+            Some(difference) => format!(
+                "Self-test **failed**: the images differ by {difference:.3} on average (out of 255), beyond the tolerance of {TOLERANCE}. The backend isn't reproducing images from their seeds."
+            ),
+        };
+
+        cmd.get_interaction_message(http)
+            .await?
+            .edit(http, |m| {
+                m.content(format!("{content}\n`{PROMPT}`, seed {SEED}"))
+                    .attachment((images[0].as_slice(), "selftest_1.png"))
+                    .attachment((images[1].as_slice(), "selftest_2.png"))
+            })
+            .await?;
+
+        Ok(())
+    })
+    .await;
+}
+
+async fn audit_log(store: &store::Store, http: &Http, cmd: ApplicationCommandInteraction) {
+    const ENTRIES: usize = 20;
+
+    cmd.create(http, "Fetching the audit log...").await.unwrap();
+
+    util::run_and_report_error(&cmd, http, async {
+        util::require_admin(&cmd, "view the audit log")?;
+
+        let entries = store.recent_audit_log(ENTRIES)?;
+        if entries.is_empty() {
+            cmd.edit(http, "No administrator actions have been recorded yet.")
+                .await?;
+            return Ok(());
+        }
+
+        let lines: Vec<_> = entries
+            .iter()
+            .map(|e| {
+                format!(
+                    "- <t:{}:f> {} `{}`{}",
+                    e.timestamp.timestamp(),
+                    e.user_id.mention(),
+                    e.action,
+                    Some(&e.details)
+                        .filter(|d| !d.is_empty())
+                        .map(|d| format!(": {d}"))
+                        .unwrap_or_default()
+                )
+            })
+            .collect();
+        util::chunked_response(http, &cmd, lines.iter().map(|s| s.as_str()), "\n").await?;
+
+        Ok(())
+    })
+    .await;
+}
+
+async fn reload_tags(store: &store::Store, http: &Http, cmd: ApplicationCommandInteraction) {
+    cmd.create(http, "Reloading tags...").await.unwrap();
+
+    util::run_and_report_error(&cmd, http, async {
+        util::require_admin(&cmd, "reload the tags")?;
+
+        let names = Configuration::reload_tags()?;
+        store.insert_audit_log(cmd.user.id, "reloadtags", &names.join(", "))?;
+        cmd.edit(
+            http,
+            &format!(
+                "Reloaded the DeepDanbooru allowlist and {} tag list(s): {}\n(New tag lists will be offered by `/wirehead` after a restart.)",
+                names.len(),
+                names.iter().map(|n| format!("`{n}`")).join(", ")
+            ),
+        )
+        .await?;
+
+        Ok(())
+    })
+    .await;
+}
+
 async fn embeddings(client: &sd::Client, http: &Http, cmd: ApplicationCommandInteraction) {
     cmd.create(http, "Getting embeddings...").await.unwrap();
 
@@ -236,65 +759,597 @@ async fn embeddings(client: &sd::Client, http: &Http, cmd: ApplicationCommandInt
     .await;
 }
 
+async fn status(client: &sd::Client, http: &Http, cmd: ApplicationCommandInteraction) {
+    const REFRESH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+    const REFRESH_DURATION: std::time::Duration = std::time::Duration::from_secs(60);
+
+    cmd.create(http, "Getting status...").await.unwrap();
+
+    util::run_and_report_error(&cmd, http, async {
+        let start = std::time::Instant::now();
+        loop {
+            let (active, waiting) = util::generation_queue();
+            let progress =
+                util::fetch_progress_without_image(&Configuration::get().authentication).await?;
+            let model = client.options().await?.model;
+
+            let generation = if progress.progress_factor > 0.0 && !progress.is_finished() {
+                format!(
+                    "{:.02}% complete, {:.02}s remaining",
+                    progress.progress_factor * 100.0,
+                    progress.eta_seconds
+                )
+            } else {
+                "idle".to_string()
+            };
+            let done = start.elapsed() >= REFRESH_DURATION;
+
+            cmd.edit(
+                http,
+                &format!(
+                    "**Status**{}:\n- *Current generation*: {generation}\n- *Running*: {active}\n- *Queued*: {waiting}\n- *Model*: {model}",
+                    if done {
+                        " (no longer updating)".to_string()
+                    } else {
+                        format!(" (as of <t:{}:T>)", chrono::Utc::now().timestamp())
+                    },
+                ),
+            )
+            .await?;
+
+            if done {
+                break;
+            }
+            tokio::time::sleep(REFRESH_INTERVAL).await;
+        }
+
+        Ok(())
+    })
+    .await;
+}
+
+async fn help(models: &[sd::Model], http: &Http, cmd: ApplicationCommandInteraction) {
+    cmd.create(http, "Putting together help...").await.unwrap();
+
+    util::run_and_report_error(&cmd, http, async {
+        // subcommands are listed as commands in their own right, so that their options
+        // are shown alongside them
+        fn describe(
+            lines: &mut Vec<String>,
+            name: &str,
+            description: &str,
+            options: &[CommandOption],
+        ) {
+            lines.push(format!("- `/{name}`: {description}"));
+            for option in options {
+                match option.kind {
+                    CommandOptionType::SubCommand | CommandOptionType::SubCommandGroup => describe(
+                        lines,
+                        &format!("{name} {}", option.name),
+                        &option.description,
+                        &option.options,
+                    ),
+                    _ => lines.push(format!(
+                        "  - `{}`{}: {}",
+                        option.name,
+                        if option.required { " (required)" } else { "" },
+                        option.description
+                    )),
+                }
+            }
+        }
+
+        let config = Configuration::get();
+        let mut lines = vec![];
+        if let Some(message) = &config.general.help_message {
+            lines.push(message.clone());
+            lines.push(String::new());
+        }
+
+        // the commands are fetched from Discord so that they match what's been registered
+        let our_commands = config.commands.all();
+        lines.push("**Commands**".to_string());
+        for command in Command::get_global_application_commands(http)
+            .await?
+            .into_iter()
+            .filter(|c| our_commands.contains(&c.name))
+            .sorted_by(|a, b| a.name.cmp(&b.name))
+        {
+            describe(
+                &mut lines,
+                &command.name,
+                &command.description,
+                &command.options,
+            );
+        }
+
+        let limits = &config.limits;
+        lines.push(String::new());
+        lines.push("**Limits**".to_string());
+        lines.push(format!(
+            "- *Count*: {}-{}",
+            limits.count_min, limits.count_max
+        ));
+        lines.push(format!(
+            "- *Width*: {}-{}",
+            limits.width_min, limits.width_max
+        ));
+        lines.push(format!(
+            "- *Height*: {}-{}",
+            limits.height_min, limits.height_max
+        ));
+        lines.push(format!(
+            "- *Guidance scale*: {}-{}",
+            limits.guidance_scale_min, limits.guidance_scale_max
+        ));
+        lines.push(format!(
+            "- *Steps*: {}-{}",
+            limits.steps_min, limits.steps_max
+        ));
+        lines.push(format!(
+            "- *Prompt length*: {} characters",
+            limits.prompt_length_max
+        ));
+        for (name, quota) in [
+            ("Daily", limits.daily_quota),
+            ("Weekly", limits.weekly_quota),
+        ] {
+            if let Some(quota) = quota {
+                lines.push(format!("- *{name} quota*: {quota} images"));
+            }
+        }
+
+        let enabled = |enabled: bool| if enabled { "enabled" } else { "disabled" };
+        lines.push(String::new());
+        lines.push("**Features**".to_string());
+        lines.push(format!(
+            "- *Automatically prepending model keywords*: {}",
+            enabled(config.general.automatically_prepend_keyword)
+        ));
+        lines.push(format!(
+            "- *DeepDanbooru tag filtering*: {}",
+            enabled(config.deepdanbooru_tag_whitelist().is_some())
+        ));
+        lines.push(format!(
+            "- *Progress previews*: {}",
+            enabled(config.progress.show_preview)
+        ));
+        if !config.quality_presets.0.is_empty() {
+            lines.push(format!(
+                "- *Quality presets*: {}",
+                config
+                    .quality_presets
+                    .0
+                    .keys()
+                    .sorted()
+                    .map(|k| format!("`{k}`"))
+                    .join(", ")
+            ));
+        }
+
+        lines.push(String::new());
+        lines.push(format!("**Models** ({})", models.len()));
+        for model in models {
+            lines.push(format!(
+                "- `{}`{}",
+                model.name,
+                model
+                    .hash_short
+                    .as_deref()
+                    .map(|h| format!(" (`{h}`)"))
+                    .unwrap_or_default()
+            ));
+        }
+
+        util::chunked_response(http, &cmd, lines.iter().map(|s| s.as_str()), "\n").await?;
+
+        Ok(())
+    })
+    .await;
+}
+
+async fn params(store: &store::Store, http: &Http, cmd: ApplicationCommandInteraction) {
+    cmd.create(http, "Getting parameters...").await.unwrap();
+
+    util::run_and_report_error(&cmd, http, async {
+        let guild_id = util::require_guild(cmd.guild_id)?;
+        let id = util::get_value(&cmd.data.options[0].options, constant::value::ID)
+            .and_then(util::value_to_int)
+            .context("no id specified")?;
+        let generation = store
+            .get_generation(id)?
+            .filter(|g| g.guild_id == guild_id)
+            .with_context(|| format!("generation #{id} not found"))?;
+
+        cmd.edit(
+            http,
+            &format!(
+                "Parameters for #{id}:\n```\n{}\n```",
+                generation.as_a1111_parameters()
+            ),
+        )
+        .await?;
+
+        Ok(())
+    })
+    .await;
+}
+
+async fn settings(store: &store::Store, http: &Http, cmd: ApplicationCommandInteraction) {
+    cmd.create(http, "Processing settings request...")
+        .await
+        .unwrap();
+
+    util::run_and_report_error(&cmd, http, async {
+        let mut settings = store.get_user_settings(cmd.user.id)?;
+        if let Some(dm) = util::get_value(&cmd.data.options[0].options, constant::value::DM)
+            .and_then(util::value_to_bool)
+        {
+            settings.dm_results = dm;
+            store.set_user_settings(cmd.user.id, &settings)?;
+        }
+
+        cmd.edit(
+            http,
+            &format!(
+                "Your settings:\n- *Send /paint results to your DMs*: {}",
+                settings.dm_results
+            ),
+        )
+        .await?;
+
+        Ok(())
+    })
+    .await;
+}
+
+async fn model_preview(models: &[sd::Model], http: &Http, cmd: ApplicationCommandInteraction) {
+    cmd.create(http, "Fetching model preview...").await.unwrap();
+
+    util::run_and_report_error(&cmd, http, async {
+        let hash = util::get_value(&cmd.data.options[0].options, constant::value::MODEL)
+            .and_then(util::value_to_string)
+            .context("no model specified")?;
+        let (_, model) = util::find_model_by_hash(models, &hash)
+            .with_context(|| format!("no model with the hash `{hash}` is available"))?;
+
+        let bytes = util::fetch_model_preview(&Configuration::get().authentication, &model).await?;
+
+        cmd.get_interaction_message(http)
+            .await?
+            .edit(http, |m| {
+                m.content(format!("Preview of `{}`", model.name))
+                    .attachment((bytes.as_slice(), "preview.png"))
+            })
+            .await?;
+
+        Ok(())
+    })
+    .await;
+}
+
+async fn search(store: &store::Store, http: &Http, cmd: ApplicationCommandInteraction) {
+    const PAGE_SIZE: usize = 5;
+
+    cmd.create(http, "Searching...").await.unwrap();
+
+    util::run_and_report_error(&cmd, http, async {
+        let options = &cmd.data.options[0].options;
+        let query = util::get_value(options, constant::value::QUERY)
+            .and_then(util::value_to_string)
+            .unwrap_or_default();
+        let label = util::get_value(options, constant::value::LABEL)
+            .and_then(util::value_to_string)
+            .map(|l| l.trim().to_lowercase());
+        if query.is_empty() && label.is_none() {
+            anyhow::bail!("Please specify a query, a label, or both.");
+        }
+        let everyone = util::get_value(options, constant::value::EVERYONE)
+            .and_then(util::value_to_bool)
+            .unwrap_or(false);
+        let page = util::get_value(options, constant::value::PAGE)
+            .and_then(util::value_to_int)
+            .unwrap_or(1)
+            .max(1) as usize;
+
+        let guild_id = util::require_guild(cmd.guild_id)?;
+        let predicate = if everyone {
+            store::GenerationPredicate::Guild(guild_id)
+        } else {
+            store::GenerationPredicate::User(guild_id, cmd.user.id)
+        };
+        let (results, count) = store.search_generations(
+            predicate,
+            &query,
+            label.as_deref(),
+            (PAGE_SIZE, (page - 1) * PAGE_SIZE),
+        )?;
+
+        let criteria = [
+            Some(query.as_str())
+                .filter(|q| !q.is_empty())
+                .map(|q| format!("`{q}`")),
+            label.as_ref().map(|l| format!("label `{l}`")),
+        ]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join(" with ");
+        let page_count = count / PAGE_SIZE + usize::from(count % PAGE_SIZE != 0);
+        let content = if count == 0 {
+            format!("No generations found matching {criteria}.")
+        } else if results.is_empty() {
+            format!("There are only {page_count} page(s) of results for {criteria}.")
+        } else {
+            format!("Generations matching {criteria} (page {page} of {page_count}, {count} total):")
+        };
+
+        let (embeds, thumbnails) = search_result_embeds(results, everyone);
+
+        cmd.get_interaction_message(http)
+            .await?
+            .edit(http, |m| {
+                m.content(content).set_embeds(embeds);
+                for (bytes, filename) in &thumbnails {
+                    m.attachment((bytes.as_slice(), filename.as_str()));
+                }
+                m
+            })
+            .await?;
+
+        Ok(())
+    })
+    .await;
+}
+
+/// Builds an embed for each generation, linking to its message where possible, along with the
+/// stored thumbnails to attach to the message for them
+fn search_result_embeds(
+    results: Vec<store::SearchResult>,
+    show_user: bool,
+) -> (Vec<CreateEmbed>, Vec<(Vec<u8>, String)>) {
+    const PROMPT_LENGTH: usize = 200;
+
+    // the stored thumbnails are re-uploaded, as the original attachments may have expired or
+    // been deleted along with their message
+    let thumbnails: Vec<_> = results
+        .iter()
+        .filter_map(|r| Some((r.thumbnail.clone()?, format!("thumbnail_{}.png", r.id))))
+        .collect();
+
+    let embeds = results
+        .into_iter()
+        .map(|result| {
+            let mut embed = CreateEmbed::default();
+            let prompt: String = result.prompt.chars().take(PROMPT_LENGTH).collect();
+            embed.title(format!("#{}", result.id)).description(format!(
+                "`{prompt}`{}\n{}<t:{}:R>",
+                if prompt.len() < result.prompt.len() {
+                    "..."
+                } else {
+                    ""
+                },
+                if show_user {
+                    format!("{}, ", result.user_id.mention())
+                } else {
+                    String::new()
+                },
+                result.timestamp.timestamp()
+            ));
+            if let Some(url) = result.message_url.as_ref().or(result.image_url.as_ref()) {
+                embed.url(url);
+            }
+            if result.thumbnail.is_some() {
+                embed.thumbnail(format!("attachment://thumbnail_{}.png", result.id));
+            } else if let Some(image_url) = &result.image_url {
+                embed.thumbnail(image_url);
+            }
+            embed
+        })
+        .collect();
+
+    (embeds, thumbnails)
+}
+
+async fn recent(store: &store::Store, http: &Http, cmd: ApplicationCommandInteraction) {
+    cmd.create(http, "Fetching recent generations...")
+        .await
+        .unwrap();
+
+    util::run_and_report_error(&cmd, http, async {
+        let guild_id = util::require_guild(cmd.guild_id)?;
+        let mut message = cmd.get_interaction_message(http).await?;
+        show_recent_page(store, http, &mut message, guild_id, 1).await
+    })
+    .await;
+}
+
+/// Replaces the message's contents with the given page of the guild's recent generations,
+/// with buttons to move between pages
+pub async fn show_recent_page(
+    store: &store::Store,
+    http: &Http,
+    message: &mut Message,
+    guild_id: GuildId,
+    page: usize,
+) -> anyhow::Result<()> {
+    const PAGE_SIZE: usize = 5;
+
+    let (results, count) =
+        store.recent_generations(guild_id, (PAGE_SIZE, (page - 1) * PAGE_SIZE))?;
+    let page_count = (count / PAGE_SIZE + usize::from(count % PAGE_SIZE != 0)).max(1);
+    let content = if count == 0 {
+        "Nothing has been generated in this server yet.".to_string()
+    } else {
+        format!("Recent generations in this server (page {page} of {page_count}, {count} total):")
+    };
+    let (embeds, thumbnails) = search_result_embeds(results, true);
+
+    let existing_attachments: Vec<_> = message.attachments.iter().map(|a| a.id).collect();
+    message
+        .edit(http, |m| {
+            m.content(content).set_embeds(embeds);
+            for id in existing_attachments {
+                m.remove_existing_attachment(id);
+            }
+            for (bytes, filename) in &thumbnails {
+                m.attachment((bytes.as_slice(), filename.as_str()));
+            }
+            m.components(|c| {
+                c.create_action_row(|r| {
+                    for (value, label, disabled) in [
+                        (cid::Recent::Previous, "Previous", page <= 1),
+                        (cid::Recent::Next, "Next", page >= page_count),
+                    ] {
+                        r.create_button(|b| {
+                            b.label(label)
+                                .style(component::ButtonStyle::Secondary)
+                                .disabled(disabled)
+                                .custom_id(value.to_id(page as i64))
+                        });
+                    }
+                    r
+                })
+            })
+        })
+        .await?;
+
+    Ok(())
+}
+
 async fn stats(
     models: &[sd::Model],
     store: &store::Store,
+    user_names: &UserNameCache,
     http: &Http,
     cmd: ApplicationCommandInteraction,
 ) {
     cmd.create(http, "Getting stats...").await.unwrap();
 
     util::run_and_report_error(&cmd, http, async {
-        let stats = store.get_model_usage_counts(cmd.guild_id.context("no guild id")?)?;
+        let guild_id = util::require_guild(cmd.guild_id)?;
+        let stats = store.get_model_usage_counts(guild_id)?;
+        let compute_time = store.get_user_compute_time(guild_id)?;
         async fn get_user_name(
             http: &Http,
-            guild_id: Option<GuildId>,
+            user_names: &UserNameCache,
+            guild_id: GuildId,
             id: UserId,
         ) -> anyhow::Result<(String, UserId)> {
+            if let Some((name, _)) = user_names
+                .lock()
+                .get(&(guild_id, id))
+                .filter(|(_, looked_up)| looked_up.elapsed() < USER_NAME_CACHE_TTL)
+            {
+                return Ok((name.clone(), id));
+            }
+
             let user = id.to_user(http).await?;
-            let name = if let Some(guild_id) = guild_id {
-                user.nick_in(http, guild_id).await
-            } else {
-                None
-            };
-            Ok((name.unwrap_or(user.name), id))
+            let name = user.nick_in(http, guild_id).await.unwrap_or(user.name);
+            // expired names are dropped, so that the cache only holds recent lookups
+            let mut user_names = user_names.lock();
+            user_names.retain(|_, (_, looked_up)| looked_up.elapsed() < USER_NAME_CACHE_TTL);
+            user_names.insert((guild_id, id), (name.clone(), Instant::now()));
+            Ok((name, id))
         }
 
-        let mut users = futures::future::join_all(
-            stats
-                .keys()
-                .map(|id| get_user_name(http, cmd.guild_id, *id)),
-        )
-        .await
-        .into_iter()
-        .collect::<Result<Vec<_>, _>>()?;
+        // collected up-front so that the stream doesn't borrow the closure
+        let lookups: Vec<_> = stats
+            .keys()
+            .map(|id| get_user_name(http, user_names, guild_id, *id))
+            .collect();
+        let mut users = futures::stream::iter(lookups)
+            .buffer_unordered(USER_NAME_LOOKUP_CONCURRENCY)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()?;
         users.sort_by(|a, b| a.0.cmp(&b.0));
 
-        let guild = http
-            .get_guild(*cmd.guild_id.context("no guild id")?.as_u64())
-            .await?;
+        let guild = http.get_guild(*guild_id.as_u64()).await?;
         let header = format!("**Statistics for server *{}***:", guild.name);
 
+        let format = util::get_value(&cmd.data.options[0].options, constant::value::FORMAT)
+            .and_then(util::value_to_string);
+        if format.as_deref() == Some("collage") {
+            // ranked by how many images each user has generated
+            let totals: HashMap<UserId, u64> = stats
+                .iter()
+                .map(|(id, usage)| (*id, usage.iter().map(|u| u.total()).sum()))
+                .collect();
+            users.sort_by_key(|(_, id)| std::cmp::Reverse(totals[id]));
+
+            let mut thumbnails = store.get_latest_thumbnail_per_user(guild_id)?;
+            let entries: Vec<_> = users
+                .iter()
+                .filter_map(|(name, id)| Some((name, id, thumbnails.remove(id)?)))
+                .enumerate()
+                .map(|(rank, (name, id, (generation_id, thumbnail)))| {
+                    (
+                        thumbnail,
+                        format!("{}. {name} ({}, #{generation_id})", rank + 1, totals[id]),
+                    )
+                })
+                .collect();
+            if entries.is_empty() {
+                cmd.edit(http, &format!("{header}\nNo generations yet."))
+                    .await?;
+                return Ok(());
+            }
+
+            let columns = Configuration::get().general.leaderboard_columns;
+            let bytes = util::run_image_task(move || {
+                let images = entries
+                    .into_iter()
+                    .map(|(thumbnail, label)| {
+                        anyhow::Ok((
+                            image::load_from_memory(&thumbnail)?.thumbnail(256, 256),
+                            label,
+                        ))
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                util::encode_image_to_png_bytes(util::make_contact_sheet(&images, columns)?)
+            })
+            .await?;
+            cmd.get_interaction_message(http)
+                .await?
+                .edit(http, |m| {
+                    m.content(header)
+                        .attachment((bytes.as_slice(), "leaderboard.png"))
+                })
+                .await?;
+            return Ok(());
+        }
+
         let mut body = users
             .into_iter()
             .flat_map(|(user_name, user_id)| {
-                std::iter::once(format!("**{user_name}**"))
-                    .chain(
-                        stats
-                            .get(&user_id)
-                            .unwrap()
-                            .iter()
-                            .map(|(model_hash, count)| {
-                                format!(
-                                    "- {}: {} generations",
-                                    util::find_model_by_hash(models, model_hash)
-                                        .as_ref()
-                                        .map(|m| m.1.name.clone())
-                                        .unwrap_or_else(|| format!("unknown model [{model_hash}]")),
-                                    count
-                                )
-                            }),
-                    )
+                let compute_time = compute_time
+                    .get(&user_id)
+                    .filter(|(_, count)| *count > 0)
+                    .map(|(total_ms, count)| {
+                        format!(
+                            " ({:.1} min total, {:.1}s avg)",
+                            *total_ms as f64 / 60_000.0,
+                            *total_ms as f64 / *count as f64 / 1_000.0
+                        )
+                    })
+                    .unwrap_or_default();
+
+                std::iter::once(format!("**{user_name}**{compute_time}"))
+                    .chain(stats.get(&user_id).unwrap().iter().map(|usage| {
+                        let model_hash = &usage.model_hash;
+                        format!(
+                            "- {}: {} generations ({} txt2img, {} img2img)",
+                            util::find_model_by_hash(models, model_hash)
+                                .as_ref()
+                                .map(|m| m.1.name.clone())
+                                .unwrap_or_else(|| format!("unknown model [{model_hash}]")),
+                            usage.total(),
+                            usage.text_count,
+                            usage.image_count
+                        )
+                    }))
                     .chain(std::iter::once(String::new()))
             })
             .collect::<Vec<String>>();
@@ -323,10 +1378,41 @@ pub async fn paint(
         .unwrap();
 
     util::run_and_report_error(&aci, http, async {
-        let params = command::GenerationParameters::load(
+        let mut options =
+            command::GenerationOptions::from_command_options(&aci.data.options, models)?;
+        // reserved before any work is done; it's handed back if this doesn't generate
+        let reservation = util::reserve_quota(store, &aci, options.batch_count() as usize)?;
+        // without a prompt, one is derived from the image by interrogating it with CLIP
+        let derived_prompt = options.prompt.is_none() && options.image_url.is_some();
+        let random_model = options.model.as_deref() == Some(constant::value::MODEL_RANDOM);
+        if let Some(url) = &options.image_url {
+            let (id, url, image) = util::resolve_upload(
+                store,
+                aci.user().id,
+                util::require_guild(aci.guild_id())?,
+                url,
+            )
+            .await?;
+            // referred to by ID from here on, so that it isn't downloaded again
+            options.image_url = Some(format!("#{id}"));
+
+            if derived_prompt {
+                aci.edit(
+                    http,
+                    &format!("Interrogating {url} with CLIP to derive a prompt..."),
+                )
+                .await?;
+                options.prompt =
+                    Some(issuer::interrogate(client, &image, sd::Interrogator::Clip, false).await?);
+            }
+        }
+
+        let denoising_strength_note =
+            command::GenerationParameters::denoising_strength_note(&options);
+        let params = command::GenerationParameters::from_options(
             aci.user().id,
-            aci.guild_id().context("no guild id")?,
-            &aci.data.options,
+            util::require_guild(aci.guild_id())?,
+            options,
             store,
             models,
             true,
@@ -334,32 +1420,95 @@ pub async fn paint(
         )
         .await?;
 
+        let dry_run = util::get_value(&aci.data.options, constant::value::DRY_RUN)
+            .and_then(util::value_to_bool)
+            .unwrap_or(false);
+        if dry_run {
+            aci.edit(
+                http,
+                &format!(
+                    "Dry run; this request would generate with the following settings:\n{}{}",
+                    params
+                        .settings()
+                        .into_iter()
+                        .map(|(key, value)| format!("- *{key}*: {value}"))
+                        .join("\n"),
+                    params
+                        .resolution_warning()
+                        .map(|w| format!("\n\n{w}"))
+                        .unwrap_or_default()
+                ),
+            )
+            .await?;
+            return Ok(());
+        }
+
         let base = params.base_generation();
         aci.edit(
             http,
             &format!(
-                "`{}`{}: Generating (waiting for start)...",
+                "`{}`{}{}{}: Generating (waiting for start)...",
                 &base.prompt,
                 base.negative_prompt
                     .as_ref()
                     .filter(|s| !s.is_empty())
                     .map(|s| format!(" - `{s}`"))
+                    .unwrap_or_default(),
+                if derived_prompt {
+                    " (prompt derived with CLIP)"
+                } else {
+                    ""
+                },
+                base.model
+                    .as_ref()
+                    .filter(|_| random_model)
+                    .map(|m| format!(" with randomly chosen model `{}`", m.name))
                     .unwrap_or_default()
             ),
         )
         .await?;
 
+        // sent separately, as the progress updates replace the reply's content
+        for warning in [params.resolution_warning(), denoising_strength_note]
+            .into_iter()
+            .flatten()
+        {
+            aci.create_followup_message(http, |m| m.ephemeral(true).content(warning))
+                .await?;
+        }
+
+        // if the DM channel can't be opened, the result is posted here as usual
+        let dm_channel = if store.get_user_settings(aci.user.id)?.dm_results {
+            match aci.user.create_dm_channel(http).await {
+                Ok(channel) => Some(channel.id),
+                Err(err) => {
+                    tracing::warn!("failed to open DM channel for {}: {err}", aci.user.id);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
         let (prompt, negative_prompt) = (base.prompt.clone(), base.negative_prompt.clone());
-        issuer::generation_task(
+        let outcome = issuer::generation_task(
             (client, models),
-            tokio::task::spawn(params.clone().generate(client)),
+            util::spawn_generation(params.clone().generate(client)),
             store,
             http,
-            (&aci, None),
+            (&aci, dm_channel, ButtonProfiles::DEFAULT),
             (&prompt, negative_prompt.as_deref()),
-            params.image_generation(),
+            (
+                params.image_generation(),
+                base.eta,
+                (base.denoising_strength, params.hires_denoising_strength()),
+                None,
+            ),
         )
-        .await
+        .await?;
+        reservation.settle(outcome);
+
+        Ok(())
     })
     .await;
 }
@@ -375,23 +1524,22 @@ pub async fn postprocess(client: &sd::Client, http: &Http, aci: ApplicationComma
 
         aci.edit(http, &format!("Postprocessing {url}...")).await?;
 
-        let bytes = reqwest::get(&url).await?.bytes().await?;
-        let image = image::load_from_memory(&bytes)?;
+        let image = util::download_image(&url).await?;
 
         let upscaler_1 = util::get_value(options, constant::value::UPSCALER_1)
             .and_then(util::value_to_string)
             .and_then(|v| sd::Upscaler::try_from(v.as_str()).ok())
-            .context("expected upscaler 1")?;
+            .unwrap_or(sd::Upscaler::None);
 
         let upscaler_2 = util::get_value(options, constant::value::UPSCALER_2)
             .and_then(util::value_to_string)
             .and_then(|v| sd::Upscaler::try_from(v.as_str()).ok())
-            .context("expected upscaler 2")?;
+            .unwrap_or(sd::Upscaler::None);
 
         let scale_factor = util::get_value(options, constant::value::SCALE_FACTOR)
             .and_then(util::value_to_number)
             .map(|n| n as f32)
-            .context("expected scale factor")?;
+            .unwrap_or(1.0);
 
         let codeformer_visibility =
             util::get_value(options, constant::value::CODEFORMER_VISIBILITY)
@@ -414,6 +1562,15 @@ pub async fn postprocess(client: &sd::Client, http: &Http, aci: ApplicationComma
         let upscale_first =
             util::get_value(options, constant::value::UPSCALE_FIRST).and_then(util::value_to_bool);
 
+        let upscaling = upscaler_1 != sd::Upscaler::None || upscaler_2 != sd::Upscaler::None;
+        let face_restoration = [codeformer_visibility, gfpgan_visibility]
+            .into_iter()
+            .flatten()
+            .any(|v| v > 0.0);
+        if !upscaling && !face_restoration {
+            anyhow::bail!("Nothing to do: specify an upscaler, or a CodeFormer or GFPGAN visibility for face restoration.");
+        }
+
         let result = client
             .postprocess(
                 &image,
@@ -431,7 +1588,7 @@ pub async fn postprocess(client: &sd::Client, http: &Http, aci: ApplicationComma
             )
             .await?;
 
-        let bytes = util::encode_image_to_png_bytes(result)?;
+        let bytes = util::run_image_task(move || util::encode_image_to_png_bytes(result)).await?;
 
         aci.get_interaction_message(http)
             .await?
@@ -458,24 +1615,29 @@ pub async fn interrogate(
 
     util::run_and_report_error(&aci, http, async {
         let url = util::get_image_url(&aci.data.options).context("no url specified")?;
+        let (_, url, image) =
+            util::resolve_upload(store, aci.user.id, util::require_guild(aci.guild_id)?, &url)
+                .await?;
 
         let interrogator = util::get_value(&aci.data.options, constant::value::INTERROGATOR)
             .and_then(util::value_to_string)
             .and_then(|v| sd::Interrogator::try_from(v.as_str()).ok())
             .context("expected interrogator")?;
 
+        let filtered = util::get_value(&aci.data.options, constant::value::FILTERED)
+            .and_then(util::value_to_bool)
+            .unwrap_or(true);
+
         aci.edit(http, &format!("Interrogating {url} with {interrogator}..."))
             .await?;
 
-        let bytes = reqwest::get(&url).await?.bytes().await?;
-        let image = image::load_from_memory(&bytes)?;
-
         issuer::interrogate_task(
             client,
             store,
             &aci,
             http,
             (image, store::InterrogationSource::Url(url), interrogator),
+            filtered,
         )
         .await?;
 
@@ -497,7 +1659,7 @@ pub async fn png_info(client: &sd::Client, http: &Http, aci: ApplicationCommandI
             .edit(http, &format!("Reading PNG info of {url}..."))
             .await?;
 
-        let bytes = reqwest::get(&url).await?.bytes().await?;
+        let bytes = util::download_image_bytes(&url).await?;
         let result = client.png_info(&bytes).await?;
         interaction.edit(http, &result).await?;
 
@@ -505,3 +1667,247 @@ pub async fn png_info(client: &sd::Client, http: &Http, aci: ApplicationCommandI
     })
     .await;
 }
+
+pub async fn compare(
+    models: &[sd::Model],
+    store: &store::Store,
+    http: &Http,
+    aci: ApplicationCommandInteraction,
+) {
+    aci.create(http, "Compare request received, processing...")
+        .await
+        .unwrap();
+
+    util::run_and_report_error(&aci, http, async {
+        let guild_id = util::require_guild(aci.guild_id)?;
+        let generations = [constant::value::GENERATION_1, constant::value::GENERATION_2]
+            .into_iter()
+            .map(|name| {
+                let id = util::get_value(&aci.data.options, name)
+                    .and_then(util::value_to_int)
+                    .with_context(|| format!("expected {name}"))?;
+                store
+                    .get_generation(id)?
+                    .filter(|g| g.guild_id == guild_id)
+                    .with_context(|| format!("generation {id} not found"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        // only label the parameters that actually differ between the generations
+        let fields: Vec<Vec<(&str, String)>> = generations
+            .iter()
+            .map(|g| {
+                vec![
+                    ("model", util::model_hash_to_name(models, &g.model_hash)),
+                    ("prompt", g.prompt.clone()),
+                    (
+                        "negative prompt",
+                        g.negative_prompt.clone().unwrap_or_default(),
+                    ),
+                    ("seed", g.seed.to_string()),
+                    ("size", format!("{}x{}", g.width, g.height)),
+                    ("sampler", g.sampler.to_string()),
+                    ("steps", g.steps.to_string()),
+                    ("guidance scale", g.cfg_scale.to_string()),
+                    ("denoising strength", g.denoising_strength.to_string()),
+                ]
+            })
+            .collect();
+
+        let labels: Vec<_> = generations
+            .iter()
+            .zip(fields.iter())
+            .map(|(generation, generation_fields)| {
+                let differences = generation_fields
+                    .iter()
+                    .enumerate()
+                    .filter(|(idx, (_, value))| fields.iter().any(|f| &f[*idx].1 != value))
+                    .map(|(_, (key, value))| format!("{key}: {value}"))
+                    .join(", ");
+
+                format!(
+                    "#{}{}",
+                    generation.id.unwrap_or_default(),
+                    if differences.is_empty() {
+                        String::new()
+                    } else {
+                        format!(" ({differences})")
+                    }
+                )
+            })
+            .collect();
+
+        let bytes = util::run_image_task({
+            let labels = labels.clone();
+            move || {
+                let images = generations
+                    .into_iter()
+                    .zip(labels)
+                    .map(|(generation, label)| {
+                        anyhow::Ok((image::load_from_memory(&generation.image)?, label))
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                util::encode_image_to_png_bytes(util::make_contact_sheet(&images, images.len())?)
+            }
+        })
+        .await?;
+
+        aci.get_interaction_message(http)
+            .await?
+            .edit(http, |m| {
+                m.content(format!(
+                    "Comparison of {} for {}",
+                    labels
+                        .iter()
+                        .map(|label| format!("`{label}`"))
+                        .join(" and "),
+                    aci.user.mention()
+                ))
+                .attachment((bytes.as_slice(), "compare.png"))
+            })
+            .await?;
+
+        Ok(())
+    })
+    .await;
+}
+
+/// Suggests completions for the prompt option from the user's recent prompts, for the
+/// LoRA option, completing the last entry of the comma-separated list, for model names,
+/// for Wirehead's tag lists, and for the configuration keys that can be changed
+pub async fn autocomplete(
+    models: &[sd::Model],
+    loras: &[String],
+    store: &store::Store,
+    http: &Http,
+    aci: AutocompleteInteraction,
+) {
+    // Discord allows at most 25 suggestions, each of which can be at most 100 characters
+    const MAX_CHOICES: usize = 25;
+    const MAX_CHOICE_LENGTH: usize = 100;
+
+    // the focused option may be nested within a subcommand, or a subcommand group
+    let Some(focused) = aci
+        .data
+        .options
+        .iter()
+        .chain(aci.data.options.iter().flat_map(|o| o.options.iter()))
+        .chain(
+            aci.data
+                .options
+                .iter()
+                .flat_map(|o| o.options.iter())
+                .flat_map(|o| o.options.iter()),
+        )
+        .find(|o| o.focused)
+    else {
+        return;
+    };
+    let value = focused
+        .value
+        .as_ref()
+        .and_then(|v| v.as_str())
+        .unwrap_or_default();
+
+    if focused.name == constant::value::MODEL {
+        let partial = value.to_lowercase();
+        // generation commands that have too many models for choices fall back to autocomplete,
+        // and should still offer the random choice; the model preview has no use for it
+        let random = Some((
+            "Random (weighted by your usage)",
+            constant::value::MODEL_RANDOM,
+        ))
+        .filter(|_| !aci.data.options.iter().any(|o| o.name == "modelpreview"))
+        .filter(|(name, _)| name.to_lowercase().contains(&partial));
+        let choices = models
+            .iter()
+            .filter(|m| m.name.to_lowercase().contains(&partial))
+            .filter_map(|m| Some((m.name.as_str(), m.hash_short.as_deref()?)))
+            .filter(|(name, _)| name.len() <= MAX_CHOICE_LENGTH)
+            .take(MAX_CHOICES - usize::from(random.is_some()))
+            .chain(random);
+        if let Err(err) = aci
+            .create_autocomplete_response(http, |r| {
+                for (name, hash) in choices {
+                    r.add_string_choice(name, hash);
+                }
+                r
+            })
+            .await
+        {
+            tracing::warn!("failed to send autocomplete response: {err}");
+        }
+        return;
+    }
+
+    let choices: Vec<String> = if focused.name == constant::value::PROMPT {
+        // fetch extra prompts to make up for the ones too long to suggest
+        match store.recent_prompts_for_user(aci.user.id, value, MAX_CHOICES * 2) {
+            Ok(prompts) => prompts
+                .into_iter()
+                .filter(|choice| choice.len() <= MAX_CHOICE_LENGTH)
+                .take(MAX_CHOICES)
+                .collect(),
+            Err(err) => {
+                tracing::warn!("failed to fetch recent prompts: {err:?}");
+                return;
+            }
+        }
+    } else if focused.name == constant::value::LORA {
+        let (prefix, partial) = match value.rsplit_once(',') {
+            Some((prefix, partial)) => (format!("{}, ", prefix.trim_end()), partial),
+            None => (String::new(), value),
+        };
+        let partial = partial.trim().to_lowercase();
+
+        loras
+            .iter()
+            .filter(|lora| lora.to_lowercase().contains(&partial))
+            .map(|lora| format!("{prefix}{lora}"))
+            .filter(|choice| choice.len() <= MAX_CHOICE_LENGTH)
+            .take(MAX_CHOICES)
+            .collect()
+    } else if focused.name == constant::value::TAGS {
+        // a URL to a tag file is passed through as-is, if it fits
+        let partial = value.trim().to_lowercase();
+        let url = Some(value.trim().to_string())
+            .filter(|v| v.starts_with("http://") || v.starts_with("https://"));
+        let config = Configuration::get();
+        url.into_iter()
+            .chain(
+                config
+                    .tags()
+                    .keys()
+                    .filter(|name| name.to_lowercase().contains(&partial))
+                    .sorted()
+                    .cloned(),
+            )
+            .filter(|choice| choice.len() <= MAX_CHOICE_LENGTH)
+            .take(MAX_CHOICES)
+            .collect()
+    } else if focused.name == constant::value::CONFIG_KEY {
+        let partial = value.trim().to_lowercase();
+        Configuration::get()
+            .runtime_values()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(key, _)| key)
+            .filter(|key| key.contains(&partial))
+            .take(MAX_CHOICES)
+            .collect()
+    } else {
+        return;
+    };
+
+    if let Err(err) = aci
+        .create_autocomplete_response(http, |r| {
+            for choice in choices {
+                r.add_string_choice(&choice, &choice);
+            }
+            r
+        })
+        .await
+    {
+        tracing::warn!("failed to send autocomplete response: {err}");
+    }
+}
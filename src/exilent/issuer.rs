@@ -1,99 +1,267 @@
 use crate::{
     cid,
-    config::Configuration,
+    config::{Configuration, Emojis, GenerationButton},
     store::{self, Store},
     util::{self, DiscordInteraction},
 };
 use anyhow::Context;
+use once_cell::sync::Lazy;
 use serenity::{
+    builder::CreateButton,
     http::Http,
-    model::prelude::{component, ChannelId, ReactionType},
+    model::prelude::{component, Channel, ChannelId},
     prelude::Mentionable,
 };
 use stable_diffusion_a1111_webui_client as sd;
 use std::time::Duration;
 
+/// The image-to-image and hires fix denoising strengths of a request, respectively
+pub type DenoisingStrengths = (Option<f32>, Option<f32>);
+/// The ID of the generation a request was derived from, and how it was derived
+pub type Parent = (i64, store::Derivation);
+
+#[tracing::instrument(
+    skip_all,
+    fields(user = %interaction.user().id, prompt = %prompt, negative_prompt = ?negative_prompt)
+)]
 pub async fn generation_task(
     (client, models): (&sd::Client, &[sd::Model]),
-    task: tokio::task::JoinHandle<sd::Result<sd::GenerationResult>>,
+    task: util::GenerationTask<sd::Result<sd::GenerationResult>>,
     store: &Store,
     http: &Http,
-    (interaction, result_channel_override): (&dyn DiscordInteraction, Option<ChannelId>),
+    (interaction, result_channel_override, button_profile): (
+        &dyn DiscordInteraction,
+        Option<ChannelId>,
+        &str,
+    ),
     (prompt, negative_prompt): (&str, Option<&str>),
-    image_generation: Option<store::ImageGeneration>,
-) -> anyhow::Result<()> {
+    // these aren't reported back by the web UI, so we need to carry them over from the request
+    (image_generation, eta, (denoising_strength, hires_denoising_strength), parent): (
+        Option<store::ImageGeneration>,
+        Option<f32>,
+        DenoisingStrengths,
+        Option<Parent>,
+    ),
+) -> anyhow::Result<util::GenerationOutcome> {
     // How many seconds to subtract from the time of job issuance to accommodate for
     // early starts
     const START_TIME_SLACK: i64 = 2;
+    // The most progress frames kept for the animated preview, to bound memory use
+    const ANIMATED_PREVIEW_MAX_FRAMES: usize = 32;
+    // How many new frames to wait for before updating the animated preview
+    const ANIMATED_PREVIEW_FRAMES_PER_UPDATE: usize = 4;
 
     // generate and update progress
     let mut max_progress_factor = 0.0;
+    // the backend's progress and when it last changed, to detect stuck generations; the
+    // time spent waiting for a slot doesn't count
+    let mut last_progress = (0.0, std::time::Instant::now());
+    let animated_preview = Configuration::get().progress.animated_preview;
+    let mut preview_frames = std::collections::VecDeque::new();
+    let mut new_frame_count = 0;
+    // the image being painted over, shown next to the preview so that the two can be compared
+    let init_thumbnail = match image_generation
+        .as_ref()
+        .filter(|_| Configuration::get().progress.show_init_image)
+    {
+        Some(ig) => {
+            let init_image = ig.init_image.clone();
+            Some(util::run_image_task(move || util::make_thumbnail(&init_image)).await?)
+        }
+        None => None,
+    };
+    let mut init_thumbnail_attached = false;
 
     let start_time = chrono::Local::now() - chrono::Duration::seconds(START_TIME_SLACK);
 
+    // the button is added up front, so that generations can be cancelled while they're queued
+    let cancellation = util::Cancellation::register(interaction.id(), interaction.user().id);
+    set_cancel_button(http, interaction, true).await;
+
+    tracing::info!("generation started");
     loop {
-        let progress = client.progress().await?;
+        if cancellation.is_cancelled() {
+            tracing::info!("generation cancelled");
+            // only interrupt the backend if it's working on this generation, and not another
+            if task.has_started() {
+                if let Err(err) =
+                    util::interrupt_generation(&Configuration::get().authentication).await
+                {
+                    tracing::warn!("failed to interrupt generation: {err}");
+                }
+            }
+            task.handle.abort();
+            interaction
+                .get_interaction_message(http)
+                .await?
+                .edit(http, |m| {
+                    m.content(format!("`{prompt}`: Cancelled."))
+                        .set_components(Default::default())
+                })
+                .await?;
+            return Ok(util::GenerationOutcome::Cancelled);
+        }
+
+        let progress = if Configuration::get().progress.show_preview {
+            client.progress().await.map_err(anyhow::Error::from)
+        } else {
+            util::fetch_progress_without_image(&Configuration::get().authentication).await
+        }
+        .map_err(|err| {
+            tracing::warn!("failed to retrieve progress: {err}");
+            err
+        })?;
 
         // Only update the message if the ongoing job was started after
         // this job was issued
         if progress.job_timestamp.unwrap_or(start_time) >= start_time {
-            let image_bytes = progress
-                .current_image
-                .as_ref()
-                .map(|i| {
-                    util::encode_image_to_png_bytes(i.resize(
-                        ((i.width() as f32) * Configuration::get().progress.scale_factor) as u32,
-                        ((i.height() as f32) * Configuration::get().progress.scale_factor) as u32,
-                        image::imageops::FilterType::Nearest,
-                    ))
-                })
-                .transpose()?;
+            let preview = progress.current_image.as_ref().map(|i| {
+                i.resize(
+                    ((i.width() as f32) * Configuration::get().progress.scale_factor) as u32,
+                    ((i.height() as f32) * Configuration::get().progress.scale_factor) as u32,
+                    image::imageops::FilterType::Nearest,
+                )
+            });
 
-            max_progress_factor = progress.progress_factor.max(max_progress_factor);
+            // when animating, frames are accumulated and the message is only updated once
+            // enough new frames have come in
+            let (attachment, should_update) = match preview {
+                Some(preview) if animated_preview => {
+                    preview_frames.push_back(preview);
+                    if preview_frames.len() > ANIMATED_PREVIEW_MAX_FRAMES {
+                        preview_frames.pop_front();
+                    }
+                    new_frame_count += 1;
 
-            interaction
-                .get_interaction_message(http)
-                .await?
-                .edit(http, |m| {
-                    m.content(format!(
-                        "`{}`{}{}: {:.02}% complete. ({:.02} seconds remaining)",
-                        prompt,
-                        negative_prompt
-                            .filter(|s| !s.is_empty())
-                            .map(|s| format!(" - `{s}`"))
-                            .unwrap_or_default(),
-                        image_generation
-                            .as_ref()
-                            .map(|ig| format!(" for {}", ig.init_url))
-                            .unwrap_or_default(),
-                        max_progress_factor * 100.0,
-                        progress.eta_seconds
-                    ));
-
-                    if let Some(image_bytes) = &image_bytes {
-                        if let Some(a) = m.0.get_mut("attachments").and_then(|e| e.as_array_mut()) {
-                            a.clear();
-                        }
-                        m.attachment((image_bytes.as_slice(), "progress.png"));
+                    if new_frame_count >= ANIMATED_PREVIEW_FRAMES_PER_UPDATE {
+                        new_frame_count = 0;
+                        let frames: Vec<_> = preview_frames.iter().cloned().collect();
+                        let bytes = util::run_image_task(move || {
+                            util::encode_frames_to_gif(
+                                frames,
+                                Configuration::get().progress.update_ms,
+                            )
+                        })
+                        .await?;
+                        (Some((bytes, "progress.gif")), true)
+                    } else {
+                        (None, false)
                     }
+                }
+                Some(preview) => (
+                    Some((
+                        util::run_image_task(move || util::encode_image_to_png_bytes(preview))
+                            .await?,
+                        "progress.png",
+                    )),
+                    true,
+                ),
+                None => (None, true),
+            };
 
-                    m
-                })
-                .await?;
+            max_progress_factor = progress.progress_factor.max(max_progress_factor);
+            tracing::debug!(
+                progress = max_progress_factor,
+                eta_seconds = progress.eta_seconds,
+                "generation progress"
+            );
+
+            if should_update {
+                // the attachments are replaced wholesale, so the thumbnail has to be sent
+                // again whenever the preview changes
+                let attach_init =
+                    init_thumbnail.is_some() && (attachment.is_some() || !init_thumbnail_attached);
+                let update = async {
+                    interaction
+                        .get_interaction_message(http)
+                        .await?
+                        .edit(http, |m| {
+                            m.content(format!(
+                                "`{}`{}{}: {:.02}% complete. ({:.02} seconds remaining)",
+                                prompt,
+                                negative_prompt
+                                    .filter(|s| !s.is_empty())
+                                    .map(|s| format!(" - `{s}`"))
+                                    .unwrap_or_default(),
+                                image_generation
+                                    .as_ref()
+                                    .map(|ig| format!(" for {}", ig.init_url))
+                                    .unwrap_or_default(),
+                                max_progress_factor * 100.0,
+                                progress.eta_seconds
+                            ));
+
+                            if attachment.is_some() || attach_init {
+                                if let Some(a) =
+                                    m.0.get_mut("attachments").and_then(|e| e.as_array_mut())
+                                {
+                                    a.clear();
+                                }
+                            }
+                            if let Some(init) = init_thumbnail.as_ref().filter(|_| attach_init) {
+                                m.attachment((init.as_slice(), "init.png"));
+                            }
+                            if let Some((bytes, filename)) = &attachment {
+                                m.attachment((bytes.as_slice(), *filename));
+                            }
+
+                            m
+                        })
+                        .await?;
+                    anyhow::Ok(())
+                };
+                // a missed progress update isn't worth failing the generation over
+                match update.await {
+                    Ok(()) => init_thumbnail_attached |= attach_init,
+                    Err(err) => tracing::warn!("failed to update progress, skipping: {err}"),
+                }
+            }
         }
 
-        if task.is_finished() {
+        if task.handle.is_finished() {
             break;
         }
 
+        if !task.has_started() || progress.progress_factor != last_progress.0 {
+            last_progress = (progress.progress_factor, std::time::Instant::now());
+        } else {
+            let timeout = Configuration::get().general.generation_timeout_seconds;
+            if timeout > 0 && last_progress.1.elapsed() > Duration::from_secs(timeout) {
+                tracing::warn!("generation made no progress for {timeout} seconds");
+                if let Err(err) =
+                    util::interrupt_generation(&Configuration::get().authentication).await
+                {
+                    tracing::warn!("failed to interrupt generation: {err}");
+                }
+                task.handle.abort();
+                anyhow::bail!("Generation timed out");
+            }
+        }
+
         tokio::time::sleep(Duration::from_millis(
             Configuration::get().progress.update_ms,
         ))
         .await;
     }
 
+    // it's too late to cancel once the images are being uploaded
+    drop(cancellation);
+    set_cancel_button(http, interaction, false).await;
+
     // retrieve result
-    let result = task.await??;
+    let result = task.handle.await?.map_err(|err| {
+        tracing::warn!("generation failed: {err}");
+        err
+    })?;
+    let duration_ms = (chrono::Local::now() - result.info.job_timestamp)
+        .num_milliseconds()
+        .max(0) as u64
+        / result.pngs.len().max(1) as u64;
+    tracing::info!(
+        images = result.pngs.len(),
+        duration_ms,
+        "generation complete"
+    );
     let images = result
         .pngs
         .into_iter()
@@ -101,24 +269,26 @@ pub async fn generation_task(
         .map(|(idx, image)| Ok((format!("image_{idx}.png"), image)))
         .collect::<anyhow::Result<Vec<_>>>()?;
 
+    // results sent to DMs only get the buttons that work outside of a server
+    let override_is_dm = match result_channel_override {
+        Some(channel_id) => matches!(channel_id.to_channel(http).await, Ok(Channel::Private(_))),
+        None => false,
+    };
+
     // send images
     for (idx, ((filename, bytes), seed)) in images.iter().zip(result.info.seeds.iter()).enumerate()
     {
-        interaction
-            .edit(
-                http,
-                &format!(
-                    "`{}`{}: Uploading {}/{}...",
-                    prompt,
-                    negative_prompt
-                        .filter(|s| !s.is_empty())
-                        .map(|s| format!(" - `{s}`"))
-                        .unwrap_or_default(),
-                    idx + 1,
-                    images.len()
-                ),
-            )
-            .await?;
+        let uploading_message = format!(
+            "`{}`{}: Uploading {}/{}...",
+            prompt,
+            negative_prompt
+                .filter(|s| !s.is_empty())
+                .map(|s| format!(" - `{s}`"))
+                .unwrap_or_default(),
+            idx + 1,
+            images.len()
+        );
+        util::retry_if_rate_limited(|| interaction.edit(http, &uploading_message)).await?;
 
         let generation = store::Generation {
             id: None,
@@ -139,96 +309,331 @@ pub async fn generation_task(
             image_url: None,
             timestamp: result.info.job_timestamp,
             user_id: interaction.user().id,
-            guild_id: interaction.guild_id().context("no guild id")?,
-            denoising_strength: result.info.denoising_strength,
+            guild_id: util::require_guild(interaction.guild_id())?,
+            // with hires fix, the web UI reports the upscaling pass's strength instead
+            denoising_strength: match hires_denoising_strength {
+                Some(_) => denoising_strength.unwrap_or(result.info.denoising_strength),
+                None => result.info.denoising_strength,
+            },
+            hires_denoising_strength,
+            eta,
+            duration_ms: Some(duration_ms),
+            parent_generation_id: parent.map(|(id, _)| id),
+            derivation: parent.map(|(_, derivation)| derivation),
             image_generation: image_generation.clone(),
         };
+        // with embeds, the parameters are shown in the embed rather than the message
+        let (message, embed) = if Configuration::get().general.use_embeds {
+            let embed = (
+                generation.prompt.clone(),
+                generation.as_embed_fields(models),
+            );
+            (None, Some(embed))
+        } else {
+            (Some(generation.as_message(models)), None)
+        };
+        let bytes = util::run_image_task({
+            let bytes = bytes.clone();
+            let parameters = generation.as_a1111_parameters();
+            // only the posted copy is marked, so that paint-overs start from a clean image
+            let watermark = Some(
+                Configuration::get()
+                    .general
+                    .watermark_for(interaction.guild_id())
+                    .clone(),
+            )
+            .filter(|w| w.enabled);
+            move || {
+                let bytes = match watermark {
+                    Some(watermark) => util::encode_image_to_png_bytes(util::apply_watermark(
+                        image::load_from_memory(&bytes)?,
+                        &watermark,
+                    )?)?,
+                    None => bytes,
+                };
+                Ok(util::embed_png_parameters(&bytes, &parameters))
+            }
+        })
+        .await?;
+        // the image blobs can be large, so let the runtime move other tasks off this thread
+        let store_key = tokio::task::block_in_place(|| store.insert_generation(generation))?;
         let message = format!(
-            "{} - {}",
-            generation.as_message(models),
-            interaction.user().mention()
+            "{}{} (#{store_key}){}",
+            message.map(|m| format!("{m} - ")).unwrap_or_default(),
+            interaction.user().mention(),
+            parent
+                .map(|(id, derivation)| format!("\n↳ {} of #{id}", derivation.as_str()))
+                .unwrap_or_default()
         );
-        let store_key = store.insert_generation(generation)?;
-
-        let final_message = result_channel_override
-            .unwrap_or_else(|| interaction.channel_id())
-            .send_files(&http, [(bytes.as_slice(), filename.as_str())], |m| {
-                m.content(message).components(|c| {
-                    let e = &Configuration::get().emojis;
-                    c.create_action_row(|r| {
-                        r.create_button(|b| {
-                            b.emoji(e.retry.parse::<ReactionType>().unwrap())
-                                .label("Retry")
-                                .style(component::ButtonStyle::Secondary)
-                                .custom_id(cid::Generation::Retry.to_id(store_key))
-                        })
-                        .create_button(|b| {
-                            b.emoji(e.retry_with_options.parse::<ReactionType>().unwrap())
-                                .label("Retry (options)")
-                                .style(component::ButtonStyle::Secondary)
-                                .custom_id(cid::Generation::RetryWithOptions.to_id(store_key))
-                        })
-                        .create_button(|b| {
-                            b.emoji(e.remix.parse::<ReactionType>().unwrap())
-                                .label("Remix")
-                                .style(component::ButtonStyle::Secondary)
-                                .custom_id(cid::Generation::Remix.to_id(store_key))
-                        })
-                        .create_button(|b| {
-                            b.emoji(e.upscale.parse::<ReactionType>().unwrap())
-                                .label("Upscale (ESRGAN 2x)")
-                                .style(component::ButtonStyle::Secondary)
-                                .custom_id(cid::Generation::Upscale.to_id(store_key))
-                        })
-                    })
-                    .create_action_row(|r| {
-                        r.create_button(|b| {
-                            b.emoji(e.interrogate_with_clip.parse::<ReactionType>().unwrap())
-                                .label("CLIP")
-                                .style(component::ButtonStyle::Secondary)
-                                .custom_id(cid::Generation::InterrogateClip.to_id(store_key))
-                        })
-                        .create_button(|b| {
-                            b.emoji(
-                                e.interrogate_with_deepdanbooru
-                                    .parse::<ReactionType>()
-                                    .unwrap(),
-                            )
-                            .label("DeepDanbooru")
-                            .style(component::ButtonStyle::Secondary)
-                            .custom_id(cid::Generation::InterrogateDeepDanbooru.to_id(store_key))
-                        })
+
+        // if the result can't be delivered to the requested channel (e.g. the user has
+        // their DMs closed), fall back to the channel the command was issued in, unless
+        // that's where it was already going
+        let mut destinations = vec![(
+            result_channel_override.unwrap_or_else(|| interaction.channel_id()),
+            message.clone(),
+        )];
+        if result_channel_override.is_some_and(|c| c != interaction.channel_id()) {
+            destinations.push((
+                interaction.channel_id(),
+                format!("{message}\n*Couldn't deliver this to the requested channel, so it's been posted here instead.*"),
+            ));
+        }
+
+        let mut final_message = None;
+        let mut last_error = None;
+        for (channel_id, message) in destinations {
+            let is_fallback = Some(channel_id) != result_channel_override;
+            let buttons: Vec<_> = Configuration::get()
+                .buttons
+                .get(button_profile)
+                .iter()
+                .copied()
+                .filter(|b| is_fallback || !override_is_dm || b.works_in_dms())
+                .collect();
+            let result = util::retry_if_rate_limited(|| async {
+                Ok(channel_id
+                    .send_files(&http, [(bytes.as_slice(), filename.as_str())], |m| {
+                        m.content(&message);
+                        if let Some((prompt, fields)) = &embed {
+                            // Discord limits descriptions to 4096 characters, and fields to 1024,
+                            // and rejects either if it's empty
+                            m.embed(|e| {
+                                e.description(if prompt.trim().is_empty() {
+                                    "*No prompt*".to_string()
+                                } else {
+                                    util::truncate(prompt, 4096)
+                                })
+                                .fields(
+                                    fields
+                                        .iter()
+                                        .filter(|(_, value)| !value.trim().is_empty())
+                                        .map(|(name, value)| {
+                                            (name, util::truncate(value, 1024), true)
+                                        }),
+                                )
+                                .image(format!("attachment://{filename}"))
+                            });
+                        }
+                        m.components(|c| {
+                            // Discord allows at most five buttons in a row
+                            for row in buttons.chunks(5) {
+                                c.create_action_row(|r| {
+                                    for button in row {
+                                        r.create_button(|b| create_button(b, *button, store_key));
+                                    }
+                                    r
+                                });
+                            }
+                            c
+                        });
+
+                        if is_fallback {
+                            if let Some(message) = interaction.message() {
+                                m.reference_message(message);
+                            }
+                        }
+
+                        m
                     })
-                });
+                    .await?)
+            })
+            .await;
 
-                if result_channel_override.is_none() {
-                    if let Some(message) = interaction.message() {
-                        m.reference_message(message);
-                    }
+            match result {
+                Ok(message) => {
+                    final_message = Some(message);
+                    break;
+                }
+                Err(err) => {
+                    tracing::warn!("failed to send result to {channel_id}: {err}");
+                    last_error = Some(err);
                 }
+            }
+        }
+        let final_message = match final_message {
+            Some(message) => message,
+            None => return Err(last_error.unwrap_or_else(|| anyhow::anyhow!("no destinations"))),
+        };
 
-                m
-            })
-            .await?;
+        // the result may have been sent to a DM instead of the server it was requested in
+        let guild_id = if Some(final_message.channel_id) == result_channel_override {
+            final_message
+                .channel_id
+                .to_channel(&http)
+                .await
+                .ok()
+                .and_then(|c| c.guild())
+                .map(|c| c.guild_id)
+        } else {
+            interaction.guild_id()
+        };
+        let image_url = &final_message
+            .attachments
+            .first()
+            .context("no attachment")?
+            .url;
+        let message_url = final_message.id.link(final_message.channel_id, guild_id);
+        store.set_generation_url(store_key, image_url, &message_url)?;
 
-        store.set_generation_url(
-            store_key,
-            &final_message
-                .attachments
-                .first()
-                .context("no attachment")?
-                .url,
-        )?;
+        if let Some(webhook) = &Configuration::get().general.completion_webhook {
+            notify_completion_webhook(
+                webhook.clone(),
+                serde_json::json!({
+                    "generation_id": store_key,
+                    "user_id": interaction.user().id.to_string(),
+                    "guild_id": guild_id.map(|g| g.to_string()),
+                    "prompt": prompt,
+                    "image_url": image_url,
+                    "message_url": message_url,
+                }),
+            );
+        }
     }
+
+    if let Some(keep) = Configuration::get().storage.max_generations_per_user {
+        let user_id = interaction.user().id;
+        let deleted = tokio::task::block_in_place(|| store.prune_user_generations(user_id, keep))?;
+        if deleted > 0 {
+            tracing::info!("pruned {deleted} old generations");
+        }
+    }
+
     interaction
         .get_interaction_message(http)
         .await?
         .delete(http)
         .await?;
 
-    Ok(())
+    Ok(util::GenerationOutcome::Completed)
+}
+
+/// Posts the completed generation to the webhook in the background, so that a slow or
+/// unreachable endpoint doesn't hold up the rest of the results
+fn notify_completion_webhook(url: String, payload: serde_json::Value) {
+    /// How long the endpoint has to respond before the notification is given up on
+    const TIMEOUT: Duration = Duration::from_secs(10);
+    static CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+        reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .build()
+            .expect("failed to build the webhook client")
+    });
+
+    tokio::spawn(async move {
+        let result = CLIENT
+            .post(&url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(payload.to_string())
+            .send()
+            .await
+            .and_then(|r| r.error_for_status());
+        if let Err(err) = result {
+            tracing::warn!("failed to notify the completion webhook: {err}");
+        }
+    });
 }
 
+/// Adds or removes the button for cancelling the interaction's generation. This is cosmetic,
+/// so failures are only logged.
+async fn set_cancel_button(http: &Http, interaction: &dyn DiscordInteraction, shown: bool) {
+    let result = async {
+        interaction
+            .get_interaction_message(http)
+            .await?
+            .edit(http, |m| {
+                if shown {
+                    m.components(|c| {
+                        c.create_action_row(|r| {
+                            r.create_button(|b| {
+                                b.label("Cancel")
+                                    .style(component::ButtonStyle::Danger)
+                                    .custom_id(cid::Task::Cancel.to_id(interaction.id()))
+                            })
+                        })
+                    })
+                } else {
+                    m.set_components(Default::default())
+                }
+            })
+            .await?;
+        anyhow::Ok(())
+    }
+    .await;
+    if let Err(err) = result {
+        tracing::warn!("failed to update the cancel button: {err}");
+    }
+}
+
+/// Fills in one of the buttons shown beneath the generation with the ID `store_key`
+fn create_button(
+    b: &mut CreateButton,
+    button: GenerationButton,
+    store_key: i64,
+) -> &mut CreateButton {
+    // the emoji is selected rather than borrowed, so that invalid emojis fall back to the default
+    type EmojiSelector = fn(&Emojis) -> &String;
+    let (emoji, label, custom_id): (Option<EmojiSelector>, _, _) = match button {
+        GenerationButton::Retry => (Some(|e| &e.retry), "Retry", cid::Generation::Retry),
+        GenerationButton::RetryWithOptions => (
+            Some(|e| &e.retry_with_options),
+            "Retry (options)",
+            cid::Generation::RetryWithOptions,
+        ),
+        GenerationButton::Remix => (Some(|e| &e.remix), "Remix", cid::Generation::Remix),
+        GenerationButton::Upscale => (
+            Some(|e| &e.upscale),
+            "Upscale (ESRGAN 2x)",
+            cid::Generation::Upscale,
+        ),
+        GenerationButton::Upres => (Some(|e| &e.upres), "Upres", cid::Generation::HiResRegen),
+        GenerationButton::InterrogateClip => (
+            Some(|e| &e.interrogate_with_clip),
+            "CLIP",
+            cid::Generation::InterrogateClip,
+        ),
+        GenerationButton::InterrogateDeepDanbooru => (
+            Some(|e| &e.interrogate_with_deepdanbooru),
+            "DeepDanbooru",
+            cid::Generation::InterrogateDeepDanbooru,
+        ),
+        GenerationButton::CopySeed => (None, "Copy seed", cid::Generation::ShowSeed),
+        GenerationButton::Label => (None, "Label", cid::Generation::Label),
+    };
+    if let Some(emoji) = emoji {
+        b.emoji(Configuration::get().emojis.reaction_type(emoji));
+    }
+    b.label(label)
+        .style(component::ButtonStyle::Secondary)
+        .custom_id(custom_id.to_id(store_key))
+}
+
+/// Interrogates the image, filtering DeepDanbooru's tags through the whitelist if requested
+pub async fn interrogate(
+    client: &sd::Client,
+    image: &image::DynamicImage,
+    interrogator: sd::Interrogator,
+    filtered: bool,
+) -> anyhow::Result<String> {
+    let result = client
+        .interrogate(image, interrogator)
+        .await
+        .map_err(|err| {
+            tracing::warn!("interrogation with {interrogator} failed: {err}");
+            err
+        })?;
+    let config = Configuration::get();
+    Ok(match interrogator {
+        sd::Interrogator::DeepDanbooru => util::filter_deepdanbooru_tags(
+            &result,
+            config.deepdanbooru_tag_whitelist().filter(|_| filtered),
+            config.general.deepdanbooru_max_tags,
+        ),
+        sd::Interrogator::Clip => result,
+    })
+}
+
+#[tracing::instrument(skip_all, fields(user = %interaction.user().id))]
 pub async fn interrogate_task(
     client: &sd::Client,
     store: &Store,
@@ -239,23 +644,23 @@ pub async fn interrogate_task(
         store::InterrogationSource,
         sd::Interrogator,
     ),
+    filtered: bool,
 ) -> anyhow::Result<()> {
-    let result = client.interrogate(&image, interrogator).await?;
-    let result = match (
-        interrogator,
-        Configuration::get().deepdanbooru_tag_whitelist(),
-    ) {
-        (sd::Interrogator::DeepDanbooru, Some(tags)) => result
-            .split(", ")
-            .filter(|s| tags.contains(*s))
-            .collect::<Vec<_>>()
-            .join(", "),
-        _ => result,
-    };
+    let result = interrogate(client, &image, interrogator, filtered).await?;
 
+    // images that came from a URL were given an ID, so that they can be used again without it
+    let source_text = match &source {
+        store::InterrogationSource::GenerationId(_) => String::new(),
+        store::InterrogationSource::Url(url) => {
+            match store.find_upload(util::require_guild(interaction.guild_id())?, url)? {
+                Some(id) => format!(" on {url} (image ID {id})"),
+                None => format!(" on {url}"),
+            }
+        }
+    };
     let store_key = store.insert_interrogation(store::Interrogation {
         user_id: interaction.user().id,
-        guild_id: interaction.guild_id().context("no guild id")?,
+        guild_id: util::require_guild(interaction.guild_id())?,
         source: source.clone(),
         result: result.clone(),
         interrogator,
@@ -269,46 +674,63 @@ pub async fn interrogate_task(
                 "`{}` - {}{} for {}",
                 result,
                 interrogator,
-                match source {
-                    store::InterrogationSource::GenerationId(_) => String::new(),
-                    store::InterrogationSource::Url(url) => format!(" on {url}"),
-                },
+                source_text,
                 interaction.user().mention()
             ))
             .components(|c| {
                 c.create_action_row(|r| {
-                    let e = &Configuration::get().emojis;
+                    let config = Configuration::get();
+                    let e = &config.emojis;
+                    // the default order gets the main button, and the other order a plain one
+                    let shuffle = config.general.shuffle_interrogation_tags(interrogator);
                     r.create_button(|b| {
-                        b.emoji(e.interrogate_generate.parse::<ReactionType>().unwrap())
-                            .label(match interrogator {
-                                sd::Interrogator::Clip => "Generate",
-                                sd::Interrogator::DeepDanbooru => "Generate with shuffle",
+                        b.emoji(e.reaction_type(|e| &e.interrogate_generate))
+                            .label(if shuffle {
+                                "Generate with shuffle"
+                            } else {
+                                "Generate"
                             })
                             .style(component::ButtonStyle::Secondary)
                             .custom_id(cid::Interrogation::Generate.to_id(store_key))
                     });
+                    r.create_button(|b| {
+                        let (label, custom_id) = if shuffle {
+                            ("Generate in order", cid::Interrogation::GenerateInOrder)
+                        } else {
+                            (
+                                "Generate with shuffle",
+                                cid::Interrogation::GenerateShuffled,
+                            )
+                        };
+                        b.label(label)
+                            .style(component::ButtonStyle::Secondary)
+                            .custom_id(custom_id.to_id(store_key))
+                    });
 
                     match interrogator {
                         sd::Interrogator::Clip => r.create_button(|b| {
-                            b.emoji(
-                                e.interrogate_with_deepdanbooru
-                                    .parse::<ReactionType>()
-                                    .unwrap(),
-                            )
-                            .label("Re-interrogate with DeepDanbooru")
-                            .style(component::ButtonStyle::Secondary)
-                            .custom_id(
-                                cid::Interrogation::ReinterrogateWithDeepDanbooru.to_id(store_key),
-                            )
-                        }),
-                        sd::Interrogator::DeepDanbooru => r.create_button(|b| {
-                            b.emoji(e.interrogate_with_clip.parse::<ReactionType>().unwrap())
-                                .label("Re-interrogate with CLIP")
+                            b.emoji(e.reaction_type(|e| &e.interrogate_with_deepdanbooru))
+                                .label("Re-interrogate with DeepDanbooru")
                                 .style(component::ButtonStyle::Secondary)
                                 .custom_id(
-                                    cid::Interrogation::ReinterrogateWithClip.to_id(store_key),
+                                    cid::Interrogation::ReinterrogateWithDeepDanbooru
+                                        .to_id(store_key),
                                 )
                         }),
+                        sd::Interrogator::DeepDanbooru => r
+                            .create_button(|b| {
+                                b.emoji(e.reaction_type(|e| &e.interrogate_with_clip))
+                                    .label("Re-interrogate with CLIP")
+                                    .style(component::ButtonStyle::Secondary)
+                                    .custom_id(
+                                        cid::Interrogation::ReinterrogateWithClip.to_id(store_key),
+                                    )
+                            })
+                            .create_button(|b| {
+                                b.label("Send tags to Wirehead")
+                                    .style(component::ButtonStyle::Secondary)
+                                    .custom_id(cid::Interrogation::SendToWirehead.to_id(store_key))
+                            }),
                     }
                 })
             })
@@ -1,6 +1,6 @@
 use super::issuer;
 use crate::{
-    config::Configuration,
+    config::{ButtonProfiles, Configuration},
     constant, custom_id as cid, store,
     util::{self, DiscordInteraction},
 };
@@ -17,7 +17,7 @@ use serenity::{
     },
 };
 use stable_diffusion_a1111_webui_client as sd;
-use std::{collections::HashMap, str::FromStr};
+use std::collections::HashMap;
 
 pub async fn retry(
     client: &sd::Client,
@@ -30,6 +30,28 @@ pub async fn retry(
     retry_impl(client, models, store, http, mci, id, Overrides::none(false)).await
 }
 
+/// Regenerates the generation with the same seed at a higher resolution, re-sampling
+/// (with hires fix for text-to-image) to get a more detailed version of the same composition
+pub async fn hires_regen(
+    client: &sd::Client,
+    models: &[sd::Model],
+    store: &store::Store,
+    http: &Http,
+    mci: &MessageComponentInteraction,
+    id: i64,
+) {
+    /// How much larger the regenerated image is
+    const UPRES_SCALE_FACTOR: f32 = 1.5;
+
+    let overrides = Overrides {
+        seed: None,
+        upres: Some(UPRES_SCALE_FACTOR),
+        derivation: store::Derivation::Upres,
+        ..Overrides::none(false)
+    };
+    retry_impl(client, models, store, http, mci, id, overrides).await
+}
+
 pub async fn retry_with_options(
     store: &store::Store,
     http: &Http,
@@ -98,7 +120,7 @@ pub async fn upscale(
             .map(|g| (g.image, g.image_url))
             .context("generation not found")?;
 
-        let image = image::load_from_memory(&image)?;
+        let image = util::decode_image(image).await?;
         let url = url.as_deref().unwrap_or("unknown");
 
         mci.edit(http, &format!("Postprocessing {url}...")).await?;
@@ -116,7 +138,7 @@ pub async fn upscale(
             )
             .await?;
 
-        let bytes = util::encode_image_to_png_bytes(result)?;
+        let bytes = util::run_image_task(move || util::encode_image_to_png_bytes(result)).await?;
 
         mci.get_interaction_message(http)
             .await?
@@ -131,6 +153,178 @@ pub async fn upscale(
     .await;
 }
 
+pub async fn show_seed(
+    store: &store::Store,
+    http: &Http,
+    mci: &MessageComponentInteraction,
+    id: i64,
+) {
+    util::run_and_report_error(mci, http, async {
+        let seed = store
+            .get_generation(id)?
+            .map(|g| g.seed)
+            .context("generation not found")?;
+
+        // the seed is sent on its own so that it can be copied in one go
+        mci.create_interaction_response(http, |r| {
+            r.kind(InteractionResponseType::ChannelMessageWithSource)
+                .interaction_response_data(|d| d.ephemeral(true).content(seed))
+        })
+        .await?;
+
+        Ok(())
+    })
+    .await;
+}
+
+pub async fn label(store: &store::Store, http: &Http, mci: &MessageComponentInteraction, id: i64) {
+    util::run_and_report_error(mci, http, async {
+        let generation = store.get_generation(id)?.context("generation not found")?;
+        if generation.user_id != mci.user.id || Some(generation.guild_id) != mci.guild_id {
+            anyhow::bail!("Only the author of a generation can label it.");
+        }
+        let labels = store.get_labels(id)?.join(", ");
+
+        mci.create_interaction_response(http, |r| {
+            r.kind(InteractionResponseType::Modal)
+                .interaction_response_data(|d| {
+                    d.components(|c| {
+                        c.create_action_row(|r| {
+                            r.create_input_text(|t| {
+                                t.label("Labels (separated by commas)")
+                                    .custom_id(constant::value::LABEL)
+                                    .style(component::InputTextStyle::Short)
+                                    .required(false)
+                                    .value(labels)
+                            })
+                        })
+                    })
+                    .title(format!("Label #{id}"))
+                    .custom_id(cid::Generation::LabelResponse.to_id(id))
+                })
+        })
+        .await?;
+
+        Ok(())
+    })
+    .await;
+}
+
+pub async fn label_response(
+    store: &store::Store,
+    http: &Http,
+    msi: &ModalSubmitInteraction,
+    id: i64,
+) {
+    util::run_and_report_error(msi, http, async {
+        // the modal's ID can be submitted without going through the button, so the
+        // checks are repeated here
+        let generation = store.get_generation(id)?.context("generation not found")?;
+        if generation.user_id != msi.user.id || Some(generation.guild_id) != msi.guild_id {
+            anyhow::bail!("Only the author of a generation can label it.");
+        }
+
+        let value = msi
+            .data
+            .components
+            .iter()
+            .flat_map(|r| r.components.iter())
+            .find_map(|c| match c {
+                component::ActionRowComponent::InputText(it)
+                    if it.custom_id == constant::value::LABEL =>
+                {
+                    Some(it.value.as_str())
+                }
+                _ => None,
+            })
+            .unwrap_or_default();
+        let labels = util::parse_labels(value)?;
+        tokio::task::block_in_place(|| store.set_labels(id, &labels))?;
+
+        let content = if labels.is_empty() {
+            format!("Removed the labels from #{id}.")
+        } else {
+            format!(
+                "Labelled #{id} with {}.",
+                labels
+                    .iter()
+                    .map(|l| format!("`{l}`"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        };
+        msi.create_interaction_response(http, |r| {
+            r.kind(InteractionResponseType::ChannelMessageWithSource)
+                .interaction_response_data(|d| d.ephemeral(true).content(content))
+        })
+        .await?;
+
+        Ok(())
+    })
+    .await;
+}
+
+pub async fn cancel(http: &Http, mci: &MessageComponentInteraction, id: InteractionId) {
+    util::run_and_report_error(mci, http, async {
+        util::request_cancellation(id, mci.user.id)?;
+        mci.create_interaction_response(http, |r| {
+            r.kind(InteractionResponseType::ChannelMessageWithSource)
+                .interaction_response_data(|d| d.ephemeral(true).content("Cancelling..."))
+        })
+        .await?;
+
+        Ok(())
+    })
+    .await;
+}
+
+pub async fn confirm_config(
+    store: &store::Store,
+    http: &Http,
+    mci: &MessageComponentInteraction,
+    id: InteractionId,
+) {
+    util::run_and_report_error(mci, http, async {
+        mci.create(http, "Changing the configuration...").await?;
+        super::command::apply_pending_config(store, mci, id)?;
+
+        // the change can't be confirmed twice
+        mci.message
+            .clone()
+            .edit(http, |m| m.components(|c| c))
+            .await?;
+        mci.edit(http, &super::command::describe_configuration()?)
+            .await?;
+        Ok(())
+    })
+    .await;
+}
+
+pub async fn recent_page(
+    store: &store::Store,
+    http: &Http,
+    mci: &MessageComponentInteraction,
+    page: i64,
+    value: cid::Recent,
+) {
+    util::run_and_report_error(mci, http, async {
+        let page = match value {
+            cid::Recent::Previous => page - 1,
+            cid::Recent::Next => page + 1,
+        }
+        .max(1) as usize;
+        let guild_id = util::require_guild(mci.guild_id)?;
+
+        mci.create_interaction_response(http, |r| {
+            r.kind(InteractionResponseType::DeferredUpdateMessage)
+        })
+        .await?;
+        let mut message = mci.message.clone();
+        super::command::show_recent_page(store, http, &mut message, guild_id, page).await
+    })
+    .await;
+}
+
 pub async fn interrogate(
     client: &sd::Client,
     store: &store::Store,
@@ -145,12 +339,13 @@ pub async fn interrogate(
         .unwrap();
 
     util::run_and_report_error(interaction, http, async {
-        let image = image::load_from_memory(
-            &store
+        let image = util::decode_image(
+            store
                 .get_generation(id)?
                 .context("generation not found")?
                 .image,
-        )?;
+        )
+        .await?;
 
         issuer::interrogate_task(
             client,
@@ -162,6 +357,7 @@ pub async fn interrogate(
                 store::InterrogationSource::GenerationId(id),
                 interrogator,
             ),
+            true,
         )
         .await
     })
@@ -194,7 +390,17 @@ pub async fn interrogate_reinterrogate(
                     .image
             }
             store::InterrogationSource::Url(url) => {
-                reqwest::get(&url).await?.bytes().await?.to_vec()
+                // the URL may have expired, so the copy kept when it was used is preferred
+                let guild_id = util::require_guild(interaction.guild_id())?;
+                match store.find_upload(guild_id, &url)? {
+                    Some(upload_id) => {
+                        store
+                            .get_upload(upload_id, guild_id)?
+                            .context("image not found")?
+                            .1
+                    }
+                    None => util::download_image_bytes(&url).await?,
+                }
             }
         };
 
@@ -204,10 +410,11 @@ pub async fn interrogate_reinterrogate(
             interaction,
             http,
             (
-                image::load_from_memory(&image)?,
+                util::decode_image(image).await?,
                 store::InterrogationSource::GenerationId(id),
                 interrogator,
             ),
+            true,
         )
         .await
     })
@@ -220,7 +427,8 @@ pub async fn interrogate_generate(
     store: &store::Store,
     http: &Http,
     interaction: &dyn DiscordInteraction,
-    id: i64,
+    // whether to shuffle the interrogation's tags; `None` for the configured default
+    (id, shuffle): (i64, Option<bool>),
 ) {
     interaction
         .create(
@@ -231,6 +439,7 @@ pub async fn interrogate_generate(
         .unwrap();
 
     util::run_and_report_error(interaction, http, async {
+        let reservation = util::reserve_quota(store, interaction, 1)?;
         let interrogation = store
             .get_interrogation(id)?
             .context("no interrogation found")?;
@@ -238,20 +447,26 @@ pub async fn interrogate_generate(
         // use last generation as default if available
         let last_generation = store.get_last_generation_for_user(
             interaction.user().id,
-            interaction.guild_id().context("no guild id")?,
+            util::require_guild(interaction.guild_id())?,
         )?;
         let last_generation = last_generation.as_ref();
 
         let base = {
             // always applied
             let prompt = interrogation.result;
-            let prompt = if let sd::Interrogator::DeepDanbooru = interrogation.interrogator {
+            let shuffle = shuffle.unwrap_or_else(|| {
+                Configuration::get()
+                    .general
+                    .shuffle_interrogation_tags(interrogation.interrogator)
+            });
+            let prompt = if shuffle {
                 let mut components: Vec<_> = prompt.split(", ").collect();
                 components.shuffle(&mut rand::thread_rng());
                 components.join(", ")
             } else {
                 prompt
             };
+            util::check_banned_terms(interaction.user().id, &prompt)?;
 
             let width = last_generation.map(|g| g.width);
             let height = last_generation.map(|g| g.height);
@@ -260,6 +475,7 @@ pub async fn interrogate_generate(
             let tiling = last_generation.map(|g| g.tiling);
             let restore_faces = last_generation.map(|g| g.restore_faces);
             let sampler = last_generation.map(|g| g.sampler);
+            let eta = last_generation.and_then(|g| g.eta);
             let model = last_generation
                 .and_then(|g| util::find_model_by_hash(models, &g.model_hash).map(|t| t.1));
 
@@ -283,16 +499,17 @@ pub async fn interrogate_generate(
                 tiling,
                 restore_faces,
                 sampler,
+                eta,
                 model,
                 ..Default::default()
             };
-            util::fixup_base_generation_request(&mut base);
+            util::fixup_base_generation_request(&mut base, true);
             base
         };
-        let prompt = base.prompt.clone();
-        issuer::generation_task(
+        let (prompt, eta) = (base.prompt.clone(), base.eta);
+        let outcome = issuer::generation_task(
             (client, models),
-            tokio::task::spawn(
+            util::spawn_generation(
                 client.generate_from_text(&sd::TextToImageGenerationRequest {
                     base,
                     ..Default::default()
@@ -300,11 +517,14 @@ pub async fn interrogate_generate(
             ),
             store,
             http,
-            (interaction, None),
+            (interaction, None, ButtonProfiles::DEFAULT),
             (prompt.as_str(), None),
-            None,
+            (None, eta, (None, None), None),
         )
-        .await
+        .await?;
+        reservation.settle(outcome);
+
+        Ok(())
     })
     .await;
 }
@@ -354,54 +574,110 @@ async fn reissue_response_impl(
         })
         .collect();
 
-    fn parse_two<T: FromStr, U: FromStr>(value: Option<&String>) -> (Option<T>, Option<U>) {
-        fn parse_two_impl<T: FromStr, U: FromStr>(value: Option<&String>) -> Option<(T, U)> {
-            fn trim_parse<T: FromStr>(value: &str) -> Option<T> {
-                value.trim().parse().ok()
+    // Numeric values prefixed with `+` or `-` (e.g. `+10`) are applied relative to the
+    // original generation's value, instead of replacing it. Invalid values are reported
+    // rather than ignored.
+    let parse_values = |key: &str, originals: &[Option<f64>]| -> anyhow::Result<Vec<Option<f64>>> {
+        fn parse_value(value: &str, original: Option<f64>) -> anyhow::Result<Option<f64>> {
+            let value = value.trim();
+            if value.is_empty() {
+                return Ok(None);
+            }
+            let parsed: f64 = value
+                .parse()
+                .ok()
+                .filter(|v: &f64| v.is_finite())
+                .with_context(|| format!("`{value}` is not a number"))?;
+            if value.starts_with(['+', '-']) {
+                let original = original.with_context(|| {
+                    format!("`{value}` can't be applied without the original value")
+                })?;
+                Ok(Some(original + parsed))
+            } else {
+                Ok(Some(parsed))
             }
-
-            let (str1, str2) = value?.split_once(',')?;
-            Some((trim_parse(str1)?, trim_parse(str2)?))
-        }
-        match parse_two_impl(value) {
-            Some((t, u)) => (Some(t), Some(u)),
-            _ => (None, None),
         }
-    }
 
-    let prompt = rows.get(constant::value::PROMPT).map(|s| s.as_str());
+        let values: Vec<_> = rows
+            .get(key)
+            .map(|s| s.split(',').collect())
+            .unwrap_or_default();
+        originals
+            .iter()
+            .enumerate()
+            .map(|(idx, original)| match values.get(idx) {
+                Some(value) => parse_value(value, *original),
+                None => Ok(None),
+            })
+            .collect()
+    };
 
-    let negative_prompt = rows
-        .get(constant::value::NEGATIVE_PROMPT)
-        .map(|s| s.as_str());
+    let overrides = async {
+        let original = store.get_generation(id)?;
 
-    let (width, height) = parse_two(rows.get(constant::value::WIDTH_HEIGHT));
+        let prompt = rows.get(constant::value::PROMPT).map(|s| s.as_str());
 
-    let seed = rows
-        .get(constant::value::SEED)
-        .map(|s| s.parse::<i64>().ok());
+        let negative_prompt = rows
+            .get(constant::value::NEGATIVE_PROMPT)
+            .map(|s| s.as_str());
 
-    let (guidance_scale, denoising_strength) =
-        parse_two(rows.get(constant::value::GUIDANCE_SCALE_DENOISING_STRENGTH));
+        let (width, height) = match parse_values(
+            constant::value::WIDTH_HEIGHT,
+            &[
+                original.as_ref().map(|g| g.width as f64),
+                original.as_ref().map(|g| g.height as f64),
+            ],
+        )?[..]
+        {
+            [width, height] => (
+                width.map(|w| w.max(0.0).round() as u32),
+                height.map(|h| h.max(0.0).round() as u32),
+            ),
+            _ => unreachable!(),
+        };
 
-    retry_impl(
-        client,
-        models,
-        store,
-        http,
-        msi,
-        id,
-        Overrides::new(
+        // an empty, invalid or negative seed asks for a fresh one
+        let seed = rows
+            .get(constant::value::SEED)
+            .map(|s| s.parse::<i64>().ok().filter(|seed| *seed >= 0));
+
+        let (guidance_scale, denoising_strength, steps) = match parse_values(
+            constant::value::GUIDANCE_SCALE_DENOISING_STRENGTH_STEPS,
+            &[
+                original.as_ref().map(|g| g.cfg_scale as f64),
+                original.as_ref().map(|g| g.denoising_strength as f64),
+                original.as_ref().map(|g| g.steps as f64),
+            ],
+        )?[..]
+        {
+            [guidance_scale, denoising_strength, steps] => (
+                guidance_scale,
+                denoising_strength,
+                steps.map(|s| s.max(0.0).round() as usize),
+            ),
+            _ => unreachable!(),
+        };
+
+        anyhow::Ok(Overrides::new(
             (prompt, negative_prompt),
             (width, height),
             guidance_scale,
-            None,
+            steps,
             seed,
             denoising_strength,
             paintover,
-        ),
-    )
-    .await
+        ))
+    }
+    .await;
+    let overrides = match overrides {
+        Ok(overrides) => overrides,
+        Err(err) => {
+            util::run_and_report_error(msi, http, async { Err(err) }).await;
+            return;
+        }
+    };
+
+    retry_impl(client, models, store, http, msi, id, overrides).await
 }
 
 async fn retry_impl(
@@ -419,26 +695,30 @@ async fn retry_impl(
         .unwrap();
 
     util::run_and_report_error(interaction, http, async {
+        let reservation = util::reserve_quota(store, interaction, 1)?;
         let mut generation = store
             .get_generation(id)?
             .context("generation not found")?
             .clone();
 
         if overrides.paintover {
-            let init_image = image::load_from_memory(&generation.image)?;
+            let init_image = util::decode_image(generation.image.clone()).await?;
             let init_url = generation
                 .image_url
                 .clone()
                 .unwrap_or_else(|| "UNKNOWN".to_string());
 
+            // the result is painted over as a whole, even if it was outpainted
             if let Some(image_generation) = generation.image_generation.as_mut() {
                 image_generation.init_image = init_image;
                 image_generation.init_url = init_url;
+                image_generation.mask = None;
             } else {
                 generation.image_generation = Some(store::ImageGeneration {
                     init_image,
                     init_url,
                     resize_mode: Default::default(),
+                    mask: None,
                 });
             }
         }
@@ -446,14 +726,22 @@ async fn retry_impl(
         let mut request = generation.as_generation_request(models);
         {
             let base = match &mut request {
-                store::GenerationRequest::Text(r) => &mut r.base,
+                store::GenerationRequest::Text(r, _) => &mut r.base,
                 store::GenerationRequest::Image(r) => &mut r.base,
             };
+            // a generation made without the model's keyword shouldn't get it back
+            let prepend_keyword = base
+                .model
+                .as_ref()
+                .is_some_and(|m| util::has_model_keyword(&generation.prompt, &m.name));
             if let Some(prompt) = overrides.prompt {
+                util::check_banned_terms(interaction.user().id, prompt)?;
                 base.prompt = prompt.to_string();
             }
             if let Some(negative_prompt) = overrides.negative_prompt {
-                base.negative_prompt = Some(negative_prompt.to_string());
+                util::check_banned_terms(interaction.user().id, negative_prompt)?;
+                // an empty override clears the negative prompt
+                base.negative_prompt = Some(negative_prompt.to_string()).filter(|s| !s.is_empty());
             }
             if let Some(width) = overrides.width {
                 base.width = Some(width);
@@ -473,7 +761,31 @@ async fn retry_impl(
             if let Some(denoising_strength) = overrides.denoising_strength {
                 base.denoising_strength = Some(denoising_strength as f32);
             }
-            util::fixup_base_generation_request(base);
+            if let Some(factor) = overrides.upres {
+                base.width = Some((generation.width as f32 * factor) as u32);
+                base.height = Some((generation.height as f32 * factor) as u32);
+            }
+            util::fixup_base_generation_request(base, prepend_keyword);
+
+            if overrides.upres.is_some()
+                && base.width <= Some(generation.width)
+                && base.height <= Some(generation.height)
+            {
+                anyhow::bail!(
+                    "This generation is already at the maximum resolution ({}x{}).",
+                    generation.width,
+                    generation.height
+                );
+            }
+        }
+        if let (store::GenerationRequest::Text(r, hires_denoising_strength), Some(_)) =
+            (&mut request, overrides.upres)
+        {
+            r.enable_hr = Some(true);
+            hires_denoising_strength
+                .get_or_insert(Configuration::get().limits.default_hires_denoising_strength);
+            r.firstphase_width = Some(generation.width);
+            r.firstphase_height = Some(generation.height);
         }
         interaction
             .edit(
@@ -492,19 +804,28 @@ async fn retry_impl(
             )
             .await?;
 
-        issuer::generation_task(
+        let outcome = issuer::generation_task(
             (client, models),
             request.generate(client),
             store,
             http,
-            (interaction, None),
+            (interaction, None, ButtonProfiles::DEFAULT),
             (
                 &request.base().prompt,
                 request.base().negative_prompt.as_deref(),
             ),
-            generation.image_generation.clone(),
+            (
+                generation.image_generation.clone(),
+                request.base().eta,
+                (
+                    request.base().denoising_strength,
+                    request.hires_denoising_strength(),
+                ),
+                Some((id, overrides.derivation)),
+            ),
         )
         .await?;
+        reservation.settle(outcome);
 
         Ok(())
     })
@@ -524,6 +845,11 @@ struct Overrides<'a> {
     seed: Option<Option<i64>>,
     denoising_strength: Option<f64>,
     paintover: bool,
+    /// Scales the original resolution by this factor. Text-to-image generations are
+    /// generated at the original resolution first, and then upscaled with hires fix.
+    upres: Option<f32>,
+    /// how the result is recorded as having been made from the original
+    derivation: store::Derivation,
 }
 impl<'a> Overrides<'a> {
     fn new(
@@ -540,14 +866,20 @@ impl<'a> Overrides<'a> {
         Self {
             prompt: prompt.filter(|s| !s.is_empty()),
             negative_prompt,
-            width,
-            height,
+            width: width.map(|w| w.clamp(l.width_min, l.width_max)),
+            height: height.map(|h| h.clamp(l.height_min, l.height_max)),
             guidance_scale: guidance_scale
                 .map(|s| s.clamp(l.guidance_scale_min, l.guidance_scale_max)),
             steps: steps.map(|s| s.clamp(l.steps_min, l.steps_max)),
             seed,
             denoising_strength: denoising_strength.map(|s| s.clamp(0.0, 1.0)),
             paintover,
+            upres: None,
+            derivation: if paintover {
+                store::Derivation::Paintover
+            } else {
+                store::Derivation::Remix
+            },
         }
     }
 
@@ -562,6 +894,8 @@ impl<'a> Overrides<'a> {
             seed: Some(None),
             denoising_strength: None,
             paintover,
+            upres: None,
+            derivation: store::Derivation::Retry,
         }
     }
 }
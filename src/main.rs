@@ -17,6 +17,7 @@ use std::{
     sync::Arc,
 };
 
+mod api;
 mod command;
 mod config;
 mod constant;
@@ -32,6 +33,13 @@ use store::Store;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("warn,exilent=info")),
+        )
+        .init();
+
     constant::resource::write_assets()?;
     Configuration::init()?;
 
@@ -53,9 +61,10 @@ async fn main() -> anyhow::Result<()> {
         )
     };
 
-    let mut models: Vec<_> = {
-        let config_models = &Configuration::get().general.models;
-
+    let config_models = &Configuration::get().general.models;
+    // All models with a hash, including those hidden by the allowlist/blocklist, so that
+    // existing generations can still be looked up and retried
+    let mut all_models: Vec<_> = {
         let models = client.models().await?;
         let hashes: HashSet<_> = models.iter().filter_map(|m| m.hash_short.clone()).collect();
         for (list_name, list) in [
@@ -64,7 +73,7 @@ async fn main() -> anyhow::Result<()> {
         ] {
             for hash in list {
                 if !hashes.contains(hash) {
-                    println!("Warning: The hash `{hash}` in the {list_name} does not correspond to any of the loaded models. Do you need to migrate to the new hash system, or use the short hash instead of the long hash?");
+                    tracing::warn!("The hash `{hash}` in the {list_name} does not correspond to any of the loaded models. Do you need to migrate to the new hash system, or use the short hash instead of the long hash?");
                 }
             }
         }
@@ -75,23 +84,44 @@ async fn main() -> anyhow::Result<()> {
                 if m.hash_short.is_some() {
                     true
                 } else {
-                    println!("Warning: The model `{}` does not have a SHA256 hash and will be skipped. Please load it in the UI.", m.name);
+                    tracing::warn!("The model `{}` does not have a SHA256 hash and will be skipped. Please load it in the UI.", m.name);
                     false
                 }
             })
-            .filter(|m| {
-                let hash = m.hash_short.as_ref().unwrap();
-                let in_allowlist = config_models.allowlist.is_empty() || config_models.allowlist.contains(hash);
-                let in_blocklist = config_models.blocklist.contains(hash);
-                in_allowlist && !in_blocklist
-            })
             .collect()
     };
-    models.sort_by(|a, b| a.name.cmp(&b.name));
-    let store = Store::load()?;
+    all_models.sort_by(|a, b| a.name.cmp(&b.name));
+    let models: Vec<_> = all_models
+        .iter()
+        .filter(|m| {
+            let hash = m.hash_short.as_ref().unwrap();
+            let in_allowlist =
+                config_models.allowlist.is_empty() || config_models.allowlist.contains(hash);
+            let in_blocklist = config_models.blocklist.contains(hash);
+            in_allowlist && !in_blocklist
+        })
+        .cloned()
+        .collect();
+    if let Some(hash) = &Configuration::get().general.default_model_hash {
+        if util::find_model_by_hash(&models, hash).is_none() {
+            tracing::warn!("The default model hash `{hash}` does not correspond to any of the available models, and will be ignored.");
+        }
+    }
+    let loras = util::fetch_loras(authentication)
+        .await
+        .unwrap_or_else(|err| {
+            tracing::warn!(
+                "Could not retrieve the list of LoRAs, so they will not be suggested: {err}"
+            );
+            vec![]
+        });
+    let models = Arc::new(models);
+    let all_models = Arc::new(all_models);
+    let store = Arc::new(Store::load()?);
+    let sessions = Arc::new(Mutex::new(HashMap::new()));
 
     // Build our client.
-    let mut client = Client::builder(
+    let mut discord_client = Client::builder(
         authentication
             .discord_token
             .as_deref()
@@ -99,50 +129,109 @@ async fn main() -> anyhow::Result<()> {
         GatewayIntents::default(),
     )
     .event_handler(Handler {
-        client,
-        models,
-        store,
-        sessions: Mutex::new(HashMap::new()),
+        client: client.clone(),
+        models: models.clone(),
+        all_models,
+        loras,
+        store: store.clone(),
+        sessions: sessions.clone(),
+        user_names: Mutex::new(HashMap::new()),
     })
     .await
     .context("Error creating client")?;
 
+    tokio::spawn(
+        api::serve(api::ApiState {
+            http: discord_client.cache_and_http.http.clone(),
+            client,
+            models,
+            store,
+        })
+        .map(|r| {
+            if let Err(err) = r {
+                tracing::error!("API error: {err}");
+            }
+        }),
+    );
+
+    tokio::spawn({
+        let http = discord_client.cache_and_http.http.clone();
+        let shard_manager = discord_client.shard_manager.clone();
+        async move {
+            if let Err(err) = tokio::signal::ctrl_c().await {
+                tracing::error!("Could not listen for the shutdown signal: {err}");
+                return;
+            }
+            shutdown(&http, &sessions).await;
+            shard_manager.lock().await.shutdown_all().await;
+        }
+    });
+
     // Finally, start a single shard, and start listening to events.
     // Shards will automatically attempt to reconnect, and will perform
     // exponential backoff until it reconnects.
-    if let Err(why) = client.start().await {
-        println!("Client error: {why:?}");
+    if let Err(why) = discord_client.start().await {
+        tracing::error!("Client error: {why:?}");
     }
 
     Ok(())
 }
 
+/// Stops accepting new work, stops all Wirehead sessions, and gives in-flight generations
+/// a chance to finish and be stored before the bot disconnects
+async fn shutdown(http: &Http, sessions: &Mutex<HashMap<ChannelId, wirehead::Session>>) {
+    // How long to wait for in-flight generations before giving up on them
+    const GRACE_PERIOD: std::time::Duration = std::time::Duration::from_secs(30);
+    const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(250);
+
+    tracing::info!("Shutting down...");
+    util::begin_shutdown();
+    wirehead::shutdown_all(http, sessions).await;
+
+    let deadline = std::time::Instant::now() + GRACE_PERIOD;
+    loop {
+        let (active, waiting) = util::generation_queue();
+        if active + waiting == 0 {
+            break;
+        }
+        if std::time::Instant::now() >= deadline {
+            tracing::warn!(
+                "Shutting down with {active} generation(s) running and {waiting} queued"
+            );
+            break;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
 struct Handler {
     client: Arc<sd::Client>,
-    models: Vec<sd::Model>,
-    store: Store,
-    sessions: Mutex<HashMap<ChannelId, wirehead::Session>>,
+    /// The models available for new generations
+    models: Arc<Vec<sd::Model>>,
+    /// All models, including hidden ones; used to look up the models of past generations
+    all_models: Arc<Vec<sd::Model>>,
+    loras: Vec<String>,
+    store: Arc<Store>,
+    sessions: Arc<Mutex<HashMap<ChannelId, wirehead::Session>>>,
+    user_names: exilent::command::UserNameCache,
 }
 
 async fn ready_handler(http: &Http, models: &[sd::Model]) -> anyhow::Result<()> {
-    let registered_commands = Command::get_global_application_commands(http).await?;
-    let registered_commands: HashSet<_> = registered_commands
-        .iter()
-        .map(|c| c.name.as_str())
-        .collect();
-
-    let our_commands: HashSet<_> = Configuration::get()
-        .commands
-        .all()
-        .iter()
-        .cloned()
-        .collect();
-
-    if registered_commands != our_commands {
-        // If the commands registered with Discord don't match the commands configured
-        // for this bot, reset them entirely.
-        Command::set_global_application_commands(http, |c| c.set_application_commands(vec![]))
-            .await?;
+    // Registering a command overwrites the registered command with the same name, so only
+    // this instance's commands are replaced. Any others are left alone, as they may belong
+    // to another instance with a different prefix or suffix.
+    let our_commands = Configuration::get().commands.all();
+    let other_commands = Command::get_global_application_commands(http)
+        .await?
+        .into_iter()
+        .filter(|c| !our_commands.contains(&c.name))
+        .map(|c| c.name)
+        .collect::<Vec<_>>();
+    if !other_commands.is_empty() {
+        tracing::info!(
+            "Leaving other registered commands alone: {}",
+            other_commands.join(", ")
+        );
     }
 
     // TEMP HACK: Serenity 0.11.5 does not handle top-level error objects correctly,
@@ -189,16 +278,15 @@ async fn ready_handler(http: &Http, models: &[sd::Model]) -> anyhow::Result<()>
 #[async_trait]
 impl EventHandler for Handler {
     async fn ready(&self, ctx: Context, ready: Ready) {
-        println!("{} is connected; registering commands...", ready.user.name);
+        tracing::info!("{} is connected; registering commands...", ready.user.name);
 
         if let Err(err) = ready_handler(&ctx.http, &self.models).await {
-            println!("Error while registering commands: `{err}`");
+            tracing::error!("Error while registering commands: `{err}`");
             if err.to_string() == "expected object" {
-                println!();
-                println!(
+                tracing::error!(
                     "Discord refused to register the commands due to the request being too long."
                 );
-                println!(
+                tracing::error!(
                     "Consider using `general.models.allowlist` or `general.models.blocklist` to control the number of models in `config.toml` to fix this."
                 );
                 ctx.shard.shutdown_clean();
@@ -206,29 +294,76 @@ impl EventHandler for Handler {
             }
         }
 
-        println!("{} is good to go!", ready.user.name);
+        tracing::info!("{} is good to go!", ready.user.name);
     }
 
     async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
         let http = &ctx.http;
         match interaction {
             Interaction::ApplicationCommand(cmd) => {
-                let name = cmd.data.name.as_str();
-                let commands = &Configuration::get().commands;
+                if util::is_shutting_down() {
+                    if let Err(err) = cmd
+                        .create_interaction_response(http, |r| {
+                            r.kind(InteractionResponseType::ChannelMessageWithSource)
+                                .interaction_response_data(|d| {
+                                    d.ephemeral(true).content(
+                                        "The bot is shutting down; please try again later.",
+                                    )
+                                })
+                        })
+                        .await
+                    {
+                        tracing::warn!("failed to refuse command during shutdown: {err}");
+                    }
+                    return;
+                }
+
+                let config = Configuration::get();
+                let commands = &config.commands;
+                let is_command = |name: &str| cmd.data.name == commands.full_name(name);
+
+                // these are registered as unavailable in DMs, but older clients may still
+                // offer them
+                if cmd.guild_id.is_none() && commands.guild_only().contains(&cmd.data.name) {
+                    if let Err(err) = cmd
+                        .create_interaction_response(http, |r| {
+                            r.kind(InteractionResponseType::ChannelMessageWithSource)
+                                .interaction_response_data(|d| {
+                                    d.ephemeral(true).content(format!(
+                                        "`/{}` can only be used in a server, not in DMs.",
+                                        cmd.data.name
+                                    ))
+                                })
+                        })
+                        .await
+                    {
+                        tracing::warn!("failed to refuse command in DMs: {err}");
+                    }
+                    return;
+                }
 
-                if name == commands.paint {
+                if is_command(&commands.paint) {
                     exilent::command::paint(&self.client, &self.models, &self.store, http, cmd)
                         .await
-                } else if name == commands.postprocess {
+                } else if is_command(&commands.postprocess) {
                     exilent::command::postprocess(&self.client, http, cmd).await
-                } else if name == commands.interrogate {
+                } else if is_command(&commands.interrogate) {
                     exilent::command::interrogate(&self.client, &self.store, http, cmd).await
-                } else if name == commands.exilent {
-                    exilent::command::exilent(&self.client, &self.models, &self.store, http, cmd)
-                        .await
-                } else if name == commands.png_info {
+                } else if is_command(&commands.exilent) {
+                    exilent::command::exilent(
+                        &self.client,
+                        &self.all_models,
+                        &self.store,
+                        &self.user_names,
+                        http,
+                        cmd,
+                    )
+                    .await
+                } else if is_command(&commands.png_info) {
                     exilent::command::png_info(&self.client, http, cmd).await
-                } else if name == commands.wirehead {
+                } else if is_command(&commands.compare) {
+                    exilent::command::compare(&self.all_models, &self.store, http, cmd).await
+                } else if is_command(&commands.wirehead) {
                     wirehead::command::wirehead(
                         ctx.http.clone(),
                         cmd,
@@ -244,13 +379,22 @@ impl EventHandler for Handler {
                 use exilent::message_component as exmc;
                 use wirehead::message_component as whmc;
 
-                let custom_id = cid::CustomId::try_from(mci.data.custom_id.as_str())
-                    .expect("invalid interaction id");
+                let custom_id = match cid::CustomId::try_from(mci.data.custom_id.as_str()) {
+                    Ok(custom_id) => custom_id,
+                    Err(err) => {
+                        tracing::warn!("invalid interaction id `{}`: {err}", mci.data.custom_id);
+                        util::run_and_report_error(&mci, http, async {
+                            Err(err.context("invalid interaction id"))
+                        })
+                        .await;
+                        return;
+                    }
+                };
 
                 match custom_id {
                     cid::CustomId::Generation { id, value } => match value {
                         cid::Generation::Retry => {
-                            exmc::retry(&self.client, &self.models, &self.store, http, &mci, id)
+                            exmc::retry(&self.client, &self.all_models, &self.store, http, &mci, id)
                                 .await
                         }
                         cid::Generation::RetryWithOptions => {
@@ -282,18 +426,41 @@ impl EventHandler for Handler {
                             )
                             .await
                         }
+                        cid::Generation::ShowSeed => {
+                            exmc::show_seed(&self.store, http, &mci, id).await
+                        }
+                        cid::Generation::HiResRegen => {
+                            exmc::hires_regen(
+                                &self.client,
+                                &self.all_models,
+                                &self.store,
+                                http,
+                                &mci,
+                                id,
+                            )
+                            .await
+                        }
+                        cid::Generation::Label => exmc::label(&self.store, http, &mci, id).await,
                         cid::Generation::RetryWithOptionsResponse => unreachable!(),
                         cid::Generation::RemixResponse => unreachable!(),
+                        cid::Generation::LabelResponse => unreachable!(),
                     },
                     cid::CustomId::Interrogation { id, value } => match value {
-                        cid::Interrogation::Generate => {
+                        cid::Interrogation::Generate
+                        | cid::Interrogation::GenerateShuffled
+                        | cid::Interrogation::GenerateInOrder => {
+                            let shuffle = match value {
+                                cid::Interrogation::GenerateShuffled => Some(true),
+                                cid::Interrogation::GenerateInOrder => Some(false),
+                                _ => None,
+                            };
                             exmc::interrogate_generate(
                                 &self.client,
-                                &self.models,
+                                &self.all_models,
                                 &self.store,
                                 http,
                                 &mci,
-                                id,
+                                (id, shuffle),
                             )
                             .await
                         }
@@ -319,36 +486,80 @@ impl EventHandler for Handler {
                             )
                             .await
                         }
+                        cid::Interrogation::SendToWirehead => {
+                            whmc::interrogation_to_wirehead(&self.store, http, &mci, id).await
+                        }
+                        cid::Interrogation::SendToWireheadResponse => {
+                            whmc::interrogation_to_wirehead_response(
+                                ctx.http.clone(),
+                                mci,
+                                &self.sessions,
+                                (self.client.clone(), &self.models),
+                                &self.store,
+                                id,
+                            )
+                            .await
+                        }
                     },
                     cid::CustomId::Wirehead { genome, value } => match value.value {
-                        cid::WireheadValue::ToExilent => {
+                        cid::WireheadValue::ToExilent | cid::WireheadValue::ToExilentNewSeed => {
+                            let seed = matches!(value.value, cid::WireheadValue::ToExilent)
+                                .then_some(value.seed);
                             whmc::to_exilent(
                                 &self.sessions,
                                 &self.store,
-                                (&self.client, &self.models),
+                                (&self.client, &self.all_models),
                                 http,
                                 mci,
                                 genome,
-                                value.seed,
+                                seed,
                             )
                             .await
                         }
                         _ => whmc::rate(&self.sessions, http, mci, genome, value).await,
                     },
+                    cid::CustomId::Recent { page, value } => {
+                        exmc::recent_page(&self.store, http, &mci, page, value).await
+                    }
+                    cid::CustomId::Task { id, value } => match value {
+                        cid::Task::Cancel => exmc::cancel(http, &mci, id).await,
+                        cid::Task::ConfirmConfig => {
+                            exmc::confirm_config(&self.store, http, &mci, id).await
+                        }
+                        cid::Task::RestartWirehead => {
+                            whmc::restart(
+                                ctx.http.clone(),
+                                &mci,
+                                &self.sessions,
+                                self.client.clone(),
+                                id,
+                            )
+                            .await
+                        }
+                    },
                 }
             }
             Interaction::ModalSubmit(msi) => {
                 use exilent::message_component as exmc;
 
-                let custom_id = cid::CustomId::try_from(msi.data.custom_id.as_str())
-                    .expect("invalid interaction id");
+                let custom_id = match cid::CustomId::try_from(msi.data.custom_id.as_str()) {
+                    Ok(custom_id) => custom_id,
+                    Err(err) => {
+                        tracing::warn!("invalid interaction id `{}`: {err}", msi.data.custom_id);
+                        util::run_and_report_error(&msi, http, async {
+                            Err(err.context("invalid interaction id"))
+                        })
+                        .await;
+                        return;
+                    }
+                };
 
                 match custom_id {
                     cid::CustomId::Generation { id, value } => match value {
                         cid::Generation::RetryWithOptionsResponse => {
                             exmc::retry_with_options_response(
                                 &self.client,
-                                &self.models,
+                                &self.all_models,
                                 &self.store,
                                 http,
                                 &msi,
@@ -359,7 +570,7 @@ impl EventHandler for Handler {
                         cid::Generation::RemixResponse => {
                             exmc::remix_response(
                                 &self.client,
-                                &self.models,
+                                &self.all_models,
                                 &self.store,
                                 http,
                                 &msi,
@@ -367,6 +578,9 @@ impl EventHandler for Handler {
                             )
                             .await
                         }
+                        cid::Generation::LabelResponse => {
+                            exmc::label_response(&self.store, http, &msi, id).await
+                        }
 
                         cid::Generation::Retry => unreachable!(),
                         cid::Generation::RetryWithOptions => unreachable!(),
@@ -374,11 +588,20 @@ impl EventHandler for Handler {
                         cid::Generation::Upscale => unreachable!(),
                         cid::Generation::InterrogateClip => unreachable!(),
                         cid::Generation::InterrogateDeepDanbooru => unreachable!(),
+                        cid::Generation::ShowSeed => unreachable!(),
+                        cid::Generation::HiResRegen => unreachable!(),
+                        cid::Generation::Label => unreachable!(),
                     },
                     cid::CustomId::Interrogation { .. } => unreachable!(),
                     cid::CustomId::Wirehead { .. } => unreachable!(),
+                    cid::CustomId::Recent { .. } => unreachable!(),
+                    cid::CustomId::Task { .. } => unreachable!(),
                 }
             }
+            Interaction::Autocomplete(aci) => {
+                exilent::command::autocomplete(&self.models, &self.loras, &self.store, http, aci)
+                    .await
+            }
             _ => {}
         };
     }
@@ -5,15 +5,44 @@ use parking_lot::Mutex;
 use rusqlite::OptionalExtension;
 use serenity::model::id::{GuildId, UserId};
 use stable_diffusion_a1111_webui_client::Sampler;
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Duration};
 
-pub struct Store(Mutex<rusqlite::Connection>);
+/// The database is opened in WAL mode with separate connections for reading and writing,
+/// so that reads (e.g. from progress updates) aren't held up by large inserts.
+pub struct Store {
+    writer: Mutex<rusqlite::Connection>,
+    reader: Mutex<rusqlite::Connection>,
+}
 impl Store {
     const FILENAME: &'static str = "store.sqlite";
+    const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
 
     pub fn load() -> anyhow::Result<Self> {
-        let connection = rusqlite::Connection::open(Self::FILENAME)?;
-        connection.execute(
+        Self::from_connections(
+            rusqlite::Connection::open(Self::FILENAME)?,
+            rusqlite::Connection::open(Self::FILENAME)?,
+        )
+    }
+
+    /// A store that only lives in memory; `name` has to be unique to the test
+    #[cfg(test)]
+    pub(crate) fn in_memory(name: &str) -> Self {
+        let open =
+            || rusqlite::Connection::open(format!("file:{name}?mode=memory&cache=shared")).unwrap();
+        Self::from_connections(open(), open()).unwrap()
+    }
+
+    fn from_connections(
+        writer: rusqlite::Connection,
+        reader: rusqlite::Connection,
+    ) -> anyhow::Result<Self> {
+        for c in [&writer, &reader] {
+            c.pragma_update(None, "journal_mode", "WAL")?;
+            c.pragma_update(None, "synchronous", "NORMAL")?;
+            c.busy_timeout(Self::BUSY_TIMEOUT)?;
+        }
+
+        writer.execute(
             r"
             CREATE TABLE IF NOT EXISTS generation (
                 id	                INTEGER PRIMARY KEY AUTOINCREMENT,
@@ -30,8 +59,15 @@ impl Store {
                 sampler	            TEXT NOT NULL,
                 model_hash	        TEXT NOT NULL,
                 image	            BLOB NOT NULL,
+                thumbnail           BLOB,
                 image_url           TEXT,
+                message_url         TEXT,
                 denoising_strength  REAL NOT NULL,
+                eta                 REAL,
+                duration_ms         INTEGER,
+                hires_denoising_strength REAL,
+                parent_generation_id INTEGER,
+                derivation          TEXT,
 
                 user_id             TEXT NOT NULL,
                 timestamp	        TEXT NOT NULL,
@@ -40,12 +76,21 @@ impl Store {
                 -- img2img specific fields
                 init_image          BLOB,
                 resize_mode         TEXT,
-                init_url            TEXT
+                init_url            TEXT,
+                init_mask           BLOB
             ) STRICT;
             ",
             (),
         )?;
-        connection.execute(
+        add_column_if_missing(&writer, "generation", "eta", "REAL")?;
+        add_column_if_missing(&writer, "generation", "duration_ms", "INTEGER")?;
+        add_column_if_missing(&writer, "generation", "hires_denoising_strength", "REAL")?;
+        add_column_if_missing(&writer, "generation", "thumbnail", "BLOB")?;
+        add_column_if_missing(&writer, "generation", "parent_generation_id", "INTEGER")?;
+        add_column_if_missing(&writer, "generation", "derivation", "TEXT")?;
+        add_column_if_missing(&writer, "generation", "message_url", "TEXT")?;
+        add_column_if_missing(&writer, "generation", "init_mask", "BLOB")?;
+        writer.execute(
             r"
             CREATE TABLE IF NOT EXISTS interrogation (
                 id	            INTEGER PRIMARY KEY AUTOINCREMENT,
@@ -64,25 +109,121 @@ impl Store {
         ",
             (),
         )?;
+        writer.execute(
+            r"
+            CREATE TABLE IF NOT EXISTS label (
+                generation_id   INTEGER NOT NULL,
+                label           TEXT NOT NULL,
+
+                PRIMARY KEY(generation_id, label),
+                FOREIGN KEY(generation_id)  REFERENCES generation(id)
+            ) STRICT;
+        ",
+            (),
+        )?;
+        writer.execute(
+            r"
+            CREATE TABLE IF NOT EXISTS upload (
+                id	            INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id	        TEXT NOT NULL,
+                timestamp	    TEXT NOT NULL,
+                guild_id        TEXT NOT NULL,
+
+                url	            TEXT NOT NULL,
+                image	        BLOB NOT NULL,
+
+                UNIQUE (guild_id, url)
+            ) STRICT;
+        ",
+            (),
+        )?;
+        let usage_exists = table_exists(&writer, "usage")?;
+        writer.execute(
+            r"
+            CREATE TABLE IF NOT EXISTS usage (
+                id	            INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id	        TEXT NOT NULL,
+                timestamp	    TEXT NOT NULL,
+
+                count	        INTEGER NOT NULL
+            ) STRICT;
+        ",
+            (),
+        )?;
+        if !usage_exists {
+            // quotas used to be counted from the generations, so carry them over
+            writer.execute(
+                r"
+                INSERT INTO usage (user_id, timestamp, count)
+                SELECT user_id, timestamp, 1 FROM generation
+                ",
+                (),
+            )?;
+        }
+        writer.execute(
+            r"
+            CREATE TABLE IF NOT EXISTS audit_log (
+                id	            INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id	        TEXT NOT NULL,
+                timestamp	    TEXT NOT NULL,
+
+                action	        TEXT NOT NULL,
+                details	        TEXT NOT NULL
+            ) STRICT;
+        ",
+            (),
+        )?;
+        writer.execute(
+            r"
+            CREATE TABLE IF NOT EXISTS user_settings (
+                user_id	        TEXT PRIMARY KEY,
 
-        Ok(Self(Mutex::new(connection)))
+                dm_results	    INTEGER NOT NULL
+            ) STRICT;
+        ",
+            (),
+        )?;
+
+        Ok(Self {
+            writer: Mutex::new(writer),
+            reader: Mutex::new(reader),
+        })
     }
 
     pub fn insert_generation(&self, generation: Generation) -> anyhow::Result<i64> {
         let g = generation;
-        let db = &mut *self.0.lock();
+        // encode ahead of time to avoid holding the lock for longer than necessary
+        let init_image = g
+            .image_generation
+            .as_ref()
+            .map(|ig| util::encode_image_to_png_bytes(ig.init_image.clone()))
+            .transpose()?;
+        let init_mask = g
+            .image_generation
+            .as_ref()
+            .and_then(|ig| ig.mask.clone())
+            .map(util::encode_image_to_png_bytes)
+            .transpose()?;
+        // kept alongside the full image so that listings don't need to load the full image
+        let thumbnail = image::load_from_memory(&g.image)
+            .ok()
+            .map(|image| util::make_thumbnail(&image))
+            .transpose()?;
+
+        let db = &mut *self.writer.lock();
         db.execute(
             r"
             INSERT INTO generation
                 (prompt, negative_prompt, seed, width, height, cfg_scale, steps, tiling,
                  restore_faces, sampler, model_hash, image, user_id, timestamp, guild_id, denoising_strength,
-                 init_image, resize_mode, init_url)
+                 init_image, resize_mode, init_url, eta, duration_ms, hires_denoising_strength,
+                 thumbnail, parent_generation_id, derivation, init_mask)
             VALUES
-                (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+                (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
             ",
             rusqlite::params![
                 g.prompt,
-                g.negative_prompt,
+                g.negative_prompt.as_deref().filter(|s| !s.is_empty()),
                 g.seed,
                 g.width,
                 g.height,
@@ -97,25 +238,35 @@ impl Store {
                 g.timestamp,
                 g.guild_id.as_u64().to_string(),
                 g.denoising_strength,
-                g.image_generation
-                    .as_ref()
-                    .map(|ig| util::encode_image_to_png_bytes(ig.init_image.clone()))
-                    .transpose()?,
+                init_image,
                 g.image_generation
                     .as_ref()
                     .map(|ig| ig.resize_mode.to_string()),
                 g.image_generation.as_ref().map(|ig| ig.init_url.as_str()),
+                g.eta,
+                g.duration_ms,
+                g.hires_denoising_strength,
+                thumbnail,
+                g.parent_generation_id,
+                g.derivation.map(Derivation::as_str),
+                init_mask,
             ],
         )?;
 
         Ok(db.last_insert_rowid())
     }
 
-    pub fn set_generation_url(&self, key: i64, url: &str) -> anyhow::Result<()> {
-        let db = &mut *self.0.lock();
+    /// Records where the generation was posted: the URL of its image, and of the message itself
+    pub fn set_generation_url(
+        &self,
+        key: i64,
+        image_url: &str,
+        message_url: &str,
+    ) -> anyhow::Result<()> {
+        let db = &mut *self.writer.lock();
         db.execute(
-            r"UPDATE generation SET image_url = ? WHERE id = ?",
-            (url, key),
+            r"UPDATE generation SET image_url = ?, message_url = ? WHERE id = ?",
+            (image_url, message_url, key),
         )?;
 
         Ok(())
@@ -138,7 +289,7 @@ impl Store {
 
     pub fn insert_interrogation(&self, interrogation: Interrogation) -> anyhow::Result<i64> {
         let i = interrogation;
-        let db = &mut *self.0.lock();
+        let db = &mut *self.writer.lock();
         db.execute(
             r"
             INSERT INTO interrogation
@@ -161,7 +312,7 @@ impl Store {
     }
 
     pub fn get_interrogation(&self, key: i64) -> anyhow::Result<Option<Interrogation>> {
-        let db = &mut *self.0.lock();
+        let db = &mut *self.reader.lock();
         let Some((user_id, generation_id, guild_id, url, result, interrogator)) = db
             .query_row(
                 r"
@@ -192,19 +343,24 @@ impl Store {
         )?))
     }
 
+    /// Returns how often each user in the guild has used each model, most used first
     pub fn get_model_usage_counts(
         &self,
         guild_id: GuildId,
-    ) -> anyhow::Result<HashMap<UserId, Vec<(String, u64)>>> {
-        self.0
+    ) -> anyhow::Result<HashMap<UserId, Vec<ModelUsage>>> {
+        self.reader
             .lock()
             .prepare(
                 r#"
-                SELECT user_id, model_hash, COUNT(*) AS count
+                SELECT
+                    user_id,
+                    model_hash,
+                    SUM(init_image IS NULL) AS text_count,
+                    SUM(init_image IS NOT NULL) AS image_count
                 FROM generation
                 WHERE guild_id = :guild_id
                 GROUP BY user_id, model_hash
-                ORDER BY user_id, count DESC
+                ORDER BY user_id, COUNT(*) DESC
                 "#,
             )?
             .query_map(
@@ -212,26 +368,577 @@ impl Store {
                 |row| row.try_into(),
             )?
             .flat_map(Result::ok)
-            .group_by(|(uid, _, _): &(String, String, i64)| uid.clone())
+            .group_by(|(uid, _, _, _): &(String, String, i64, i64)| uid.clone())
             .into_iter()
             .map(|(uid, group)| {
                 anyhow::Ok((
                     UserId(uid.parse()?),
                     group
                         .into_iter()
-                        .map(|(_, hash, count)| (hash, count as u64))
-                        .collect::<Vec<(String, u64)>>(),
+                        .map(|(_, model_hash, text_count, image_count)| ModelUsage {
+                            model_hash,
+                            text_count: text_count as u64,
+                            image_count: image_count as u64,
+                        })
+                        .collect(),
+                ))
+            })
+            .collect::<Result<_, _>>()
+    }
+
+    /// Returns the ID and thumbnail of each user's most recent generation in the guild.
+    /// Generations from before thumbnails were stored fall back to the full image.
+    pub fn get_latest_thumbnail_per_user(
+        &self,
+        guild_id: GuildId,
+    ) -> anyhow::Result<HashMap<UserId, (i64, Vec<u8>)>> {
+        self.reader
+            .lock()
+            .prepare(
+                r#"
+                SELECT user_id, id, COALESCE(thumbnail, image)
+                FROM generation
+                WHERE id IN (
+                    SELECT MAX(id)
+                    FROM generation
+                    WHERE guild_id = :guild_id
+                    GROUP BY user_id
+                )
+                "#,
+            )?
+            .query_map(
+                &[(":guild_id", guild_id.as_u64().to_string().as_str())],
+                |row| row.try_into(),
+            )?
+            .map(|row| {
+                let (user_id, id, thumbnail): (String, i64, Vec<u8>) = row?;
+                anyhow::Ok((UserId(user_id.parse()?), (id, thumbnail)))
+            })
+            .collect()
+    }
+
+    /// Returns the total compute time in milliseconds and the number of timed generations
+    /// for each user in the guild
+    pub fn get_user_compute_time(
+        &self,
+        guild_id: GuildId,
+    ) -> anyhow::Result<HashMap<UserId, (u64, u64)>> {
+        self.reader
+            .lock()
+            .prepare(
+                r#"
+                SELECT user_id, SUM(duration_ms) AS total, COUNT(duration_ms) AS count
+                FROM generation
+                WHERE guild_id = :guild_id AND duration_ms IS NOT NULL
+                GROUP BY user_id
+                "#,
+            )?
+            .query_map(
+                &[(":guild_id", guild_id.as_u64().to_string().as_str())],
+                |row| row.try_into(),
+            )?
+            .flat_map(Result::ok)
+            .map(|(uid, total, count): (String, i64, i64)| {
+                anyhow::Ok((UserId(uid.parse()?), (total as u64, count as u64)))
+            })
+            .collect::<Result<_, _>>()
+    }
+
+    /// Finds the generations matching the predicate whose prompts contain the term, newest first,
+    /// optionally only those with the label. Returns the requested page of results and the total
+    /// number of matches.
+    pub fn search_generations(
+        &self,
+        predicate: GenerationPredicate,
+        term: &str,
+        label: Option<&str>,
+        (limit, offset): (usize, usize),
+    ) -> anyhow::Result<(Vec<SearchResult>, usize)> {
+        let (guild_id, user_id) = match predicate {
+            GenerationPredicate::User(guild_id, user_id) => {
+                (guild_id, Some(user_id.as_u64().to_string()))
+            }
+            GenerationPredicate::Guild(guild_id) => (guild_id, None),
+        };
+        let guild_id = guild_id.as_u64().to_string();
+        let pattern = format!("%{}%", escape_like(term));
+        const FILTER: &str = r"
+            guild_id = :guild_id
+            AND (:user_id IS NULL OR user_id = :user_id)
+            AND prompt LIKE :pattern ESCAPE '\'
+            AND (:label IS NULL OR id IN (SELECT generation_id FROM label WHERE label = :label))
+        ";
+
+        let db = &mut *self.reader.lock();
+        let count: i64 = db.query_row(
+            &format!("SELECT COUNT(*) FROM generation WHERE {FILTER}"),
+            rusqlite::named_params! {
+                ":guild_id": guild_id,
+                ":user_id": user_id,
+                ":pattern": pattern,
+                ":label": label,
+            },
+            |r| r.get(0),
+        )?;
+
+        let results = db
+            .prepare(&format!(
+                r"
+                SELECT id, prompt, image_url, message_url, timestamp, user_id, thumbnail
+                FROM generation
+                WHERE {FILTER}
+                ORDER BY id DESC
+                LIMIT :limit OFFSET :offset
+                "
+            ))?
+            .query_map(
+                rusqlite::named_params! {
+                    ":guild_id": guild_id,
+                    ":user_id": user_id,
+                    ":pattern": pattern,
+                    ":label": label,
+                    ":limit": limit as i64,
+                    ":offset": offset as i64,
+                },
+                |r| {
+                    Ok((
+                        r.get::<_, i64>(0)?,
+                        r.get::<_, String>(1)?,
+                        r.get::<_, Option<String>>(2)?,
+                        r.get::<_, Option<String>>(3)?,
+                        r.get::<_, chrono::DateTime<chrono::Local>>(4)?,
+                        r.get::<_, String>(5)?,
+                        r.get::<_, Option<Vec<u8>>>(6)?,
+                    ))
+                },
+            )?
+            .map(|r| {
+                let (id, prompt, image_url, message_url, timestamp, user_id, thumbnail) = r?;
+                Ok(SearchResult {
+                    id,
+                    prompt,
+                    image_url,
+                    message_url,
+                    timestamp,
+                    user_id: UserId(user_id.parse()?),
+                    thumbnail,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok((results, count as usize))
+    }
+
+    /// Returns the user's most recently used distinct prompts that start with the prefix,
+    /// newest first
+    pub fn recent_prompts_for_user(
+        &self,
+        user_id: UserId,
+        prefix: &str,
+        limit: usize,
+    ) -> anyhow::Result<Vec<String>> {
+        self.reader
+            .lock()
+            .prepare(
+                r"
+                SELECT prompt
+                FROM generation
+                WHERE user_id = :user_id AND prompt LIKE :pattern ESCAPE '\'
+                GROUP BY prompt
+                ORDER BY MAX(id) DESC
+                LIMIT :limit
+                ",
+            )?
+            .query_map(
+                rusqlite::named_params! {
+                    ":user_id": user_id.as_u64().to_string(),
+                    ":pattern": format!("{}%", escape_like(prefix)),
+                    ":limit": limit as i64,
+                },
+                |r| r.get(0),
+            )?
+            .collect::<Result<_, _>>()
+            .map_err(Into::into)
+    }
+
+    /// Returns the requested page of the guild's generations from every user, newest first,
+    /// along with the total number of generations
+    pub fn recent_generations(
+        &self,
+        guild_id: GuildId,
+        page: (usize, usize),
+    ) -> anyhow::Result<(Vec<SearchResult>, usize)> {
+        self.search_generations(GenerationPredicate::Guild(guild_id), "", None, page)
+    }
+
+    /// Replaces the generation's labels with the given ones
+    pub fn set_labels(&self, generation_id: i64, labels: &[String]) -> anyhow::Result<()> {
+        let db = &mut *self.writer.lock();
+        let transaction = db.transaction()?;
+        transaction.execute("DELETE FROM label WHERE generation_id = ?", [generation_id])?;
+        for label in labels {
+            transaction.execute(
+                "INSERT OR IGNORE INTO label (generation_id, label) VALUES (?, ?)",
+                (generation_id, label),
+            )?;
+        }
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    /// Records an image that was given to a command by URL, along with its contents, returning
+    /// its ID so that it can be referred to again without re-uploading it. Images seen before
+    /// in the same server keep their ID.
+    pub fn insert_upload(
+        &self,
+        user_id: UserId,
+        guild_id: GuildId,
+        url: &str,
+        image: &[u8],
+    ) -> anyhow::Result<i64> {
+        let guild_id = guild_id.as_u64().to_string();
+        let db = &mut *self.writer.lock();
+        db.execute(
+            r"
+            INSERT OR IGNORE INTO upload
+                (user_id, timestamp, guild_id, url, image)
+            VALUES
+                (?, ?, ?, ?, ?)
+            ",
+            (
+                user_id.as_u64().to_string(),
+                chrono::Local::now(),
+                &guild_id,
+                url,
+                image,
+            ),
+        )?;
+
+        Ok(db.query_row(
+            "SELECT id FROM upload WHERE guild_id = ? AND url = ?",
+            (&guild_id, url),
+            |r| r.get(0),
+        )?)
+    }
+
+    /// Returns the ID of the image uploaded from the URL in the server, if there is one
+    pub fn find_upload(&self, guild_id: GuildId, url: &str) -> anyhow::Result<Option<i64>> {
+        Ok(self
+            .reader
+            .lock()
+            .query_row(
+                "SELECT id FROM upload WHERE guild_id = ? AND url = ?",
+                (guild_id.as_u64().to_string(), url),
+                |r| r.get(0),
+            )
+            .optional()?)
+    }
+
+    /// Returns the URL and contents of the image uploaded with the given ID, if it was
+    /// uploaded in the server
+    pub fn get_upload(
+        &self,
+        id: i64,
+        guild_id: GuildId,
+    ) -> anyhow::Result<Option<(String, Vec<u8>)>> {
+        Ok(self
+            .reader
+            .lock()
+            .query_row(
+                "SELECT url, image FROM upload WHERE id = ? AND guild_id = ?",
+                (id, guild_id.as_u64().to_string()),
+                |r| Ok((r.get(0)?, r.get(1)?)),
+            )
+            .optional()?)
+    }
+
+    /// Records an action taken by an administrator
+    pub fn insert_audit_log(
+        &self,
+        user_id: UserId,
+        action: &str,
+        details: &str,
+    ) -> anyhow::Result<()> {
+        self.writer.lock().execute(
+            "INSERT INTO audit_log (user_id, timestamp, action, details) VALUES (?, ?, ?, ?)",
+            (
+                user_id.as_u64().to_string(),
+                chrono::Local::now(),
+                action,
+                details,
+            ),
+        )?;
+
+        Ok(())
+    }
+
+    /// Returns the most recent administrator actions, newest first
+    pub fn recent_audit_log(&self, limit: usize) -> anyhow::Result<Vec<AuditLogEntry>> {
+        self.reader
+            .lock()
+            .prepare(
+                "SELECT user_id, timestamp, action, details FROM audit_log ORDER BY id DESC LIMIT ?",
+            )?
+            .query_map([limit as i64], |r| {
+                Ok((
+                    r.get::<_, String>(0)?,
+                    r.get::<_, chrono::DateTime<chrono::Local>>(1)?,
+                    r.get::<_, String>(2)?,
+                    r.get::<_, String>(3)?,
                 ))
+            })?
+            .map(|r| {
+                let (user_id, timestamp, action, details) = r?;
+                Ok(AuditLogEntry {
+                    user_id: UserId(user_id.parse()?),
+                    timestamp,
+                    action,
+                    details,
+                })
             })
+            .collect()
+    }
+
+    /// Returns the user's settings, or the defaults if they haven't changed any
+    pub fn get_user_settings(&self, user_id: UserId) -> anyhow::Result<UserSettings> {
+        Ok(self
+            .reader
+            .lock()
+            .query_row(
+                "SELECT dm_results FROM user_settings WHERE user_id = ?",
+                [user_id.as_u64().to_string()],
+                |r| {
+                    Ok(UserSettings {
+                        dm_results: r.get(0)?,
+                    })
+                },
+            )
+            .optional()?
+            .unwrap_or_default())
+    }
+
+    pub fn set_user_settings(
+        &self,
+        user_id: UserId,
+        settings: &UserSettings,
+    ) -> anyhow::Result<()> {
+        self.writer.lock().execute(
+            r"
+            INSERT INTO user_settings (user_id, dm_results) VALUES (?, ?)
+            ON CONFLICT (user_id) DO UPDATE SET dm_results = excluded.dm_results
+            ",
+            (user_id.as_u64().to_string(), settings.dm_results),
+        )?;
+        Ok(())
+    }
+
+    /// Returns the generation's labels, in alphabetical order
+    pub fn get_labels(&self, generation_id: i64) -> anyhow::Result<Vec<String>> {
+        self.reader
+            .lock()
+            .prepare("SELECT label FROM label WHERE generation_id = ? ORDER BY label")?
+            .query_map([generation_id], |r| r.get(0))?
             .collect::<Result<_, _>>()
+            .map_err(Into::into)
+    }
+
+    /// Deletes the user's generations beyond the `keep` most recent, along with their
+    /// interrogations and labels, returning how many generations were deleted. The user's
+    /// uploads beyond the `keep` most recent are deleted too.
+    pub fn prune_user_generations(&self, user_id: UserId, keep: usize) -> anyhow::Result<usize> {
+        const PRUNED_IDS: &str = r"
+            SELECT id
+            FROM generation
+            WHERE user_id = ?
+            ORDER BY id DESC
+            LIMIT -1 OFFSET ?
+        ";
+        let params = (user_id.as_u64().to_string(), keep as i64);
+
+        let db = &mut *self.writer.lock();
+        let transaction = db.transaction()?;
+        transaction.execute(
+            &format!("DELETE FROM interrogation WHERE generation_id IN ({PRUNED_IDS})"),
+            params.clone(),
+        )?;
+        transaction.execute(
+            &format!("DELETE FROM label WHERE generation_id IN ({PRUNED_IDS})"),
+            params.clone(),
+        )?;
+        let deleted = transaction.execute(
+            &format!("DELETE FROM generation WHERE id IN ({PRUNED_IDS})"),
+            params.clone(),
+        )?;
+        transaction.execute(
+            r"
+            DELETE FROM upload WHERE id IN (
+                SELECT id
+                FROM upload
+                WHERE user_id = ?
+                ORDER BY id DESC
+                LIMIT -1 OFFSET ?
+            )
+            ",
+            params,
+        )?;
+        transaction.commit()?;
+
+        Ok(deleted)
+    }
+
+    /// Records that the user is about to generate `count` images, returning the ID of the
+    /// record so that it can be undone with [Self::refund_usage] if they aren't generated.
+    ///
+    /// Fails if that would take the user over any of the `quotas`, each of which is the
+    /// name of its period, the start of the period and the number of images allowed in it.
+    /// The usage is checked and recorded under the writer lock, so that concurrent requests
+    /// can't all slip under a quota.
+    pub fn reserve_usage(
+        &self,
+        user_id: UserId,
+        count: usize,
+        quotas: &[(&str, chrono::DateTime<chrono::Local>, usize)],
+    ) -> anyhow::Result<i64> {
+        let user_id = user_id.as_u64().to_string();
+        let db = &mut *self.writer.lock();
+        for (period, since, quota) in quotas {
+            // timestamps are stored with their offset, so compare them as instants
+            let used: i64 = db.query_row(
+                r"
+                SELECT COALESCE(SUM(count), 0)
+                FROM usage
+                WHERE user_id = ? AND julianday(timestamp) >= julianday(?)
+                ",
+                (&user_id, since),
+                |r| r.get(0),
+            )?;
+            let remaining = quota.saturating_sub(used as usize);
+            if remaining == 0 {
+                anyhow::bail!("You've hit your {period} limit of {quota}.");
+            }
+            if count > remaining {
+                anyhow::bail!(
+                    "You only have {remaining} images left of your {period} limit of {quota}."
+                );
+            }
+        }
+
+        db.execute(
+            "INSERT INTO usage (user_id, timestamp, count) VALUES (?, ?, ?)",
+            (&user_id, chrono::Local::now(), count as i64),
+        )?;
+        Ok(db.last_insert_rowid())
+    }
+
+    /// Undoes usage recorded by [Self::reserve_usage], for images that weren't generated
+    pub fn refund_usage(&self, id: i64) -> anyhow::Result<()> {
+        self.writer
+            .lock()
+            .execute("DELETE FROM usage WHERE id = ?", [id])?;
+        Ok(())
     }
 }
 
+/// Escapes LIKE's wildcards so that the term is matched literally (with `ESCAPE '\'`)
+fn escape_like(term: &str) -> String {
+    term.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// The generations to search through
+#[derive(Debug, Clone, Copy)]
+pub enum GenerationPredicate {
+    User(GuildId, UserId),
+    Guild(GuildId),
+}
+
+/// An administrator action recorded by [Store::insert_audit_log]
+#[derive(Debug, Clone)]
+pub struct AuditLogEntry {
+    pub user_id: UserId,
+    pub timestamp: chrono::DateTime<chrono::Local>,
+    pub action: String,
+    pub details: String,
+}
+
+/// The preferences a user has set with `/exilent settings`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UserSettings {
+    /// whether to send the results of `/paint` to the user's DMs
+    pub dm_results: bool,
+}
+
+/// A generation found by [Store::search_generations], without its images
+#[derive(Debug, Clone)]
+pub struct SearchResult {
+    pub id: i64,
+    pub prompt: String,
+    pub image_url: Option<String>,
+    /// the link to the message the generation was posted in; absent for older generations
+    pub message_url: Option<String>,
+    pub timestamp: chrono::DateTime<chrono::Local>,
+    pub user_id: UserId,
+    /// a small PNG of the image; absent for older generations
+    pub thumbnail: Option<Vec<u8>>,
+}
+
 #[derive(Debug, Clone)]
 pub struct ImageGeneration {
     pub init_image: image::DynamicImage,
     pub init_url: String,
     pub resize_mode: sd::ResizeMode,
+    /// the region of the image to paint in (e.g. the new area when outpainting); if absent,
+    /// the whole image is repainted
+    pub mask: Option<image::DynamicImage>,
+}
+
+/// How often a model has been used, split by the kind of generation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModelUsage {
+    pub model_hash: String,
+    /// text-to-image generations
+    pub text_count: u64,
+    /// image-to-image generations
+    pub image_count: u64,
+}
+impl ModelUsage {
+    pub fn total(&self) -> u64 {
+        self.text_count + self.image_count
+    }
+}
+
+/// How a generation was made from the one it was derived from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Derivation {
+    Retry,
+    Remix,
+    Paintover,
+    Upres,
+}
+impl Derivation {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Retry => "retry",
+            Self::Remix => "remix",
+            Self::Paintover => "paintover",
+            Self::Upres => "upres",
+        }
+    }
+}
+impl TryFrom<&str> for Derivation {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Ok(match value {
+            "retry" => Self::Retry,
+            "remix" => Self::Remix,
+            "paintover" => Self::Paintover,
+            "upres" => Self::Upres,
+            _ => anyhow::bail!("invalid derivation `{value}`"),
+        })
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -254,6 +961,15 @@ pub struct Generation {
     pub user_id: UserId,
     pub guild_id: GuildId,
     pub denoising_strength: f32,
+    /// the denoising strength of hires fix's upscaling pass; present only if hires fix was used
+    pub hires_denoising_strength: Option<f32>,
+    pub eta: Option<f32>,
+    /// how long the backend spent on this image; absent for older generations
+    pub duration_ms: Option<u64>,
+    /// the generation this was derived from, if any
+    pub parent_generation_id: Option<i64>,
+    /// how this was made from its parent; absent for generations from before this was recorded
+    pub derivation: Option<Derivation>,
     pub image_generation: Option<ImageGeneration>,
 }
 impl Generation {
@@ -261,8 +977,8 @@ impl Generation {
         use crate::constant as c;
         let commands = &Configuration::get().commands;
         format!(
-            "`/{} {}:{}{} {}:{} {}:{} {}:{} {}:{} {}:{} {}:{} {}:{} {}:{} {}:{}{}{}`",
-            &commands.paint,
+            "`/{} {}:{}{} {}:{} {}:{} {}:{} {}:{} {}:{} {}:{} {}:{} {}:{} {}:{}{}{}{}{}`",
+            commands.full_name(&commands.paint),
             c::value::PROMPT,
             self.prompt,
             self.negative_prompt
@@ -287,6 +1003,12 @@ impl Generation {
             self.sampler,
             c::value::DENOISING_STRENGTH,
             self.denoising_strength,
+            self.hires_denoising_strength
+                .map(|s| format!(" {}:{s}", c::value::HIRES_DENOISING_STRENGTH))
+                .unwrap_or_default(),
+            self.eta
+                .map(|eta| format!(" {}:{eta}", c::value::ETA))
+                .unwrap_or_default(),
             util::find_model_by_hash(models, &self.model_hash)
                 .map(|(idx, m)| {
                     let model_category = idx / c::value::MODEL_CHUNK_COUNT;
@@ -317,6 +1039,82 @@ impl Generation {
         )
     }
 
+    /// The parameters of the generation (other than the prompt) as name-value pairs, for
+    /// showing in an embed
+    pub fn as_embed_fields(&self, models: &[sd::Model]) -> Vec<(&'static str, String)> {
+        let mut fields = vec![];
+        if let Some(negative_prompt) = &self.negative_prompt {
+            fields.push(("Negative prompt", negative_prompt.clone()));
+        }
+        fields.extend([
+            ("Model", util::model_hash_to_name(models, &self.model_hash)),
+            ("Seed", self.seed.to_string()),
+            ("Size", format!("{}x{}", self.width, self.height)),
+            ("Guidance scale", self.cfg_scale.to_string()),
+            ("Steps", self.steps.to_string()),
+            ("Sampler", self.sampler.to_string()),
+        ]);
+        if let Some(image_generation) = &self.image_generation {
+            fields.extend([
+                ("Denoising strength", self.denoising_strength.to_string()),
+                ("Resize mode", image_generation.resize_mode.to_string()),
+                ("Image", image_generation.init_url.clone()),
+            ]);
+        }
+        if let Some(strength) = self.hires_denoising_strength {
+            fields.push(("Hires denoising strength", strength.to_string()));
+        }
+        if let Some(eta) = self.eta {
+            fields.push(("Eta", eta.to_string()));
+        }
+        if self.tiling {
+            fields.push(("Tiling", "yes".to_string()));
+        }
+        if self.restore_faces {
+            fields.push(("Restore faces", "yes".to_string()));
+        }
+        fields
+    }
+
+    /// Formats the generation in the web UI's `parameters` format, so that it can be embedded
+    /// into images or pasted into other UIs
+    pub fn as_a1111_parameters(&self) -> String {
+        let mut settings = vec![
+            format!("Steps: {}", self.steps),
+            format!("Sampler: {}", self.sampler),
+            format!("CFG scale: {}", self.cfg_scale),
+            format!("Seed: {}", self.seed),
+            format!("Size: {}x{}", self.width, self.height),
+            format!("Model hash: {}", self.model_hash),
+        ];
+        if self.image_generation.is_some() {
+            settings.push(format!("Denoising strength: {}", self.denoising_strength));
+        } else if let Some(strength) = self.hires_denoising_strength {
+            // the web UI labels the hires pass's strength the same way for text-to-image
+            settings.push(format!("Denoising strength: {strength}"));
+        }
+        if let Some(eta) = self.eta {
+            settings.push(format!("Eta: {eta}"));
+        }
+        if self.tiling {
+            settings.push("Tiling: True".to_string());
+        }
+
+        format!(
+            "{}\n{}{}",
+            self.prompt,
+            self.negative_prompt
+                .as_ref()
+                .map(|s| format!("Negative prompt: {s}\n"))
+                .unwrap_or_default(),
+            settings.join(", ")
+        )
+    }
+
+    /// Rebuilds the request for this generation, so that it can be reissued.
+    ///
+    /// LoRAs are injected into the prompt when the generation is first issued, so they're
+    /// carried over with it.
     pub fn as_generation_request(&self, models: &[sd::Model]) -> GenerationRequest {
         let base = sd::BaseGenerationRequest {
             prompt: self.prompt.clone(),
@@ -333,6 +1131,7 @@ impl Generation {
             sampler: Some(self.sampler),
             model: util::find_model_by_hash(models, &self.model_hash).map(|t| t.1),
             denoising_strength: Some(self.denoising_strength),
+            eta: self.eta,
             ..Default::default()
         };
 
@@ -341,37 +1140,59 @@ impl Generation {
                 base,
                 resize_mode: Some(image_generation.resize_mode),
                 images: vec![image_generation.init_image.clone()],
+                mask: image_generation.mask.clone(),
+                inpainting_fill_mode: image_generation
+                    .mask
+                    .as_ref()
+                    .map(|_| sd::InpaintingFillMode::Fill),
                 ..Default::default()
             })
         } else {
-            GenerationRequest::Text(sd::TextToImageGenerationRequest {
-                base,
-                ..Default::default()
-            })
+            GenerationRequest::Text(
+                sd::TextToImageGenerationRequest {
+                    base,
+                    enable_hr: self.hires_denoising_strength.map(|_| true),
+                    ..Default::default()
+                },
+                self.hires_denoising_strength,
+            )
         }
     }
 }
 
 pub enum GenerationRequest {
-    Text(sd::TextToImageGenerationRequest),
+    /// The request and, if hires fix is enabled, the denoising strength of its upscaling pass
+    Text(sd::TextToImageGenerationRequest, Option<f32>),
     Image(sd::ImageToImageGenerationRequest),
 }
 impl GenerationRequest {
     pub fn base(&self) -> &sd::BaseGenerationRequest {
         match self {
-            GenerationRequest::Text(r) => &r.base,
+            GenerationRequest::Text(r, _) => &r.base,
             GenerationRequest::Image(r) => &r.base,
         }
     }
 
+    /// The denoising strength of hires fix's upscaling pass, if hires fix is enabled
+    pub fn hires_denoising_strength(&self) -> Option<f32> {
+        match self {
+            GenerationRequest::Text(_, hires_denoising_strength) => *hires_denoising_strength,
+            GenerationRequest::Image(_) => None,
+        }
+    }
+
     pub fn generate(
         &self,
         client: &sd::Client,
-    ) -> tokio::task::JoinHandle<sd::Result<sd::GenerationResult>> {
+    ) -> util::GenerationTask<sd::Result<sd::GenerationResult>> {
         match self {
-            GenerationRequest::Text(r) => tokio::task::spawn(client.generate_from_text(r)),
+            GenerationRequest::Text(r, hires_denoising_strength) => {
+                util::spawn_generation(client.generate_from_text(
+                    &util::with_hires_denoising_strength(r, *hires_denoising_strength),
+                ))
+            }
             GenerationRequest::Image(r) => {
-                tokio::task::spawn(client.generate_from_image_and_text(r))
+                util::spawn_generation(client.generate_from_image_and_text(r))
             }
         }
     }
@@ -443,7 +1264,7 @@ impl Store {
         predicate: &str,
         params: impl rusqlite::Params,
     ) -> anyhow::Result<Option<Generation>> {
-        let db = &mut *self.0.lock();
+        let db = &mut *self.reader.lock();
         let Some((
             prompt,
             negative_prompt,
@@ -466,6 +1287,12 @@ impl Store {
             image_url,
             id,
             guild_id,
+            eta,
+            duration_ms,
+            hires_denoising_strength,
+            parent_generation_id,
+            derivation,
+            init_mask,
         )) = db
             .query_row(
                 &format!(
@@ -474,7 +1301,8 @@ impl Store {
                         prompt, negative_prompt, seed, width, height, cfg_scale, steps, tiling,
                         restore_faces, sampler, model_hash, image, user_id, timestamp,
                         denoising_strength, init_image, resize_mode, init_url, image_url, id,
-                        guild_id
+                        guild_id, eta, duration_ms, hires_denoising_strength, parent_generation_id,
+                        derivation, init_mask
                     FROM
                         generation
                     WHERE
@@ -506,6 +1334,12 @@ impl Store {
                     let image_url: Option<String> = r.get(18)?;
                     let id: i64 = r.get(19)?;
                     let guild_id: String = r.get(20)?;
+                    let eta: Option<f32> = r.get(21)?;
+                    let duration_ms: Option<u64> = r.get(22)?;
+                    let hires_denoising_strength: Option<f32> = r.get(23)?;
+                    let parent_generation_id: Option<i64> = r.get(24)?;
+                    let derivation: Option<String> = r.get(25)?;
+                    let init_mask: Option<Vec<u8>> = r.get(26)?;
 
                     Ok((
                         prompt,
@@ -529,6 +1363,12 @@ impl Store {
                         image_url,
                         id,
                         guild_id,
+                        eta,
+                        duration_ms,
+                        hires_denoising_strength,
+                        parent_generation_id,
+                        derivation,
+                        init_mask,
                     ))
                 },
             )
@@ -558,6 +1398,13 @@ impl Store {
             user_id: UserId(user_id.parse()?),
             guild_id: GuildId(guild_id.parse()?),
             denoising_strength,
+            hires_denoising_strength,
+            eta,
+            duration_ms,
+            parent_generation_id,
+            derivation: derivation
+                .map(|d| Derivation::try_from(d.as_str()))
+                .transpose()?,
             image_generation: init_image
                 .zip(resize_mode)
                 .zip(init_url)
@@ -568,9 +1415,559 @@ impl Store {
                         resize_mode: sd::ResizeMode::try_from(resize_mode.as_str())
                             .ok()
                             .context("invalid resize mode")?,
+                        mask: init_mask
+                            .map(|mask| image::load_from_memory(&mask))
+                            .transpose()?,
                     })
                 })
                 .transpose()?,
         }))
     }
 }
+
+fn table_exists(connection: &rusqlite::Connection, table: &str) -> anyhow::Result<bool> {
+    Ok(connection
+        .query_row(
+            "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?",
+            [table],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some())
+}
+
+/// Adds a column to an existing table if it isn't already present, so that databases
+/// created before the column was introduced continue to work
+fn add_column_if_missing(
+    connection: &rusqlite::Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> anyhow::Result<()> {
+    let exists = connection
+        .prepare(&format!("PRAGMA table_info({table})"))?
+        .query_map((), |r| r.get::<_, String>(1))?
+        .flat_map(Result::ok)
+        .any(|name| name == column);
+
+    if !exists {
+        connection.execute(
+            &format!("ALTER TABLE {table} ADD COLUMN {column} {definition}"),
+            (),
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates a store whose reader and writer share the same in-memory database
+    fn in_memory_store(name: &str) -> Store {
+        Store::in_memory(name)
+    }
+
+    fn test_generation() -> Generation {
+        Generation {
+            id: None,
+            prompt: "my cool prompt".to_string(),
+            negative_prompt: None,
+            seed: 1,
+            width: 512,
+            height: 512,
+            cfg_scale: 7.0,
+            steps: 20,
+            tiling: false,
+            restore_faces: false,
+            sampler: Sampler::EulerA,
+            model_hash: "abcdef".to_string(),
+            image: vec![],
+            image_url: None,
+            timestamp: chrono::Local::now(),
+            user_id: UserId(1),
+            guild_id: GuildId(2),
+            denoising_strength: 0.75,
+            hires_denoising_strength: None,
+            eta: None,
+            duration_ms: None,
+            parent_generation_id: None,
+            derivation: None,
+            image_generation: None,
+        }
+    }
+
+    #[test]
+    fn empty_negative_prompt_is_stored_as_absent() {
+        let store = in_memory_store("empty_negative_prompt_is_stored_as_absent");
+        let key = store
+            .insert_generation(Generation {
+                id: None,
+                prompt: "my cool prompt".to_string(),
+                negative_prompt: Some(String::new()),
+                seed: 1,
+                width: 512,
+                height: 512,
+                cfg_scale: 7.0,
+                steps: 20,
+                tiling: false,
+                restore_faces: false,
+                sampler: Sampler::EulerA,
+                model_hash: "abcdef".to_string(),
+                image: vec![],
+                image_url: None,
+                timestamp: chrono::Local::now(),
+                user_id: UserId(1),
+                guild_id: GuildId(2),
+                denoising_strength: 0.75,
+                hires_denoising_strength: None,
+                eta: None,
+                duration_ms: None,
+                parent_generation_id: None,
+                derivation: None,
+                image_generation: None,
+            })
+            .unwrap();
+
+        let generation = store.get_generation(key).unwrap().unwrap();
+        assert_eq!(generation.negative_prompt, None);
+    }
+
+    #[test]
+    fn parent_generation_round_trips_through_store() {
+        let store = in_memory_store("parent_generation_round_trips_through_store");
+        let parent = store.insert_generation(test_generation()).unwrap();
+        let child = store
+            .insert_generation(Generation {
+                parent_generation_id: Some(parent),
+                derivation: Some(Derivation::Remix),
+                ..test_generation()
+            })
+            .unwrap();
+
+        let generation = store.get_generation(child).unwrap().unwrap();
+        assert_eq!(generation.parent_generation_id, Some(parent));
+        assert_eq!(generation.derivation, Some(Derivation::Remix));
+        let generation = store.get_generation(parent).unwrap().unwrap();
+        assert_eq!(generation.parent_generation_id, None);
+        assert_eq!(generation.derivation, None);
+    }
+
+    #[test]
+    fn search_generations_matches_literally() {
+        let store = in_memory_store("search_generations_matches_literally");
+        for (prompt, user_id) in [
+            ("a red cat", 1),
+            ("a blue cat", 1),
+            ("100% cat", 1),
+            ("a red dog", 3),
+        ] {
+            store
+                .insert_generation(Generation {
+                    prompt: prompt.to_string(),
+                    user_id: UserId(user_id),
+                    ..test_generation()
+                })
+                .unwrap();
+        }
+
+        let search = |predicate, term| {
+            let (results, count) = store
+                .search_generations(predicate, term, None, (10, 0))
+                .unwrap();
+            assert_eq!(results.len(), count);
+            results.into_iter().map(|r| r.prompt).collect::<Vec<_>>()
+        };
+        let user = GenerationPredicate::User(GuildId(2), UserId(1));
+        assert_eq!(search(user, "cat"), ["100% cat", "a blue cat", "a red cat"]);
+        assert_eq!(search(user, "red"), ["a red cat"]);
+        assert_eq!(search(user, "0%"), ["100% cat"]);
+        assert_eq!(
+            search(GenerationPredicate::Guild(GuildId(2)), "red"),
+            ["a red dog", "a red cat"]
+        );
+        assert!(search(GenerationPredicate::Guild(GuildId(3)), "red").is_empty());
+    }
+
+    #[test]
+    fn search_generations_filters_by_label() {
+        let store = in_memory_store("search_generations_filters_by_label");
+        let keys: Vec<_> = ["a red cat", "a blue cat", "a red dog"]
+            .into_iter()
+            .map(|prompt| {
+                store
+                    .insert_generation(Generation {
+                        prompt: prompt.to_string(),
+                        ..test_generation()
+                    })
+                    .unwrap()
+            })
+            .collect();
+        let labels = |labels: &[&str]| labels.iter().map(|l| l.to_string()).collect::<Vec<_>>();
+        store
+            .set_labels(keys[0], &labels(&["pets", "red"]))
+            .unwrap();
+        store.set_labels(keys[2], &labels(&["red"])).unwrap();
+        store.set_labels(keys[2], &labels(&["pets"])).unwrap();
+        assert_eq!(store.get_labels(keys[2]).unwrap(), ["pets"]);
+
+        let search = |term, label| {
+            let predicate = GenerationPredicate::Guild(GuildId(2));
+            let (results, count) = store
+                .search_generations(predicate, term, Some(label), (10, 0))
+                .unwrap();
+            assert_eq!(results.len(), count);
+            results.into_iter().map(|r| r.prompt).collect::<Vec<_>>()
+        };
+        assert_eq!(search("", "pets"), ["a red dog", "a red cat"]);
+        assert_eq!(search("cat", "pets"), ["a red cat"]);
+        assert_eq!(search("", "red"), ["a red cat"]);
+        assert!(search("", "blue").is_empty());
+    }
+
+    #[test]
+    fn recent_prompts_for_user_are_distinct_and_newest_first() {
+        let store = in_memory_store("recent_prompts_for_user_are_distinct_and_newest_first");
+        for (prompt, user_id) in [
+            ("a red cat", 1),
+            ("a_blue cat", 1),
+            ("a red cat", 1),
+            ("a red dog", 3),
+            ("the cat", 1),
+        ] {
+            store
+                .insert_generation(Generation {
+                    prompt: prompt.to_string(),
+                    user_id: UserId(user_id),
+                    ..test_generation()
+                })
+                .unwrap();
+        }
+
+        let recent = |prefix, limit| {
+            store
+                .recent_prompts_for_user(UserId(1), prefix, limit)
+                .unwrap()
+        };
+        assert_eq!(recent("", 10), ["the cat", "a red cat", "a_blue cat"]);
+        assert_eq!(recent("a", 10), ["a red cat", "a_blue cat"]);
+        assert_eq!(recent("a_", 10), ["a_blue cat"]);
+        assert_eq!(recent("", 1), ["the cat"]);
+    }
+
+    #[test]
+    fn usage_is_reserved_within_quotas() {
+        let store = in_memory_store("usage_is_reserved_within_quotas");
+        let now = chrono::Local::now();
+        let quotas = [("daily", now - chrono::Duration::days(1), 3)];
+
+        let first = store.reserve_usage(UserId(1), 2, &quotas).unwrap();
+        // the batch has to fit in what's left, not just start under the quota
+        assert!(store.reserve_usage(UserId(1), 2, &quotas).is_err());
+        store.reserve_usage(UserId(1), 1, &quotas).unwrap();
+        assert!(store.reserve_usage(UserId(1), 1, &quotas).is_err());
+        // other users have quotas of their own
+        store.reserve_usage(UserId(3), 3, &quotas).unwrap();
+
+        store.refund_usage(first).unwrap();
+        store.reserve_usage(UserId(1), 2, &quotas).unwrap();
+    }
+
+    #[test]
+    fn pruning_keeps_the_newest_generations() {
+        let store = in_memory_store("pruning_keeps_the_newest_generations");
+        let ids: Vec<_> = [1, 1, 3, 1]
+            .into_iter()
+            .map(|user_id| {
+                store
+                    .insert_generation(Generation {
+                        user_id: UserId(user_id),
+                        ..test_generation()
+                    })
+                    .unwrap()
+            })
+            .collect();
+
+        assert_eq!(store.prune_user_generations(UserId(1), 2).unwrap(), 1);
+        assert!(store.get_generation(ids[0]).unwrap().is_none());
+        for id in &ids[1..] {
+            assert!(store.get_generation(*id).unwrap().is_some());
+        }
+        assert_eq!(store.prune_user_generations(UserId(1), 2).unwrap(), 0);
+    }
+
+    #[test]
+    fn pruning_removes_old_uploads() {
+        let store = in_memory_store("pruning_removes_old_uploads");
+        let ids: Vec<_> = ["a", "b", "c"]
+            .into_iter()
+            .map(|name| {
+                store
+                    .insert_upload(
+                        UserId(1),
+                        GuildId(2),
+                        &format!("https://example.com/{name}.png"),
+                        b"image",
+                    )
+                    .unwrap()
+            })
+            .collect();
+        let other = store
+            .insert_upload(UserId(3), GuildId(2), "https://example.com/d.png", b"image")
+            .unwrap();
+
+        store.prune_user_generations(UserId(1), 2).unwrap();
+        assert_eq!(store.get_upload(ids[0], GuildId(2)).unwrap(), None);
+        for id in ids[1..].iter().chain([&other]) {
+            assert!(store.get_upload(*id, GuildId(2)).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn pruning_doesnt_give_back_quota() {
+        let store = in_memory_store("pruning_doesnt_give_back_quota");
+        let quotas = [("daily", chrono::Local::now() - chrono::Duration::days(1), 2)];
+        for _ in 0..2 {
+            store.reserve_usage(UserId(1), 1, &quotas).unwrap();
+            store.insert_generation(test_generation()).unwrap();
+        }
+
+        assert_eq!(store.prune_user_generations(UserId(1), 1).unwrap(), 1);
+        assert!(store.reserve_usage(UserId(1), 1, &quotas).is_err());
+    }
+
+    #[test]
+    fn model_usage_is_split_by_generation_kind() {
+        let store = in_memory_store("model_usage_is_split_by_generation_kind");
+        let image_generation = ImageGeneration {
+            init_image: image::DynamicImage::new_rgb8(4, 4),
+            init_url: "https://example.com/image.png".to_string(),
+            resize_mode: Default::default(),
+            mask: None,
+        };
+        for (model_hash, image_generation) in [
+            ("abcdef", None),
+            ("abcdef", Some(image_generation.clone())),
+            ("abcdef", None),
+            ("123456", Some(image_generation)),
+        ] {
+            store
+                .insert_generation(Generation {
+                    model_hash: model_hash.to_string(),
+                    image_generation,
+                    ..test_generation()
+                })
+                .unwrap();
+        }
+
+        let usage = store.get_model_usage_counts(GuildId(2)).unwrap();
+        assert_eq!(
+            usage[&UserId(1)],
+            [
+                ModelUsage {
+                    model_hash: "abcdef".to_string(),
+                    text_count: 2,
+                    image_count: 1,
+                },
+                ModelUsage {
+                    model_hash: "123456".to_string(),
+                    text_count: 0,
+                    image_count: 1,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn latest_thumbnail_is_per_user() {
+        let store = in_memory_store("latest_thumbnail_is_per_user");
+        let image = |size| {
+            util::encode_image_to_png_bytes(image::DynamicImage::new_rgb8(size, size)).unwrap()
+        };
+        let mut keys = vec![];
+        for (user_id, size) in [(1, 16), (3, 16), (1, 512)] {
+            keys.push(
+                store
+                    .insert_generation(Generation {
+                        user_id: UserId(user_id),
+                        image: image(size),
+                        ..test_generation()
+                    })
+                    .unwrap(),
+            );
+        }
+
+        let latest = store.get_latest_thumbnail_per_user(GuildId(2)).unwrap();
+        assert_eq!(latest.len(), 2);
+        assert_eq!(latest[&UserId(1)].0, keys[2]);
+        assert_eq!(latest[&UserId(3)].0, keys[1]);
+        let thumbnail = image::load_from_memory(&latest[&UserId(1)].1).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (256, 256));
+    }
+
+    #[test]
+    fn thumbnails_are_stored_for_generations() {
+        let store = in_memory_store("thumbnails_are_stored_for_generations");
+        let image =
+            util::encode_image_to_png_bytes(image::DynamicImage::new_rgb8(1024, 512)).unwrap();
+        store
+            .insert_generation(Generation {
+                image,
+                ..test_generation()
+            })
+            .unwrap();
+
+        let (results, _) = store
+            .search_generations(GenerationPredicate::Guild(GuildId(2)), "", None, (1, 0))
+            .unwrap();
+        let thumbnail = image::load_from_memory(results[0].thumbnail.as_ref().unwrap()).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (256, 128));
+    }
+
+    #[test]
+    fn a1111_parameters_match_web_ui_format() {
+        assert_eq!(
+            test_generation().as_a1111_parameters(),
+            "my cool prompt\n\
+             Steps: 20, Sampler: Euler a, CFG scale: 7, Seed: 1, Size: 512x512, Model hash: abcdef"
+        );
+
+        let generation = Generation {
+            negative_prompt: Some("blurry".to_string()),
+            cfg_scale: 7.5,
+            eta: Some(0.5),
+            tiling: true,
+            image_generation: Some(ImageGeneration {
+                init_image: image::DynamicImage::new_rgb8(1, 1),
+                init_url: "https://example.com/image.png".to_string(),
+                resize_mode: Default::default(),
+                mask: None,
+            }),
+            ..test_generation()
+        };
+        assert_eq!(
+            generation.as_a1111_parameters(),
+            "my cool prompt\n\
+             Negative prompt: blurry\n\
+             Steps: 20, Sampler: Euler a, CFG scale: 7.5, Seed: 1, Size: 512x512, \
+             Model hash: abcdef, Denoising strength: 0.75, Eta: 0.5, Tiling: True"
+        );
+    }
+
+    #[test]
+    fn generation_request_round_trips_through_store() {
+        let store = in_memory_store("generation_request_round_trips_through_store");
+        let prompt = "my cool prompt, <lora:foo:0.8>";
+        let key = store
+            .insert_generation(Generation {
+                prompt: prompt.to_string(),
+                image_generation: Some(ImageGeneration {
+                    init_image: image::DynamicImage::new_rgb8(2, 2),
+                    init_url: "https://example.com/image.png".to_string(),
+                    resize_mode: sd::ResizeMode::CropAndResize,
+                    mask: Some(image::DynamicImage::new_luma8(2, 2)),
+                }),
+                ..test_generation()
+            })
+            .unwrap();
+
+        let generation = store.get_generation(key).unwrap().unwrap();
+        let GenerationRequest::Image(request) = generation.as_generation_request(&[]) else {
+            panic!("expected an image generation request");
+        };
+        assert_eq!(request.base.prompt, prompt);
+        assert_eq!(request.base.seed, Some(1));
+        assert_eq!(request.resize_mode, Some(sd::ResizeMode::CropAndResize));
+        assert_eq!(request.images[0].width(), 2);
+        assert_eq!(request.mask.map(|m| m.width()), Some(2));
+    }
+
+    #[test]
+    fn uploads_keep_their_id_within_a_server() {
+        let store = in_memory_store("uploads_keep_their_id_within_a_server");
+        let first = store
+            .insert_upload(UserId(1), GuildId(2), "https://example.com/a.png", b"a")
+            .unwrap();
+        let second = store
+            .insert_upload(UserId(3), GuildId(2), "https://example.com/b.png", b"b")
+            .unwrap();
+        assert_ne!(first, second);
+        assert_eq!(
+            store
+                .insert_upload(UserId(3), GuildId(2), "https://example.com/a.png", b"a")
+                .unwrap(),
+            first
+        );
+        assert_eq!(
+            store.get_upload(second, GuildId(2)).unwrap(),
+            Some(("https://example.com/b.png".to_string(), b"b".to_vec()))
+        );
+        assert_eq!(store.get_upload(second + 1, GuildId(2)).unwrap(), None);
+        assert_eq!(
+            store
+                .find_upload(GuildId(2), "https://example.com/a.png")
+                .unwrap(),
+            Some(first)
+        );
+
+        // other servers can't see the image, and get their own ID for the same URL
+        assert_eq!(store.get_upload(first, GuildId(4)).unwrap(), None);
+        assert_eq!(
+            store
+                .find_upload(GuildId(4), "https://example.com/a.png")
+                .unwrap(),
+            None
+        );
+        let other = store
+            .insert_upload(UserId(1), GuildId(4), "https://example.com/a.png", b"a")
+            .unwrap();
+        assert_ne!(other, first);
+    }
+
+    #[test]
+    fn audit_log_is_newest_first() {
+        let store = in_memory_store("audit_log_is_newest_first");
+        store.insert_audit_log(UserId(1), "reloadtags", "").unwrap();
+        store
+            .insert_audit_log(UserId(2), "config set", "limits.count_max = 8")
+            .unwrap();
+
+        let entries = store.recent_audit_log(10).unwrap();
+        assert_eq!(
+            entries
+                .iter()
+                .map(|e| (e.user_id, e.action.as_str(), e.details.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                (UserId(2), "config set", "limits.count_max = 8"),
+                (UserId(1), "reloadtags", ""),
+            ]
+        );
+        assert_eq!(store.recent_audit_log(1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn user_settings_default_until_set() {
+        let store = in_memory_store("user_settings_default_until_set");
+        assert_eq!(
+            store.get_user_settings(UserId(1)).unwrap(),
+            UserSettings::default()
+        );
+
+        let settings = UserSettings { dm_results: true };
+        store.set_user_settings(UserId(1), &settings).unwrap();
+        assert_eq!(store.get_user_settings(UserId(1)).unwrap(), settings);
+        store
+            .set_user_settings(UserId(1), &UserSettings::default())
+            .unwrap();
+        assert_eq!(
+            store.get_user_settings(UserId(1)).unwrap(),
+            UserSettings::default()
+        );
+        assert_eq!(
+            store.get_user_settings(UserId(2)).unwrap(),
+            UserSettings::default()
+        );
+    }
+}
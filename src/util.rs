@@ -1,9 +1,14 @@
+use anyhow::Context;
 use futures::Future;
+use itertools::Itertools;
+use once_cell::sync::Lazy;
 use serenity::{
     async_trait,
+    builder::CreateApplicationCommand,
     http::Http,
     model::{
         prelude::{
+            component::InputTextStyle,
             interaction::{
                 application_command::{
                     ApplicationCommandInteraction, CommandDataOption, CommandDataOptionValue,
@@ -12,13 +17,24 @@ use serenity::{
                 modal::ModalSubmitInteraction,
                 InteractionResponseType,
             },
-            ChannelId, GuildId, Message, PartialChannel,
+            ChannelId, GuildId, InteractionId, Member, Message, PartialChannel, UserId,
         },
         user::User,
     },
 };
 
-use crate::{config::Configuration, constant, sd};
+use crate::{
+    config::{Configuration, Localizations, Watermark, WatermarkPosition},
+    constant, sd,
+};
+use parking_lot::Mutex;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
 pub fn get_value<'a>(
     options: &'a [CommandDataOption],
@@ -88,6 +104,12 @@ pub fn get_image_url(options: &[CommandDataOption]) -> Option<String> {
         .or_else(|| get_value(options, constant::value::IMAGE_URL).and_then(value_to_string))
 }
 
+/// Parses an image ID (`12` or `#12`) given in place of an image URL
+pub fn parse_image_id(value: &str) -> Option<i64> {
+    let value = value.trim();
+    value.strip_prefix('#').unwrap_or(value).parse().ok()
+}
+
 pub fn generate_chunked_strings<'a>(
     strings: impl Iterator<Item = &'a str>,
     threshold: usize,
@@ -151,8 +173,808 @@ pub fn encode_image_to_png_bytes(image: image::DynamicImage) -> anyhow::Result<V
     Ok(bytes)
 }
 
-pub fn fixup_base_generation_request(params: &mut sd::BaseGenerationRequest) {
-    if let Some(model) = params.model.as_ref() {
+/// Stamps the watermark onto the image, scaling text watermarks with the image so that
+/// they stay legible without dominating it
+pub fn apply_watermark(
+    image: image::DynamicImage,
+    watermark: &Watermark,
+) -> anyhow::Result<image::DynamicImage> {
+    use imageproc::{
+        drawing::{draw_filled_rect_mut, draw_text_mut, text_size},
+        rect::Rect,
+    };
+    use rusttype::{Font, Scale};
+
+    const MARGIN: u32 = 8;
+
+    let mut image = image.to_rgba8();
+    let (width, height) = image.dimensions();
+    let [r, g, b] = watermark.color.unwrap_or([255, 255, 255]);
+    let color = image::Rgba([r, g, b, 255]);
+
+    let border = watermark.border_width.min(width / 2).min(height / 2);
+    if border > 0 {
+        for (x, y, w, h) in [
+            (0, 0, width, border),
+            (0, height - border, width, border),
+            (0, 0, border, height),
+            (width - border, 0, border, height),
+        ] {
+            draw_filled_rect_mut(
+                &mut image,
+                Rect::at(x as i32, y as i32).of_size(w, h),
+                color,
+            );
+        }
+    }
+
+    let inset = MARGIN + border;
+    let place = |mark_width: u32, mark_height: u32| -> (i64, i64) {
+        let left = inset as i64;
+        let right = width as i64 - mark_width as i64 - inset as i64;
+        let top = inset as i64;
+        let bottom = height as i64 - mark_height as i64 - inset as i64;
+        match watermark.position {
+            WatermarkPosition::TopLeft => (left, top),
+            WatermarkPosition::TopRight => (right, top),
+            WatermarkPosition::BottomLeft => (left, bottom),
+            WatermarkPosition::BottomRight => (right, bottom),
+        }
+    };
+
+    if let Some(path) = &watermark.image {
+        let mark = image::open(path)
+            .with_context(|| format!("failed to read the watermark `{}`", path.display()))?
+            .to_rgba8();
+        let (x, y) = place(mark.width(), mark.height());
+        image::imageops::overlay(&mut image, &mark, x, y);
+    } else if let Some(text) = &watermark.text {
+        let font = Font::try_from_vec(std::fs::read(constant::resource::font_path())?)
+            .context("failed to load font")?;
+        let scale = Scale::uniform((height as f32 / 32.0).max(12.0));
+        let (text_width, text_height) = text_size(scale, &font, text);
+        let (x, y) = place(text_width.max(0) as u32, text_height.max(0) as u32);
+        // the shadow keeps the text legible on both light and dark images
+        let shadow = image::Rgba([0, 0, 0, 255]);
+        draw_text_mut(
+            &mut image,
+            shadow,
+            x as i32 + 1,
+            y as i32 + 1,
+            scale,
+            &font,
+            text,
+        );
+        draw_text_mut(&mut image, color, x as i32, y as i32, scale, &font, text);
+    }
+
+    Ok(image::DynamicImage::ImageRgba8(image))
+}
+
+/// Joins the prefix and suffix onto the prompt with commas, skipping them if the prompt's
+/// comma-separated tags already start or end with theirs (e.g. when a past prompt is reused)
+pub fn wrap_prompt(prompt: &str, prefix: Option<&str>, suffix: Option<&str>) -> String {
+    fn tags(text: &str) -> Vec<&str> {
+        text.split(',')
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+            .collect()
+    }
+
+    let prompt = prompt.trim();
+    let prompt_tags = tags(prompt);
+    let prefix = prefix.map(str::trim).filter(|p| {
+        let p = tags(p);
+        !p.is_empty() && !prompt_tags.starts_with(&p)
+    });
+    let suffix = suffix.map(str::trim).filter(|s| {
+        let s = tags(s);
+        !s.is_empty() && !prompt_tags.ends_with(&s)
+    });
+
+    [prefix, Some(prompt).filter(|p| !p.is_empty()), suffix]
+        .into_iter()
+        .flatten()
+        .join(", ")
+}
+
+/// Parses comma-separated labels, normalising them to lowercase and dropping duplicates
+pub fn parse_labels(value: &str) -> anyhow::Result<Vec<String>> {
+    const MAX_LABELS: usize = 10;
+    const MAX_LABEL_LENGTH: usize = 32;
+
+    let labels: Vec<String> = value
+        .split(',')
+        .map(|l| l.trim().to_lowercase())
+        .filter(|l| !l.is_empty())
+        .unique()
+        .collect();
+    if labels.len() > MAX_LABELS {
+        anyhow::bail!("A generation can have at most {MAX_LABELS} labels.");
+    }
+    if let Some(label) = labels.iter().find(|l| l.chars().count() > MAX_LABEL_LENGTH) {
+        anyhow::bail!("The label `{label}` is longer than {MAX_LABEL_LENGTH} characters.");
+    }
+    Ok(labels)
+}
+
+/// Shortens the text to at most `max_chars` characters, marking it with an ellipsis if cut
+pub fn truncate(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+    let mut truncated: String = text.chars().take(max_chars.saturating_sub(1)).collect();
+    truncated.push('…');
+    truncated
+}
+
+/// Returns the server the interaction happened in, with a user-facing error for DMs, which
+/// have no server to store generations and look up history in
+pub fn require_guild(guild_id: Option<GuildId>) -> anyhow::Result<GuildId> {
+    guild_id.context("This can only be used in a server, not in DMs.")
+}
+
+/// Runs CPU-heavy image work (decoding, encoding, compositing) on the blocking thread pool,
+/// so that large images don't stall the runtime's worker threads
+pub async fn run_image_task<T: Send + 'static>(
+    task: impl FnOnce() -> anyhow::Result<T> + Send + 'static,
+) -> anyhow::Result<T> {
+    tokio::task::spawn_blocking(task).await?
+}
+
+/// Decodes the image on the blocking thread pool
+pub async fn decode_image(bytes: Vec<u8>) -> anyhow::Result<image::DynamicImage> {
+    run_image_task(move || Ok(image::load_from_memory(&bytes)?)).await
+}
+
+/// Downloads the image at `url`, with a friendly error if it isn't an image we can read
+pub async fn download_image_bytes(url: &str) -> anyhow::Result<Vec<u8>> {
+    let response = reqwest::get(url).await?.error_for_status()?;
+
+    // some hosts don't report a useful content type, so only reject clear mismatches
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string());
+    if let Some(content_type) = content_type
+        .as_deref()
+        .filter(|ct| !ct.starts_with("image/") && !ct.starts_with("application/octet-stream"))
+    {
+        anyhow::bail!("<{url}> is not an image (it's `{content_type}`); please use a PNG, JPEG or WebP image.");
+    }
+
+    let bytes = response.bytes().await?.to_vec();
+    if image::guess_format(&bytes).is_err() {
+        anyhow::bail!(
+            "<{url}> is not in a supported image format; please use a PNG, JPEG or WebP image."
+        );
+    }
+    Ok(bytes)
+}
+
+/// Resolves an image URL or image ID given to a command into the image's ID, URL and contents.
+/// IDs only refer to images used in the same server. Their contents are read from the store,
+/// so that they still work after the URL expires. New URLs are downloaded and recorded.
+pub async fn resolve_upload(
+    store: &crate::store::Store,
+    user_id: UserId,
+    guild_id: GuildId,
+    url_or_id: &str,
+) -> anyhow::Result<(i64, String, image::DynamicImage)> {
+    let (id, url, bytes) = match parse_image_id(url_or_id) {
+        Some(id) => {
+            let (url, bytes) = store
+                .get_upload(id, guild_id)?
+                .with_context(|| format!("There is no image with the ID {id}."))?;
+            (id, url, bytes)
+        }
+        None => {
+            let bytes = download_image_bytes(url_or_id).await?;
+            // the image blobs can be large, so let the runtime move other tasks off this thread
+            let id = tokio::task::block_in_place(|| {
+                store.insert_upload(user_id, guild_id, url_or_id, &bytes)
+            })?;
+            (id, url_or_id.to_string(), bytes)
+        }
+    };
+
+    let image = decode_image(bytes)
+        .await
+        .with_context(|| format!("failed to read the image at <{url}>"))?;
+    Ok((id, url, image))
+}
+
+/// Downloads the text file at `url`, giving up once it exceeds `max_bytes` rather than
+/// reading the whole body into memory first.
+pub async fn download_text(url: &str, max_bytes: usize) -> anyhow::Result<String> {
+    let mut response = reqwest::get(url).await?.error_for_status()?;
+
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string());
+    if let Some(content_type) = content_type
+        .as_deref()
+        .filter(|ct| !ct.starts_with("text/") && !ct.starts_with("application/octet-stream"))
+    {
+        anyhow::bail!("<{url}> is not a text file (it's `{content_type}`).");
+    }
+
+    let too_large = || anyhow::anyhow!("<{url}> is larger than the limit of {max_bytes} bytes.");
+    if matches!(response.content_length(), Some(length) if length > max_bytes as u64) {
+        return Err(too_large());
+    }
+
+    let mut bytes = vec![];
+    while let Some(chunk) = response.chunk().await? {
+        if bytes.len() + chunk.len() > max_bytes {
+            return Err(too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    String::from_utf8(bytes).with_context(|| format!("<{url}> is not valid UTF-8 text"))
+}
+
+/// Downloads and decodes the image at `url`. The image is kept in its original colour
+/// type, so that the transparency of RGBA images is preserved through to the output.
+pub async fn download_image(url: &str) -> anyhow::Result<image::DynamicImage> {
+    let bytes = download_image_bytes(url).await?;
+    decode_image(bytes)
+        .await
+        .with_context(|| format!("failed to read the image at <{url}>"))
+}
+
+/// Runs the generation once one of the backend's generation slots is free. All generations,
+/// including Wirehead's, go through this, so that no single source can starve the others.
+pub async fn with_generation_permit<T>(generation: impl Future<Output = T>) -> T {
+    static GENERATION_SEMAPHORE: Lazy<tokio::sync::Semaphore> = Lazy::new(|| {
+        tokio::sync::Semaphore::new(
+            Configuration::get()
+                .general
+                .max_concurrent_generations
+                .max(1),
+        )
+    });
+
+    // decrements the counter when dropped, so that cancelled generations are accounted for
+    struct CountGuard(&'static AtomicUsize);
+    impl CountGuard {
+        fn new(counter: &'static AtomicUsize) -> Self {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Self(counter)
+        }
+    }
+    impl Drop for CountGuard {
+        fn drop(&mut self) {
+            self.0.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    let waiting = CountGuard::new(&GENERATIONS_WAITING);
+    let _permit = GENERATION_SEMAPHORE
+        .acquire()
+        .await
+        .expect("the generation semaphore is never closed");
+    std::mem::drop(waiting);
+
+    let _active = CountGuard::new(&GENERATIONS_ACTIVE);
+    generation.await
+}
+
+/// How a generation ended, if it didn't fail
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GenerationOutcome {
+    Completed,
+    Cancelled,
+}
+
+/// A generation spawned with [spawn_generation]
+pub struct GenerationTask<T> {
+    pub handle: tokio::task::JoinHandle<T>,
+    started: Arc<AtomicBool>,
+}
+impl<T> GenerationTask<T> {
+    /// Whether the generation has been given a slot, as opposed to waiting for one
+    pub fn has_started(&self) -> bool {
+        self.started.load(Ordering::SeqCst)
+    }
+}
+
+/// Spawns the generation through [with_generation_permit], keeping track of whether it's
+/// been given a slot yet
+pub fn spawn_generation<T: Send + 'static>(
+    generation: impl Future<Output = T> + Send + 'static,
+) -> GenerationTask<T> {
+    let started = Arc::new(AtomicBool::new(false));
+    let handle = tokio::task::spawn(with_generation_permit({
+        let started = started.clone();
+        async move {
+            started.store(true, Ordering::SeqCst);
+            generation.await
+        }
+    }));
+    GenerationTask { handle, started }
+}
+
+/// The web UI uses a text-to-image request's denoising strength for hires fix's upscaling pass,
+/// so the hires strength is only swapped in when sending the request. This keeps it separate from
+/// the image-to-image denoising strength that is carried between generations.
+pub fn with_hires_denoising_strength(
+    request: &sd::TextToImageGenerationRequest,
+    hires_denoising_strength: Option<f32>,
+) -> sd::TextToImageGenerationRequest {
+    let mut request = request.clone();
+    if let Some(strength) = hires_denoising_strength.filter(|_| request.enable_hr == Some(true)) {
+        request.base.denoising_strength = Some(strength);
+    }
+    request
+}
+
+/// Runs the Discord request, retrying it with backoff if Discord rate-limited it.
+///
+/// Serenity already waits out the rate limits it knows about, but bursts of edits can still
+/// be refused; it doesn't surface `Retry-After` for these, so a fixed backoff is used instead.
+pub async fn retry_if_rate_limited<T, Fut>(mut request: impl FnMut() -> Fut) -> anyhow::Result<T>
+where
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    const MAX_ATTEMPTS: u32 = 4;
+    const BASE_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+
+    let mut attempt = 1;
+    loop {
+        match request().await {
+            Err(err) if attempt < MAX_ATTEMPTS && is_rate_limited(&err) => {
+                let delay = BASE_DELAY * 2u32.pow(attempt - 1);
+                tracing::warn!("rate limited by Discord, retrying in {delay:?}");
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Whether the error is Discord refusing a request due to rate limiting
+pub fn is_rate_limited(err: &anyhow::Error) -> bool {
+    match err.downcast_ref::<serenity::Error>() {
+        Some(serenity::Error::Http(err)) => matches!(
+            err.as_ref(),
+            serenity::http::HttpError::UnsuccessfulRequest(response)
+                if response.status_code == reqwest::StatusCode::TOO_MANY_REQUESTS
+        ),
+        _ => false,
+    }
+}
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Marks the process as shutting down, so that no new work is accepted
+pub fn begin_shutdown() {
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+}
+
+/// Whether the process is shutting down
+pub fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::SeqCst)
+}
+
+/// Fails if the process is shutting down, so that new generations aren't started while
+/// the existing ones are being drained
+pub fn require_not_shutting_down() -> anyhow::Result<()> {
+    if is_shutting_down() {
+        anyhow::bail!("The bot is shutting down; please try again later.");
+    }
+    Ok(())
+}
+
+static GENERATIONS_ACTIVE: AtomicUsize = AtomicUsize::new(0);
+static GENERATIONS_WAITING: AtomicUsize = AtomicUsize::new(0);
+
+/// The number of generations that are currently running and waiting to run, respectively
+pub fn generation_queue() -> (usize, usize) {
+    (
+        GENERATIONS_ACTIVE.load(Ordering::SeqCst),
+        GENERATIONS_WAITING.load(Ordering::SeqCst),
+    )
+}
+
+/// The generations that can currently be cancelled, by the ID of the interaction that requested
+/// them, along with the user who requested them
+type CancellationFlags = HashMap<InteractionId, (UserId, Arc<AtomicBool>)>;
+static CANCELLATIONS: Lazy<Mutex<CancellationFlags>> = Lazy::new(Default::default);
+
+/// Registered for the lifetime of a generation, so that it can be cancelled with
+/// [request_cancellation] while it's waiting to start or running
+pub struct Cancellation {
+    id: InteractionId,
+    cancelled: Arc<AtomicBool>,
+}
+impl Cancellation {
+    pub fn register(id: InteractionId, user_id: UserId) -> Self {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        CANCELLATIONS
+            .lock()
+            .insert(id, (user_id, cancelled.clone()));
+        Self { id, cancelled }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+impl Drop for Cancellation {
+    fn drop(&mut self) {
+        CANCELLATIONS.lock().remove(&self.id);
+    }
+}
+
+/// Asks the generation requested by the interaction to stop; only its requester can do so
+pub fn request_cancellation(id: InteractionId, user_id: UserId) -> anyhow::Result<()> {
+    let cancellations = CANCELLATIONS.lock();
+    let (requester, cancelled) = cancellations
+        .get(&id)
+        .context("This generation has already finished.")?;
+    if *requester != user_id {
+        anyhow::bail!("Only the person who requested this generation can cancel it.");
+    }
+    cancelled.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// The mean absolute difference between the images' channels, from 0 (identical) to 255
+pub fn mean_pixel_difference(
+    a: &image::DynamicImage,
+    b: &image::DynamicImage,
+) -> anyhow::Result<f64> {
+    let size = |image: &image::DynamicImage| (image.width(), image.height());
+    if size(a) != size(b) {
+        anyhow::bail!(
+            "the images have different sizes ({:?} and {:?})",
+            size(a),
+            size(b)
+        );
+    }
+
+    let (a, b) = (a.to_rgba8(), b.to_rgba8());
+    let total: u64 = a
+        .as_raw()
+        .iter()
+        .zip(b.as_raw())
+        .map(|(a, b)| a.abs_diff(*b) as u64)
+        .sum();
+    Ok(total as f64 / a.as_raw().len().max(1) as f64)
+}
+
+/// Shrinks the image to fit within a small square, for listings of many generations
+pub fn make_thumbnail(image: &image::DynamicImage) -> anyhow::Result<Vec<u8>> {
+    const THUMBNAIL_SIZE: u32 = 256;
+    encode_image_to_png_bytes(image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE))
+}
+
+/// Encodes the frames as a looping animated GIF, with each frame shown for `frame_ms`
+pub fn encode_frames_to_gif(
+    frames: Vec<image::DynamicImage>,
+    frame_ms: u64,
+) -> anyhow::Result<Vec<u8>> {
+    use image::codecs::gif::{GifEncoder, Repeat};
+
+    // trades some colour accuracy for encoding speed, as these are only previews
+    const ENCODING_SPEED: i32 = 10;
+
+    let mut bytes = vec![];
+    {
+        let mut encoder = GifEncoder::new_with_speed(&mut bytes, ENCODING_SPEED);
+        encoder.set_repeat(Repeat::Infinite)?;
+        encoder.encode_frames(frames.into_iter().map(|frame| {
+            image::Frame::from_parts(
+                frame.to_rgba8(),
+                0,
+                0,
+                image::Delay::from_numer_denom_ms(frame_ms as u32, 1),
+            )
+        }))?;
+    }
+    Ok(bytes)
+}
+
+/// Embeds the generation parameters into the PNG under the `parameters` key, as the web UI does.
+/// If the PNG can't be re-encoded, the original bytes are returned.
+pub fn embed_png_parameters(bytes: &[u8], parameters: &str) -> Vec<u8> {
+    fn embed(bytes: &[u8], parameters: &str) -> anyhow::Result<Vec<u8>> {
+        let mut reader = png::Decoder::new(bytes).read_info()?;
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let frame = reader.next_frame(&mut buf)?;
+        let info = reader.info();
+
+        let mut output = vec![];
+        {
+            let mut encoder = png::Encoder::new(&mut output, frame.width, frame.height);
+            encoder.set_color(frame.color_type);
+            encoder.set_depth(frame.bit_depth);
+            if let Some(palette) = &info.palette {
+                encoder.set_palette(palette.clone());
+            }
+            if let Some(trns) = &info.trns {
+                encoder.set_trns(trns.clone());
+            }
+            // tEXt is Latin-1 only, so fall back to iTXt for anything else
+            if parameters.is_ascii() {
+                encoder.add_text_chunk("parameters".to_string(), parameters.to_string())?;
+            } else {
+                encoder.add_itxt_chunk("parameters".to_string(), parameters.to_string())?;
+            }
+            let mut writer = encoder.write_header()?;
+            writer.write_image_data(&buf[..frame.buffer_size()])?;
+        }
+        Ok(output)
+    }
+
+    embed(bytes, parameters).unwrap_or_else(|err| {
+        tracing::warn!("failed to embed parameters into PNG: {err}");
+        bytes.to_vec()
+    })
+}
+
+/// The style, maximum length and initial value of a modal's prompt input for the given
+/// prompt. Long prompts are edited in a multi-line input, and the maximum length fits the
+/// existing prompt, unless it's longer than Discord allows in a modal; it's then cut to fit,
+/// which [modal_prompt_label] points out.
+pub fn modal_prompt_input(prompt: &str) -> (InputTextStyle, u64, String) {
+    let prompt: String = prompt
+        .chars()
+        .take(constant::value::MODAL_INPUT_LENGTH_MAX)
+        .collect();
+    let length = prompt.chars().count();
+    let style = if length > constant::value::MODAL_SHORT_INPUT_LENGTH {
+        InputTextStyle::Paragraph
+    } else {
+        InputTextStyle::Short
+    };
+    let max_length = Configuration::get()
+        .limits
+        .prompt_length_max
+        .max(length)
+        .min(constant::value::MODAL_INPUT_LENGTH_MAX);
+    (style, max_length as u64, prompt)
+}
+
+/// The label for a modal's prompt input, noting if [modal_prompt_input] had to cut the prompt
+pub fn modal_prompt_label(label: &str, prompt: &str) -> String {
+    if prompt.chars().count() > constant::value::MODAL_INPUT_LENGTH_MAX {
+        format!("{label} (shortened to fit)")
+    } else {
+        label.to_string()
+    }
+}
+
+/// Lays out the images in a grid with the given number of columns, with each image's label
+/// drawn above it. Each column is as wide as its widest image, and each row as tall as its
+/// tallest image.
+pub fn make_contact_sheet(
+    images: &[(image::DynamicImage, String)],
+    columns: usize,
+) -> anyhow::Result<image::DynamicImage> {
+    use imageproc::drawing::{draw_text_mut, text_size};
+    use rusttype::{Font, Scale};
+
+    const LABEL_HEIGHT: u32 = 32;
+    const PADDING: u32 = 8;
+
+    let font = Font::try_from_vec(std::fs::read(constant::resource::font_path())?)
+        .context("failed to load font")?;
+    let scale = Scale::uniform(LABEL_HEIGHT as f32 * 0.75);
+
+    let columns = columns.max(1);
+    let mut column_widths = vec![0; columns.min(images.len())];
+    let mut row_heights = vec![0; images.chunks(columns).len()];
+    for (idx, (image, _)) in images.iter().enumerate() {
+        let (column, row) = (idx % columns, idx / columns);
+        column_widths[column] = column_widths[column].max(image.width());
+        row_heights[row] = row_heights[row].max(image.height() + LABEL_HEIGHT);
+    }
+    // the offset of each column and row, with the total size as the last element
+    let offsets = |sizes: &[u32], padding: u32| -> Vec<u32> {
+        std::iter::once(0)
+            .chain(sizes.iter().scan(0, |offset, size| {
+                *offset += size + padding;
+                Some(*offset)
+            }))
+            .collect()
+    };
+    let xs = offsets(&column_widths, PADDING);
+    let ys = offsets(&row_heights, PADDING);
+    let width = xs
+        .last()
+        .copied()
+        .unwrap_or_default()
+        .saturating_sub(PADDING);
+    let height = ys
+        .last()
+        .copied()
+        .unwrap_or_default()
+        .saturating_sub(PADDING);
+
+    let mut sheet = image::RgbaImage::from_pixel(width, height, image::Rgba([0, 0, 0, 255]));
+    for (idx, (image, label)) in images.iter().enumerate() {
+        let (x, y) = (xs[idx % columns], ys[idx / columns]);
+        // shorten the label until it fits above its image
+        let max_width = image.width().saturating_sub(PADDING) as i32;
+        let mut label = label.clone();
+        if text_size(scale, &font, &label).0 > max_width {
+            while !label.is_empty() && text_size(scale, &font, &format!("{label}…")).0 > max_width
+            {
+                label.pop();
+            }
+            label.push('…');
+        }
+
+        draw_text_mut(
+            &mut sheet,
+            image::Rgba([255, 255, 255, 255]),
+            (x + PADDING / 2) as i32,
+            (y + PADDING / 2) as i32,
+            scale,
+            &font,
+            &label,
+        );
+        image::imageops::overlay(
+            &mut sheet,
+            &image.to_rgba8(),
+            x as i64,
+            (y + LABEL_HEIGHT) as i64,
+        );
+    }
+
+    Ok(image::DynamicImage::ImageRgba8(sheet))
+}
+
+/// Returns a warning if the resolution is far from the one the model was trained at,
+/// as most models degrade noticeably when used that way
+pub fn resolution_warning(model: &sd::Model, width: u32, height: u32) -> Option<String> {
+    // how far the area can stray from the native area before warning
+    const MAX_AREA_RATIO: f64 = 2.0;
+
+    let native = model
+        .hash_short
+        .as_ref()
+        .and_then(|hash| {
+            Configuration::get()
+                .general
+                .model_native_resolutions
+                .get(hash)
+                .copied()
+        })
+        .unwrap_or_else(|| guess_native_resolution(&model.name));
+
+    let ratio = (width as f64 * height as f64) / (native as f64 * native as f64);
+    (!(1.0 / MAX_AREA_RATIO..=MAX_AREA_RATIO).contains(&ratio)).then(|| {
+        format!(
+            "Note: {width}x{height} is far from the {native}x{native} that *{}* was trained at, so the results may suffer.",
+            model.name
+        )
+    })
+}
+
+/// The resolution with the aspect ratio `ratio` (width / height) whose area is closest to
+/// `megapixels`, with each side rounded to a multiple of `round`. Megapixels are counted
+/// the way SDXL users do, so that 1.0 is 1024x1024.
+pub fn megapixel_resolution(megapixels: f64, ratio: f64, round: u32) -> (u32, u32) {
+    const PIXELS_PER_MEGAPIXEL: f64 = 1024.0 * 1024.0;
+
+    let area = megapixels * PIXELS_PER_MEGAPIXEL;
+    let snap = |side: f64| {
+        ((side / round as f64).round() as u32)
+            .max(1)
+            .saturating_mul(round)
+    };
+    (snap((area * ratio).sqrt()), snap((area / ratio).sqrt()))
+}
+
+fn guess_native_resolution(model_name: &str) -> u32 {
+    if model_name.to_lowercase().contains("xl") {
+        1024
+    } else {
+        512
+    }
+}
+
+/// The directions an image can be extended in when outpainting
+pub const OUTPAINT_DIRECTIONS: &[&str] = &["left", "right", "up", "down", "all"];
+
+/// Places the image on a larger canvas for outpainting, anchored so that the new region
+/// is on the `direction` side(s) of the image.
+///
+/// Returns the padded image and a mask that is white over the new region.
+pub fn pad_for_outpainting(
+    image: &image::DynamicImage,
+    (width, height): (u32, u32),
+    direction: &str,
+) -> anyhow::Result<(image::DynamicImage, image::DynamicImage)> {
+    let extra_width = width.saturating_sub(image.width());
+    let extra_height = height.saturating_sub(image.height());
+    if extra_width == 0 && extra_height == 0 {
+        anyhow::bail!(
+            "Outpainting requires a width or height larger than the image ({}x{}).",
+            image.width(),
+            image.height()
+        );
+    }
+
+    let (x, y) = match direction {
+        "left" => (extra_width, extra_height / 2),
+        "right" => (0, extra_height / 2),
+        "up" => (extra_width / 2, extra_height),
+        "down" => (extra_width / 2, 0),
+        "all" => (extra_width / 2, extra_height / 2),
+        _ => anyhow::bail!("unknown outpaint direction `{direction}`"),
+    };
+
+    // the image may be larger than the canvas along one axis, so crop it to fit
+    let image = image.crop_imm(0, 0, image.width().min(width), image.height().min(height));
+
+    let mut canvas = image::RgbaImage::from_pixel(width, height, image::Rgba([0, 0, 0, 255]));
+    image::imageops::overlay(&mut canvas, &image.to_rgba8(), x as i64, y as i64);
+
+    let mut mask = image::GrayImage::from_pixel(width, height, image::Luma([255]));
+    image::imageops::overlay(
+        &mut mask,
+        &image::GrayImage::new(image.width(), image.height()),
+        x as i64,
+        y as i64,
+    );
+
+    Ok((
+        image::DynamicImage::ImageRgba8(canvas),
+        image::DynamicImage::ImageLuma8(mask),
+    ))
+}
+
+/// Adds the configured translations to the command and all of its options, matched by the
+/// names they're registered with. This works on the built command (rather than on each
+/// option as it's created) so that options added through shared helpers are covered too.
+pub fn localize_command(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+    fn localize_options(options: &mut [serde_json::Value], localizations: &Localizations) {
+        for option in options.iter_mut().filter_map(|o| o.as_object_mut()) {
+            let name = option
+                .get("name")
+                .and_then(|n| n.as_str())
+                .unwrap_or_default()
+                .to_string();
+            for (key, value) in localizations.fields_for(&name) {
+                option.insert(key.to_string(), value);
+            }
+            if let Some(options) = option.get_mut("options").and_then(|o| o.as_array_mut()) {
+                localize_options(options, localizations);
+            }
+        }
+    }
+
+    let localizations = &Configuration::get().localization;
+    let name = command
+        .0
+        .get("name")
+        .and_then(|n| n.as_str())
+        .unwrap_or_default()
+        .to_string();
+    for (key, value) in localizations.fields_for(&name) {
+        command.0.insert(key, value);
+    }
+    if let Some(options) = command.0.get_mut("options").and_then(|o| o.as_array_mut()) {
+        localize_options(options, localizations);
+    }
+    command
+}
+
+/// Applies the adjustments every request needs before it's sent. `prepend_keyword` can be
+/// used to skip prepending the model's keyword for a single request.
+pub fn fixup_base_generation_request(
+    params: &mut sd::BaseGenerationRequest,
+    prepend_keyword: bool,
+) {
+    if let Some(model) = params.model.as_ref().filter(|_| prepend_keyword) {
         params.prompt = prepend_keyword_if_necessary(&params.prompt, &model.name);
     }
 
@@ -173,6 +995,29 @@ fn extract_keywords(model_name: &str) -> Vec<&str> {
         .unwrap_or_default()
 }
 
+/// Whether the prompt already includes the model's keyword. A generation's stored prompt
+/// without it means that prepending it was opted out of.
+pub fn has_model_keyword(prompt: &str, model_name: &str) -> bool {
+    let keywords = extract_keywords(model_name);
+    let [keyword] = keywords.as_slice() else {
+        return false;
+    };
+    prompt_has_tag(prompt, keyword)
+}
+
+/// Whether one of the prompt's comma-separated tags is `tag`, ignoring any brackets and
+/// weight around it (e.g. `(tag:1.2)`), so that `tagged` doesn't count as `tag`
+fn prompt_has_tag(prompt: &str, tag: &str) -> bool {
+    prompt.split(',').any(|s| {
+        let s = s.trim().trim_matches(['(', ')', '[', ']', '{', '}']);
+        let s = match s.rsplit_once(':') {
+            Some((name, weight)) if weight.trim().parse::<f64>().is_ok() => name,
+            _ => s,
+        };
+        s.trim() == tag
+    })
+}
+
 fn prepend_keyword_if_necessary(prompt: &str, model_name: &str) -> String {
     if !Configuration::get().general.automatically_prepend_keyword {
         return prompt.to_string();
@@ -183,15 +1028,362 @@ fn prepend_keyword_if_necessary(prompt: &str, model_name: &str) -> String {
 
 fn prepend_keyword_if_necessary_unchecked(prompt: &str, model_name: &str) -> String {
     let keywords = extract_keywords(model_name);
-    if let [keyword] = keywords.as_slice() {
-        if prompt.contains(keyword) {
-            prompt.to_string()
-        } else {
-            format!("{keyword}, {prompt}")
+    let [keyword] = keywords.as_slice() else {
+        return prompt.to_string();
+    };
+
+    // the rest of the prompt is left exactly as it was written
+    if prompt_has_tag(prompt, keyword) {
+        prompt.to_string()
+    } else if prompt.trim().is_empty() {
+        keyword.to_string()
+    } else {
+        format!("{keyword}, {prompt}")
+    }
+}
+
+/// Removes repeated tags and tags outside of the allowlist (if given) from a DeepDanbooru
+/// caption, keeping its order. The backend lists the tags from most to least confident,
+/// so capping them at `max_tags` keeps the most confident ones.
+pub fn filter_deepdanbooru_tags(
+    caption: &str,
+    allowlist: Option<&crate::config::Tags>,
+    max_tags: Option<usize>,
+) -> String {
+    let mut seen = HashSet::new();
+    caption
+        .split(", ")
+        .filter(|tag| match allowlist {
+            Some(allowlist) => allowlist.contains(*tag),
+            None => true,
+        })
+        .filter(|tag| seen.insert(*tag))
+        .take(max_tags.unwrap_or(usize::MAX))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Fails if the prompt contains any of the configured banned terms, logging the violation
+pub fn check_banned_terms(user_id: UserId, prompt: &str) -> anyhow::Result<()> {
+    if let Some(term) = find_banned_term(prompt, &Configuration::get().general.banned_terms) {
+        tracing::warn!(user = %user_id, term, "prompt contained a banned term");
+        anyhow::bail!("Your prompt contains a disallowed term.");
+    }
+    Ok(())
+}
+
+/// Whether the interaction was made by a server administrator, or by one of the users
+/// configured as administrators
+pub fn is_administrator(interaction: &dyn DiscordInteraction) -> bool {
+    let user_id = interaction.user().id;
+    Configuration::get()
+        .general
+        .admin_user_ids
+        .contains(user_id.as_u64())
+        || matches!(
+            interaction.member().and_then(|m| m.permissions),
+            Some(p) if p.administrator()
+        )
+}
+
+/// Fails unless the interaction was made by one of the users configured as administrators.
+/// Server administrators aren't enough, as the administrator commands affect every server;
+/// `action` completes "only administrators can ..."
+pub fn require_admin(interaction: &dyn DiscordInteraction, action: &str) -> anyhow::Result<()> {
+    let user_id = interaction.user().id;
+    if !Configuration::get()
+        .general
+        .admin_user_ids
+        .contains(user_id.as_u64())
+    {
+        anyhow::bail!("only administrators can {action}");
+    }
+    Ok(())
+}
+
+/// Images reserved against a user's quotas by [reserve_quota]. They're handed back when
+/// this is dropped, unless [QuotaReservation::keep] was called once they were generated.
+pub struct QuotaReservation<'a> {
+    store: &'a crate::store::Store,
+    id: Option<i64>,
+}
+impl QuotaReservation<'_> {
+    pub fn keep(mut self) {
+        self.id = None;
+    }
+
+    /// Keeps the reservation if the generation completed; cancelled generations give
+    /// their images back
+    pub fn settle(self, outcome: GenerationOutcome) {
+        if outcome == GenerationOutcome::Completed {
+            self.keep();
         }
+    }
+}
+impl Drop for QuotaReservation<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            if let Err(err) = self.store.refund_usage(id) {
+                tracing::warn!("failed to refund usage {id}: {err}");
+            }
+        }
+    }
+}
+
+/// Reserves `count` images against the user's daily and weekly generation quotas, failing
+/// if that would take them over either. Administrators are exempt, but their usage is
+/// still recorded. Nothing can be reserved while the process is shutting down.
+pub fn reserve_quota<'a>(
+    store: &'a crate::store::Store,
+    interaction: &dyn DiscordInteraction,
+    count: usize,
+) -> anyhow::Result<QuotaReservation<'a>> {
+    require_not_shutting_down()?;
+
+    let limits = &Configuration::get().limits;
+    let now = chrono::Local::now();
+    let quotas: Vec<_> = if is_administrator(interaction) {
+        vec![]
     } else {
-        prompt.to_string()
+        [
+            ("daily", 1, limits.daily_quota),
+            ("weekly", 7, limits.weekly_quota),
+        ]
+        .into_iter()
+        .filter_map(|(period, days, quota)| {
+            Some((period, now - chrono::Duration::days(days), quota?))
+        })
+        .collect()
+    };
+
+    let id = store.reserve_usage(interaction.user().id, count, &quotas)?;
+    Ok(QuotaReservation {
+        store,
+        id: Some(id),
+    })
+}
+
+/// Returns the first of the banned terms that the prompt contains. Both are compared
+/// case-insensitively as sequences of words, so terms only match on word boundaries.
+pub fn find_banned_term<'a>(prompt: &str, banned_terms: &'a [String]) -> Option<&'a str> {
+    fn words(text: &str) -> Vec<String> {
+        text.split(|c: char| !c.is_alphanumeric())
+            .filter(|w| !w.is_empty())
+            .map(|w| w.to_lowercase())
+            .collect()
+    }
+
+    let prompt = words(prompt);
+    banned_terms
+        .iter()
+        .find(|term| {
+            let term = words(term);
+            !term.is_empty() && prompt.windows(term.len()).any(|w| w == term.as_slice())
+        })
+        .map(|s| s.as_str())
+}
+
+/// Trims each comma-separated tag in the prompt and removes any repeated tags,
+/// keeping the first occurrence. Commas within brackets (e.g. `(red, blue:1.2)`)
+/// are not treated as separators.
+pub fn normalize_prompt(prompt: &str) -> String {
+    let mut tags = vec![];
+    let mut depth = 0i32;
+    let mut start = 0;
+    for (i, c) in prompt.char_indices() {
+        match c {
+            '(' | '[' | '{' | '<' => depth += 1,
+            ')' | ']' | '}' | '>' => depth -= 1,
+            ',' if depth <= 0 => {
+                tags.push(&prompt[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    tags.push(&prompt[start..]);
+
+    let mut seen = HashSet::new();
+    tags.into_iter()
+        .map(|t| t.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|t| !t.is_empty() && seen.insert(t.clone()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Appends `<lora:name:weight>` tags to the prompt for each LoRA in the comma-separated
+/// `loras` list. Each entry may specify its own weight (`name:weight`); otherwise,
+/// `default_weight` is used.
+pub fn inject_loras(prompt: &str, loras: &str, default_weight: f64) -> anyhow::Result<String> {
+    let lora_tags = loras
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|lora| {
+            let (name, weight) = match lora.rsplit_once(':') {
+                Some((name, weight)) => (
+                    name.trim(),
+                    weight
+                        .trim()
+                        .parse::<f64>()
+                        .with_context(|| format!("invalid weight for LoRA `{}`", name.trim()))?,
+                ),
+                None => (lora, default_weight),
+            };
+            if !weight.is_finite() {
+                anyhow::bail!("the weight for LoRA `{name}` must be a finite number");
+            }
+            anyhow::Ok(format!("<lora:{name}:{weight}>"))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    Ok(std::iter::once(prompt.trim())
+        .filter(|s| !s.is_empty())
+        .chain(lora_tags.iter().map(|s| s.as_str()))
+        .collect::<Vec<_>>()
+        .join(", "))
+}
+
+/// Retrieves the names of the LoRAs available to the web UI
+pub async fn fetch_loras(
+    authentication: &crate::config::Authentication,
+) -> anyhow::Result<Vec<String>> {
+    #[derive(serde::Deserialize)]
+    struct Lora {
+        name: String,
+    }
+
+    let body = sd_api_request(authentication, reqwest::Method::GET, "sdapi/v1/loras")
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let mut loras: Vec<_> = serde_json::from_str::<Vec<Lora>>(&body)?
+        .into_iter()
+        .map(|l| l.name)
+        .collect();
+    loras.sort();
+    Ok(loras)
+}
+
+/// Retrieves the progress of the current generation without its in-progress image, which
+/// the client library always requests
+pub async fn fetch_progress_without_image(
+    authentication: &crate::config::Authentication,
+) -> anyhow::Result<sd::GenerationProgress> {
+    #[derive(serde::Deserialize)]
+    struct State {
+        job_timestamp: String,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct Progress {
+        eta_relative: f32,
+        progress: f32,
+        state: Option<State>,
     }
+
+    let body = sd_api_request(
+        authentication,
+        reqwest::Method::GET,
+        "sdapi/v1/progress?skip_current_image=true",
+    )
+    .send()
+    .await?
+    .error_for_status()?
+    .text()
+    .await?;
+    let progress: Progress = serde_json::from_str(&body)?;
+    Ok(sd::GenerationProgress {
+        eta_seconds: progress.eta_relative.max(0.0),
+        progress_factor: progress.progress.clamp(0.0, 1.0),
+        current_image: None,
+        job_timestamp: progress
+            .state
+            .map(|s| chrono::NaiveDateTime::parse_from_str(&s.job_timestamp, "%Y%m%d%H%M%S"))
+            .transpose()?
+            .and_then(|dt| dt.and_local_timezone(chrono::Local).single()),
+    })
+}
+
+/// Interrupts the generation that the web UI is currently working on
+pub async fn interrupt_generation(
+    authentication: &crate::config::Authentication,
+) -> anyhow::Result<()> {
+    sd_api_request(authentication, reqwest::Method::POST, "sdapi/v1/interrupt")
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// Fetches the preview image that the web UI shows for the model in its extra networks UI,
+/// if one has been set
+pub async fn fetch_model_preview(
+    authentication: &crate::config::Authentication,
+    model: &sd::Model,
+) -> anyhow::Result<Vec<u8>> {
+    #[derive(serde::Deserialize)]
+    struct SdModel {
+        title: String,
+        filename: String,
+    }
+
+    let body = sd_api_request(authentication, reqwest::Method::GET, "sdapi/v1/sd-models")
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let filename = serde_json::from_str::<Vec<SdModel>>(&body)?
+        .into_iter()
+        .find(|m| m.title == model.title)
+        .map(|m| m.filename)
+        .with_context(|| format!("the backend doesn't know about `{}`", model.name))?;
+
+    // previews sit next to the model, with the same name and a `.preview.png` or `.png` extension
+    let stem = std::path::Path::new(&filename).with_extension("");
+    for extension in ["preview.png", "png"] {
+        let preview = format!("{}.{extension}", stem.display());
+        let response = sd_api_request(
+            authentication,
+            reqwest::Method::GET,
+            "sd_extra_networks/thumb",
+        )
+        .query(&[("filename", preview.as_str())])
+        .send()
+        .await?;
+        if !response.status().is_success() {
+            continue;
+        }
+
+        let bytes = response.bytes().await?.to_vec();
+        if image::guess_format(&bytes).is_ok() {
+            return Ok(bytes);
+        }
+    }
+
+    anyhow::bail!("`{}` doesn't have a preview image.", model.name)
+}
+
+/// Builds a request to the web UI for endpoints that the client library doesn't cover
+fn sd_api_request(
+    authentication: &crate::config::Authentication,
+    method: reqwest::Method,
+    path: &str,
+) -> reqwest::RequestBuilder {
+    let mut request = reqwest::Client::new().request(
+        method,
+        format!("{}/{path}", authentication.sd_url.trim_end_matches('/')),
+    );
+    if let Some((username, password)) = Option::zip(
+        authentication.sd_api_username.as_ref(),
+        authentication.sd_api_password.as_ref(),
+    ) {
+        request = request.basic_auth(username, Some(password));
+    }
+    request
 }
 
 fn fixup_resolution(width: u32, height: u32) -> (u32, u32) {
@@ -269,6 +1461,291 @@ mod tests {
             "my cool prompt"
         );
     }
+
+    #[test]
+    fn has_model_keyword_matches_whole_tokens() {
+        use super::has_model_keyword;
+        assert!(has_model_keyword(
+            "my cool prompt, nvinkpunk",
+            "Inkpunk v1 [nvinkpunk]"
+        ));
+        assert!(!has_model_keyword(
+            "nvinkpunkish, my cool prompt",
+            "Inkpunk v1 [nvinkpunk]"
+        ));
+        assert!(!has_model_keyword("nvinkpunk", "Inkpunk v1"));
+    }
+
+    #[test]
+    fn prepend_keyword_if_necessary_unchecked_matches_whole_tokens() {
+        use super::prepend_keyword_if_necessary_unchecked;
+        assert_eq!(
+            prepend_keyword_if_necessary_unchecked(
+                "nvinkpunk, my cool prompt",
+                "Inkpunk v1 [nvinkpunk]"
+            ),
+            "nvinkpunk, my cool prompt"
+        );
+        assert_eq!(
+            prepend_keyword_if_necessary_unchecked(
+                "nvinkpunkish, my cool prompt",
+                "Inkpunk v1 [nvinkpunk]"
+            ),
+            "nvinkpunk, nvinkpunkish, my cool prompt"
+        );
+        assert_eq!(
+            prepend_keyword_if_necessary_unchecked(
+                "my cool prompt, nvinkpunk",
+                "Inkpunk v1 [nvinkpunk]"
+            ),
+            "my cool prompt, nvinkpunk"
+        );
+        assert_eq!(
+            prepend_keyword_if_necessary_unchecked(
+                "my  cool prompt,(nvinkpunk:1.2)",
+                "Inkpunk v1 [nvinkpunk]"
+            ),
+            "my  cool prompt,(nvinkpunk:1.2)"
+        );
+        assert_eq!(
+            prepend_keyword_if_necessary_unchecked(
+                "my  cool prompt,(red:1.2)",
+                "Inkpunk v1 [nvinkpunk]"
+            ),
+            "nvinkpunk, my  cool prompt,(red:1.2)"
+        );
+    }
+
+    #[test]
+    fn normalize_prompt_removes_duplicates_and_whitespace() {
+        use super::normalize_prompt;
+        assert_eq!(
+            normalize_prompt("  a cat ,  masterpiece,a cat,, masterpiece  "),
+            "a cat, masterpiece"
+        );
+        assert_eq!(
+            normalize_prompt("a   cat,\nhigh  quality"),
+            "a cat, high quality"
+        );
+        assert_eq!(normalize_prompt(""), "");
+    }
+
+    #[test]
+    fn find_banned_term_matches_whole_words() {
+        use super::find_banned_term;
+        let banned = ["ass".to_string(), "Bad Word".to_string()];
+        assert_eq!(find_banned_term("a first class ticket", &banned), None);
+        assert_eq!(
+            find_banned_term("a donkey, (ASS:1.2)", &banned),
+            Some("ass")
+        );
+        assert_eq!(
+            find_banned_term("a very bad  word", &banned),
+            Some("Bad Word")
+        );
+        assert_eq!(find_banned_term("a bad, wordy prompt", &banned), None);
+        assert_eq!(find_banned_term("anything", &[String::new()]), None);
+    }
+
+    #[test]
+    fn filter_deepdanbooru_tags_dedupes_and_caps() {
+        use super::filter_deepdanbooru_tags;
+        let caption = "1girl, solo, long_hair, solo, smile";
+        assert_eq!(
+            filter_deepdanbooru_tags(caption, None, None),
+            "1girl, solo, long_hair, smile"
+        );
+        assert_eq!(
+            filter_deepdanbooru_tags(caption, None, Some(2)),
+            "1girl, solo"
+        );
+
+        let allowlist = ["solo", "smile"].map(String::from).into_iter().collect();
+        assert_eq!(
+            filter_deepdanbooru_tags(caption, Some(&allowlist), Some(5)),
+            "solo, smile"
+        );
+    }
+
+    #[test]
+    fn watermark_border_is_drawn_around_the_edges() {
+        use super::apply_watermark;
+        use crate::config::Watermark;
+        let watermark = Watermark {
+            enabled: true,
+            border_width: 2,
+            color: Some([255, 0, 0]),
+            ..Default::default()
+        };
+        let image = apply_watermark(image::DynamicImage::new_rgb8(8, 8), &watermark)
+            .unwrap()
+            .to_rgba8();
+        assert_eq!(image.get_pixel(0, 0).0, [255, 0, 0, 255]);
+        assert_eq!(image.get_pixel(7, 6).0, [255, 0, 0, 255]);
+        assert_eq!(image.get_pixel(4, 4).0, [0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn mean_pixel_difference_averages_over_channels() {
+        use super::mean_pixel_difference;
+        let black = image::DynamicImage::new_rgb8(2, 2);
+        let mut grey = image::RgbImage::new(2, 2);
+        grey.put_pixel(0, 0, image::Rgb([100, 100, 100]));
+        let grey = image::DynamicImage::ImageRgb8(grey);
+
+        assert_eq!(mean_pixel_difference(&black, &black).unwrap(), 0.0);
+        // three of the sixteen channels (including alpha) differ by 100
+        assert_eq!(mean_pixel_difference(&black, &grey).unwrap(), 300.0 / 16.0);
+        assert!(mean_pixel_difference(&black, &image::DynamicImage::new_rgb8(2, 3)).is_err());
+    }
+
+    #[test]
+    fn wrap_prompt_adds_affixes_once() {
+        use super::wrap_prompt;
+        let (prefix, suffix) = (Some("masterpiece"), Some("detailed "));
+        assert_eq!(
+            wrap_prompt("a cat", prefix, suffix),
+            "masterpiece, a cat, detailed"
+        );
+        assert_eq!(
+            wrap_prompt("masterpiece, a cat, detailed", prefix, suffix),
+            "masterpiece, a cat, detailed"
+        );
+        assert_eq!(wrap_prompt("", prefix, None), "masterpiece");
+        assert_eq!(wrap_prompt(" a cat ", None, Some("")), "a cat");
+        // only whole tags count as already being there
+        assert_eq!(
+            wrap_prompt("masterpieces of art, not detailed", prefix, suffix),
+            "masterpiece, masterpieces of art, not detailed, detailed"
+        );
+        assert_eq!(
+            wrap_prompt(
+                "masterpiece,best quality,a cat",
+                Some("masterpiece, best quality"),
+                None
+            ),
+            "masterpiece,best quality,a cat"
+        );
+    }
+
+    #[test]
+    fn parse_labels_normalises_and_dedups() {
+        use super::parse_labels;
+        assert_eq!(
+            parse_labels(" Cats, dogs,,cats , ").unwrap(),
+            ["cats", "dogs"]
+        );
+        assert!(parse_labels("").unwrap().is_empty());
+        assert!(parse_labels(&"a".repeat(33)).is_err());
+    }
+
+    #[test]
+    fn truncate_marks_shortened_text() {
+        use super::truncate;
+        assert_eq!(truncate("a cat", 5), "a cat");
+        assert_eq!(truncate("a cat on a mat", 5), "a ca…");
+        assert_eq!(truncate("ééé", 2), "é…");
+    }
+
+    #[test]
+    fn inject_loras_appends_weighted_tags() {
+        use super::inject_loras;
+        assert_eq!(
+            inject_loras("a cat", "foo, bar:0.5", 0.8).unwrap(),
+            "a cat, <lora:foo:0.8>, <lora:bar:0.5>"
+        );
+        assert_eq!(inject_loras("", "foo", 1.0).unwrap(), "<lora:foo:1>");
+        assert!(inject_loras("a cat", "foo:heavy", 1.0).is_err());
+        assert!(inject_loras("a cat", "foo:inf", 1.0).is_err());
+        assert!(inject_loras("a cat", "foo:NaN", 1.0).is_err());
+        assert!(inject_loras("a cat", "foo", f64::NAN).is_err());
+    }
+
+    #[test]
+    fn normalize_prompt_preserves_bracketed_groups() {
+        use super::normalize_prompt;
+        assert_eq!(
+            normalize_prompt("(red, blue:1.2), red, (red, blue:1.2), <lora:foo:0.8>"),
+            "(red, blue:1.2), red, <lora:foo:0.8>"
+        );
+    }
+
+    #[test]
+    fn embed_png_parameters_round_trips() {
+        use super::{embed_png_parameters, encode_image_to_png_bytes};
+        let bytes = encode_image_to_png_bytes(image::DynamicImage::new_rgb8(4, 4)).unwrap();
+        let parameters = "a cat\nSteps: 20, Seed: 1";
+        let embedded = embed_png_parameters(&bytes, parameters);
+
+        let reader = png::Decoder::new(embedded.as_slice()).read_info().unwrap();
+        let chunk = &reader.info().uncompressed_latin1_text[0];
+        assert_eq!(chunk.keyword, "parameters");
+        assert_eq!(chunk.text, parameters);
+        assert!(image::load_from_memory(&embedded).is_ok());
+    }
+
+    #[test]
+    fn megapixel_resolution_keeps_ratio_and_rounds() {
+        use super::megapixel_resolution;
+        assert_eq!(megapixel_resolution(1.0, 1.0, 64), (1024, 1024));
+        assert_eq!(megapixel_resolution(1.0, 16.0 / 9.0, 64), (1344, 768));
+        assert_eq!(megapixel_resolution(1.0, 2.0 / 3.0, 64), (832, 1280));
+        assert_eq!(megapixel_resolution(0.25, 1.0, 64), (512, 512));
+        assert_eq!(megapixel_resolution(0.0001, 1.0, 64), (64, 64));
+        // absurd targets saturate instead of overflowing
+        assert_eq!(megapixel_resolution(1e20, 1.0, 64), (u32::MAX, u32::MAX));
+    }
+
+    #[test]
+    fn guess_native_resolution_recognises_xl_models() {
+        use super::guess_native_resolution;
+        assert_eq!(guess_native_resolution("sd_xl_base_1.0"), 1024);
+        assert_eq!(guess_native_resolution("Juggernaut XL v9"), 1024);
+        assert_eq!(guess_native_resolution("v1-5-pruned-emaonly"), 512);
+    }
+
+    #[test]
+    fn pad_for_outpainting_masks_new_region() {
+        use super::pad_for_outpainting;
+        let image = image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(
+            4,
+            4,
+            image::Rgba([255, 0, 0, 255]),
+        ));
+
+        let (padded, mask) = pad_for_outpainting(&image, (8, 4), "left").unwrap();
+        let (padded, mask) = (padded.to_rgba8(), mask.to_luma8());
+        assert_eq!(padded.dimensions(), (8, 4));
+        assert_eq!(padded.get_pixel(0, 0), &image::Rgba([0, 0, 0, 255]));
+        assert_eq!(padded.get_pixel(4, 0), &image::Rgba([255, 0, 0, 255]));
+        assert_eq!(mask.get_pixel(3, 3), &image::Luma([255]));
+        assert_eq!(mask.get_pixel(4, 3), &image::Luma([0]));
+
+        let (_, mask) = pad_for_outpainting(&image, (8, 8), "all").unwrap();
+        let mask = mask.to_luma8();
+        assert_eq!(mask.get_pixel(1, 1), &image::Luma([255]));
+        assert_eq!(mask.get_pixel(2, 2), &image::Luma([0]));
+        assert_eq!(mask.get_pixel(6, 6), &image::Luma([255]));
+
+        assert!(pad_for_outpainting(&image, (4, 4), "left").is_err());
+    }
+
+    #[test]
+    fn cancelled_generations_give_back_their_quota() {
+        use super::{GenerationOutcome, QuotaReservation};
+        use serenity::model::id::UserId;
+
+        let store = crate::store::Store::in_memory("cancelled_generations_give_back_their_quota");
+        let quotas = [("daily", chrono::Local::now() - chrono::Duration::days(1), 2)];
+        let reserve = |count| QuotaReservation {
+            store: &store,
+            id: Some(store.reserve_usage(UserId(1), count, &quotas).unwrap()),
+        };
+
+        reserve(2).settle(GenerationOutcome::Cancelled);
+        reserve(2).settle(GenerationOutcome::Completed);
+        assert!(store.reserve_usage(UserId(1), 1, &quotas).is_err());
+    }
 }
 
 #[async_trait]
@@ -278,10 +1755,12 @@ pub trait DiscordInteraction: Send + Sync {
     async fn edit(&self, http: &Http, message: &str) -> anyhow::Result<()>;
     async fn create_or_edit(&self, http: &Http, message: &str) -> anyhow::Result<()>;
 
+    fn id(&self) -> InteractionId;
     fn channel_id(&self) -> ChannelId;
     fn guild_id(&self) -> Option<GuildId>;
     fn message(&self) -> Option<&Message>;
     fn user(&self) -> &User;
+    fn member(&self) -> Option<&Member>;
 }
 macro_rules! implement_interaction {
     ($name:ident) => {
@@ -316,6 +1795,9 @@ macro_rules! implement_interaction {
                 )
             }
 
+            fn id(&self) -> InteractionId {
+                self.id
+            }
             fn channel_id(&self) -> ChannelId {
                 self.channel_id
             }
@@ -325,6 +1807,9 @@ macro_rules! implement_interaction {
             fn user(&self) -> &User {
                 &self.user
             }
+            fn member(&self) -> Option<&Member> {
+                self.member.as_ref()
+            }
             interaction_message!($name);
         }
     };
@@ -350,17 +1835,113 @@ implement_interaction!(ApplicationCommandInteraction);
 implement_interaction!(MessageComponentInteraction);
 implement_interaction!(ModalSubmitInteraction);
 
+/// Stands in for an interaction by posting and editing a status message in a channel
+pub struct ChannelInteraction {
+    id: InteractionId,
+    channel_id: ChannelId,
+    guild_id: GuildId,
+    user: User,
+    message: Mutex<Option<Message>>,
+}
+impl ChannelInteraction {
+    pub fn new(channel_id: ChannelId, guild_id: GuildId, user: User) -> Self {
+        // real interaction IDs are snowflakes, which are far beyond anything this reaches
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        Self {
+            id: InteractionId(NEXT_ID.fetch_add(1, Ordering::SeqCst)),
+            channel_id,
+            guild_id,
+            user,
+            message: Mutex::new(None),
+        }
+    }
+}
+#[async_trait]
+impl DiscordInteraction for ChannelInteraction {
+    async fn create(&self, http: &Http, message: &str) -> anyhow::Result<()> {
+        let message = self.channel_id.say(http, message).await?;
+        *self.message.lock() = Some(message);
+        Ok(())
+    }
+    async fn get_interaction_message(&self, _http: &Http) -> anyhow::Result<Message> {
+        self.message.lock().clone().context("no status message")
+    }
+    async fn edit(&self, http: &Http, message: &str) -> anyhow::Result<()> {
+        let mut status = self.get_interaction_message(http).await?;
+        status.edit(http, |m| m.content(message)).await?;
+        *self.message.lock() = Some(status);
+        Ok(())
+    }
+    async fn create_or_edit(&self, http: &Http, message: &str) -> anyhow::Result<()> {
+        if self.message.lock().is_some() {
+            self.edit(http, message).await
+        } else {
+            self.create(http, message).await
+        }
+    }
+
+    fn id(&self) -> InteractionId {
+        self.id
+    }
+    fn channel_id(&self) -> ChannelId {
+        self.channel_id
+    }
+    fn guild_id(&self) -> Option<GuildId> {
+        Some(self.guild_id)
+    }
+    fn message(&self) -> Option<&Message> {
+        None
+    }
+    fn user(&self) -> &User {
+        &self.user
+    }
+    fn member(&self) -> Option<&Member> {
+        None
+    }
+}
+
 /// Runs the [body] and edits the interaction response if an error occurs.
+///
+/// Long errors (e.g. JSON from the backend) are cut down to a summary, with the full
+/// error attached as a text file.
 pub async fn run_and_report_error(
     interaction: &dyn DiscordInteraction,
     http: &Http,
     body: impl Future<Output = anyhow::Result<()>>,
 ) {
+    const MAX_ERROR_LENGTH: usize = 500;
+    const SUMMARY_LENGTH: usize = 200;
+
     if let Err(err) = body.await {
+        let message = err.to_string();
+        if message.chars().count() <= MAX_ERROR_LENGTH {
+            interaction
+                .create_or_edit(http, &format!("Error: {message}"))
+                .await
+                .unwrap();
+            return;
+        }
+
+        let first_line = message.lines().next().unwrap_or_default();
+        let summary: String = first_line.chars().take(SUMMARY_LENGTH).collect();
         interaction
-            .create_or_edit(http, &format!("Error: {err}"))
+            .create_or_edit(http, &format!("Error: {summary}... (full error attached)"))
             .await
             .unwrap();
+
+        let full_error = format!("{err:?}");
+        let result = async {
+            interaction
+                .get_interaction_message(http)
+                .await?
+                .edit(http, |m| m.attachment((full_error.as_bytes(), "error.txt")))
+                .await?;
+            anyhow::Ok(())
+        }
+        .await;
+        if let Err(err) = result {
+            tracing::warn!("failed to attach full error: {err}");
+        }
     }
 }
 
@@ -369,25 +1950,37 @@ macro_rules! create_modal_interaction_response {
         |r| {
             use serenity::model::prelude::component::InputTextStyle;
 
+            let negative_prompt = $generation.negative_prompt.unwrap_or_default();
+            let prompt_label = util::modal_prompt_label("Prompt", &$generation.prompt);
+            let (prompt_style, prompt_max_length, prompt) =
+                util::modal_prompt_input(&$generation.prompt);
+            let negative_prompt_label =
+                util::modal_prompt_label("Negative prompt", &negative_prompt);
+            let (negative_prompt_style, negative_prompt_max_length, negative_prompt) =
+                util::modal_prompt_input(&negative_prompt);
+
             r.kind(InteractionResponseType::Modal)
                 .interaction_response_data(|d| {
                     d.components(|c| {
                         c.create_action_row(|r| {
                             r.create_input_text(|t| {
-                                t.label("Prompt")
+                                t.label(prompt_label)
                                     .custom_id(constant::value::PROMPT)
                                     .required(true)
-                                    .style(InputTextStyle::Short)
-                                    .value($generation.prompt)
+                                    .min_length(1)
+                                    .max_length(prompt_max_length)
+                                    .style(prompt_style)
+                                    .value(prompt)
                             })
                         })
                         .create_action_row(|r| {
                             r.create_input_text(|t| {
-                                t.label("Negative prompt")
+                                t.label(negative_prompt_label)
                                     .custom_id(constant::value::NEGATIVE_PROMPT)
                                     .required(false)
-                                    .style(InputTextStyle::Short)
-                                    .value($generation.negative_prompt.unwrap_or_default())
+                                    .max_length(negative_prompt_max_length)
+                                    .style(negative_prompt_style)
+                                    .value(negative_prompt)
                             })
                         })
                         .create_action_row(|r| {
@@ -395,6 +1988,7 @@ macro_rules! create_modal_interaction_response {
                                 t.label("Seed")
                                     .custom_id(constant::value::SEED)
                                     .required(false)
+                                    .max_length(20)
                                     .style(InputTextStyle::Short)
                                     .value($generation.seed)
                             })
@@ -404,19 +1998,25 @@ macro_rules! create_modal_interaction_response {
                                 t.label("Width, height")
                                     .custom_id(constant::value::WIDTH_HEIGHT)
                                     .required(false)
+                                    .max_length(32)
                                     .style(InputTextStyle::Short)
                                     .value(format!("{}, {}", $generation.width, $generation.height))
                             })
                         })
                         .create_action_row(|r| {
                             r.create_input_text(|t| {
-                                t.label("Guidance scale, denoising strength")
-                                    .custom_id(constant::value::GUIDANCE_SCALE_DENOISING_STRENGTH)
+                                t.label("Guidance scale, denoising strength, steps")
+                                    .custom_id(
+                                        constant::value::GUIDANCE_SCALE_DENOISING_STRENGTH_STEPS,
+                                    )
                                     .required(false)
+                                    .max_length(64)
                                     .style(InputTextStyle::Short)
                                     .value(format!(
-                                        "{}, {}",
-                                        $generation.cfg_scale, $generation.denoising_strength
+                                        "{}, {}, {}",
+                                        $generation.cfg_scale,
+                                        $generation.denoising_strength,
+                                        $generation.steps
                                     ))
                             })
                         })
@@ -1,51 +1,98 @@
 use crate::{
     command,
-    config::Configuration,
-    constant, store,
+    config::{self, Configuration, DuplicateSessionAction, Tags},
+    constant, custom_id as cid, store,
     util::{self, DiscordInteraction},
 };
 
-use super::Session;
+use super::{
+    simulation::{self, Operators},
+    Session,
+};
 use anyhow::Context;
+use once_cell::sync::Lazy;
 use parking_lot::Mutex;
 use serenity::{
     http::Http,
     model::prelude::{
         command::{Command, CommandOptionType},
-        interaction::{
-            application_command::{ApplicationCommandInteraction, CommandDataOption},
-            InteractionResponseType,
-        },
-        ChannelId,
+        component::ButtonStyle,
+        interaction::application_command::{ApplicationCommandInteraction, CommandDataOption},
+        ChannelId, InteractionId, UserId,
     },
     prelude::Mentionable,
 };
 use stable_diffusion_a1111_webui_client as sd;
-use std::{collections::HashMap, fmt::Display, sync::Arc};
+use std::{collections::HashMap, sync::Arc};
 
 pub async fn register(http: &Http, models: &[sd::Model]) -> anyhow::Result<()> {
+    let limits = &Configuration::get().limits;
     Command::create_global_application_command(http, |command| {
         command
-            .name(&Configuration::get().commands.wirehead)
+            .name({
+                let commands = &Configuration::get().commands;
+                commands.full_name(&commands.wirehead)
+            })
             .description("Interact with Wirehead")
+            .dm_permission(false)
             .create_option(|o| {
                 o.kind(CommandOptionType::SubCommand)
                     .name("start")
                     .description("Start a Wirehead session (if not already running)");
 
+                // autocompleted instead of offering choices, so that a URL can be entered as well;
+                // there's no room left for a separate option
                 o.create_sub_option(|o| {
                     o.kind(CommandOptionType::String)
                         .name(constant::value::TAGS)
-                        .description("The tags to use for generation")
-                        .required(true);
+                        .description("The tag list to use for generation, or the URL of a newline-separated tag file")
+                        .required(true)
+                        .set_autocomplete(true)
+                });
+
+                o.create_sub_option(|o| {
+                    o.kind(CommandOptionType::String)
+                        .name(constant::value::EXTRA_TAGS)
+                        .description("Extra tag lists to mix in, with optional weights relative to the main list (e.g. `quality:2, styles:0.5`)")
+                });
 
-                    for tag_list_name in Configuration::get().tags().keys() {
-                        o.add_string_choice(tag_list_name, tag_list_name);
+                // crossover and mutation share one option to stay within Discord's option limit
+                o.create_sub_option(|o| {
+                    o.kind(CommandOptionType::String)
+                        .name(constant::value::OPERATORS)
+                        .description("The crossover and mutation operators used to evolve prompts (defaults to multi-point, random value)");
+
+                    for operators in Operators::all() {
+                        o.add_string_choice(operators.to_string(), operators.value());
                     }
 
                     o
                 });
 
+                o.create_sub_option(|o| {
+                    o.kind(CommandOptionType::Integer)
+                        .name(constant::value::GA_SEED)
+                        .description("The seed for the genetic algorithm, to rerun a past session (random if not set)")
+                        .min_int_value(0)
+                        .max_int_value(u32::MAX)
+                });
+
+                o.create_sub_option(|o| {
+                    o.kind(CommandOptionType::Integer)
+                        .name(constant::value::PREVIEW_STEPS)
+                        .description("The number of denoising steps for the rated previews, if not To Exilent's")
+                        .min_int_value(limits.steps_min)
+                        .max_int_value(limits.steps_max)
+                });
+
+                o.create_sub_option(|o| {
+                    o.kind(CommandOptionType::Number)
+                        .name(constant::value::PREVIEW_GUIDANCE_SCALE)
+                        .description("The scale of the guidance for the rated previews, if not To Exilent's")
+                        .min_number_value(limits.guidance_scale_min)
+                        .max_number_value(limits.guidance_scale_max)
+                });
+
                 o.create_sub_option(|o| {
                     o.kind(CommandOptionType::Boolean)
                         .name(constant::value::TO_EXILENT_ENABLED)
@@ -72,18 +119,37 @@ pub async fn register(http: &Http, models: &[sd::Model]) -> anyhow::Result<()> {
                 }).create_sub_option(|o| {
                     o.kind(CommandOptionType::String)
                         .name(constant::value::PREFIX)
-                        .description("A prefix to add to the generation prompt. (Will be joined by a comma)")
+                        .description("A prefix to add to the generation prompt. (Will be joined by the tag separator)")
                 }).create_sub_option(|o| {
                     o.kind(CommandOptionType::String)
                         .name(constant::value::SUFFIX)
-                        .description("A prefix to add to the generation prompt. (Will be joined by a comma)")
-                })
+                        .description("A suffix to add to the generation prompt. (Will be joined by the tag separator)")
+                });
+
+                command::fit_model_options(o.0.get_mut("options"));
+                o
             })
             .create_option(|o| {
                 o.kind(CommandOptionType::SubCommand)
                     .name("stop")
                     .description("Stop a Wirehead session (if running)")
+                    .create_sub_option(|o| {
+                        o.kind(CommandOptionType::Boolean)
+                            .name(constant::value::INCLUDE_PARAMETERS)
+                            .description("Whether to include the generation parameters alongside the best prompt")
+                    })
+            })
+            .create_option(|o| {
+                o.kind(CommandOptionType::SubCommand)
+                    .name("pause")
+                    .description("Pause a Wirehead session without losing its progress")
             })
+            .create_option(|o| {
+                o.kind(CommandOptionType::SubCommand)
+                    .name("resume")
+                    .description("Resume a paused Wirehead session")
+            });
+        util::localize_command(command)
     })
     .await?;
 
@@ -101,7 +167,9 @@ pub async fn wirehead(
     let subcommand = &cmd.data.options[0];
     match subcommand.name.as_str() {
         "start" => start(http, &cmd, subcommand, sessions, client, models, store).await,
-        "stop" => stop(&http, &cmd, sessions).await,
+        "stop" => stop(&http, &cmd, subcommand, sessions).await,
+        "pause" => set_paused(&http, &cmd, sessions, true).await,
+        "resume" => set_paused(&http, &cmd, sessions, false).await,
         _ => unreachable!(),
     }
 }
@@ -118,21 +186,23 @@ async fn start(
     cmd.create(&http, "Starting...").await.unwrap();
 
     util::run_and_report_error(cmd, http.clone().as_ref(), async {
-        if sessions.lock().contains_key(&cmd.channel_id) {
-            cmd.create_interaction_response(&http, |response| {
-                response
-                    .kind(InteractionResponseType::ChannelMessageWithSource)
-                    .interaction_response_data(|message| {
-                        message.content("Session already under way...")
-                    })
-            })
-            .await?;
-            return Ok(());
-        }
-
         let tag_selection = util::get_value(&subcommand.options, constant::value::TAGS)
             .and_then(util::value_to_string)
             .context("no tag selection")?;
+        let extra_tags = util::get_value(&subcommand.options, constant::value::EXTRA_TAGS)
+            .and_then(util::value_to_string);
+        let main_list = load_tag_list(cmd.user.id, &tag_selection).await?;
+        let (tags, tag_weights) = combine_tag_lists(&main_list, extra_tags.as_deref())?;
+        let operators = util::get_value(&subcommand.options, constant::value::OPERATORS)
+            .and_then(util::value_to_string)
+            .map(|v| Operators::try_from(v.as_str()))
+            .transpose()?
+            .unwrap_or_default();
+        let ga_seed = util::get_value(&subcommand.options, constant::value::GA_SEED)
+            .and_then(util::value_to_int)
+            .map(u64::try_from)
+            .transpose()?
+            .unwrap_or_else(simulation::random_seed);
 
         let hide_prompt = util::get_value(&subcommand.options, constant::value::HIDE_PROMPT)
             .and_then(util::value_to_bool)
@@ -158,10 +228,13 @@ async fn start(
             .and_then(util::value_to_string);
         let suffix = util::get_value(&subcommand.options, constant::value::SUFFIX)
             .and_then(util::value_to_string);
+        for affix in prefix.iter().chain(&suffix) {
+            util::check_banned_terms(cmd.user.id, affix)?;
+        }
 
         let parameters = command::GenerationParameters::load(
             cmd.user.id,
-            cmd.guild_id.context("no guild id")?,
+            util::require_guild(cmd.guild_id)?,
             &subcommand.options,
             store,
             models,
@@ -170,85 +243,285 @@ async fn start(
         )
         .await?;
 
-        fn display<T: Display>(value: &Option<T>) -> Option<&dyn Display> {
-            value.as_ref().map(|s| s as &dyn Display)
+        let preview_steps = util::get_value(&subcommand.options, constant::value::PREVIEW_STEPS)
+            .and_then(util::value_to_int)
+            .map(|v| v as u32);
+        let preview_guidance_scale =
+            util::get_value(&subcommand.options, constant::value::PREVIEW_GUIDANCE_SCALE)
+                .and_then(util::value_to_number)
+                .map(|v| v as f32);
+        let preview_parameters = super::GenerationParameters::with_preview_overrides(
+            &parameters,
+            (preview_steps, preview_guidance_scale),
+        );
+
+        // the generation parameters that are reported are To Exilent's, so the preview's
+        // are shown alongside them when they differ
+        let full = parameters.base_generation();
+        let settings = [
+            ("Tags", Some(tag_selection.clone())),
+            ("Extra tags", extra_tags.clone()),
+            ("Operators", Some(operators.to_string())),
+            ("Prefix", prefix.clone()),
+            ("Suffix", suffix.clone()),
+            (
+                "Preview steps",
+                preview_steps
+                    .map(|s| format!("{s} (To Exilent: {})", full.steps.unwrap_or_default())),
+            ),
+            (
+                "Preview guidance scale",
+                preview_guidance_scale
+                    .map(|s| format!("{s} (To Exilent: {})", full.cfg_scale.unwrap_or_default())),
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| Some((key, value?)))
+        .collect();
+
+        start_session(
+            http,
+            cmd,
+            sessions,
+            client,
+            (to_exilent_channel_id, hide_prompt),
+            super::GenerationParameters {
+                parameters,
+                preview_parameters,
+                tags,
+                tag_weights,
+                operators,
+                ga_seed,
+                prefix,
+                suffix,
+                tag_separator: Configuration::get().wirehead.tag_separator.clone(),
+                empty_prompt_fallback: Configuration::get().wirehead.empty_prompt_fallback.clone(),
+                global_prefix: Configuration::get().general.prompt_prefix.clone(),
+                global_suffix: Configuration::get().general.prompt_suffix.clone(),
+            },
+            settings,
+        )
+        .await
+    })
+    .await;
+}
+
+/// A session start that was refused because its channel already had a session, kept so
+/// that it can be started from the button offered in its place
+struct PendingStart {
+    interaction_id: InteractionId,
+    user_id: UserId,
+    to_exilent_channel_id: Option<ChannelId>,
+    hide_prompt: bool,
+    generation_parameters: super::GenerationParameters,
+    settings: Vec<(&'static str, String)>,
+}
+
+/// The most recently refused start in each channel; older ones can no longer be restarted
+static PENDING_STARTS: Lazy<Mutex<HashMap<ChannelId, PendingStart>>> = Lazy::new(Default::default);
+
+/// Reports the settings of the session to the interaction, and then starts the session
+/// in the interaction's channel. If the channel already has a session, it's either
+/// restarted or left alone, depending on `wirehead.on_duplicate_start`.
+pub(super) async fn start_session(
+    http: Arc<Http>,
+    interaction: &dyn DiscordInteraction,
+    sessions: &Mutex<HashMap<ChannelId, Session>>,
+    client: Arc<sd::Client>,
+    (to_exilent_channel_id, hide_prompt): (Option<ChannelId>, bool),
+    generation_parameters: super::GenerationParameters,
+    settings: Vec<(&'static str, String)>,
+) -> anyhow::Result<()> {
+    let channel_id = interaction.channel_id();
+    match Configuration::get().wirehead.on_duplicate_start {
+        DuplicateSessionAction::Refuse => {
+            let existing_link = sessions
+                .lock()
+                .get(&channel_id)
+                .map(|s| s.original_message_link.clone());
+            if let Some(link) = existing_link {
+                PENDING_STARTS.lock().insert(
+                    channel_id,
+                    PendingStart {
+                        interaction_id: interaction.id(),
+                        user_id: interaction.user().id,
+                        to_exilent_channel_id,
+                        hide_prompt,
+                        generation_parameters,
+                        settings,
+                    },
+                );
+                interaction
+                    .get_interaction_message(&http)
+                    .await?
+                    .edit(&http, |m| {
+                        m.content(format!(
+                            "A Wirehead session ({link}) is already under way in this channel."
+                        ))
+                        .components(|c| {
+                            c.create_action_row(|r| {
+                                r.create_button(|b| {
+                                    b.label("Stop and restart")
+                                        .style(ButtonStyle::Danger)
+                                        .custom_id(
+                                            cid::Task::RestartWirehead.to_id(interaction.id()),
+                                        )
+                                })
+                            })
+                        })
+                    })
+                    .await?;
+                return Ok(());
+            }
+        }
+        DuplicateSessionAction::Restart => {
+            let existing = sessions.lock().remove(&channel_id);
+            if let Some(session) = existing {
+                stop_for_restart(&http, channel_id, session).await?;
+            }
         }
+    }
 
-        let base = parameters.base_generation();
-        let (image_url, resize_mode) = parameters.image_params().unzip();
-        cmd.edit(
+    interaction
+        .edit(
             &http,
             &format!(
                 "Starting with the following settings:\n{}",
-                [
-                    ("Tags", Some(&tag_selection as &dyn Display)),
-                    ("Prefix", display(&prefix)),
-                    ("Suffix", display(&suffix)),
-                    ("Image URL", display(&image_url)),
-                    ("Negative prompt", display(&base.negative_prompt)),
-                    ("Seed", display(&base.seed)),
-                    ("Count", display(&base.batch_count)),
-                    ("Width", display(&base.width)),
-                    ("Height", display(&base.height)),
-                    ("Guidance scale", display(&base.cfg_scale)),
-                    ("Denoising strength", display(&base.denoising_strength)),
-                    ("Resize mode", display(&resize_mode)),
-                    ("Steps", display(&base.steps)),
-                    ("Tiling", display(&base.tiling)),
-                    ("Restore faces", display(&base.restore_faces)),
-                    ("Sampler", display(&base.sampler)),
-                    (
-                        "Model",
-                        display(&base.model.as_ref().map(|m| m.name.as_str()))
-                    ),
-                    (
-                        "To Exilent channel",
-                        display(&to_exilent_channel_id.map(|c| c.mention()))
+                settings
+                    .into_iter()
+                    .chain([("GA seed", generation_parameters.ga_seed.to_string())])
+                    .chain(generation_parameters.parameters.settings())
+                    .chain(
+                        to_exilent_channel_id
+                            .map(|c| ("To Exilent channel", c.mention().to_string()))
                     )
-                ]
-                .into_iter()
-                .filter_map(|(key, value)| Some((key, value?)))
-                .map(|(key, value)| format!("- *{key}*: {value}"))
-                .collect::<Vec<_>>()
-                .join("\n")
+                    .map(|(key, value)| format!("- *{key}*: {value}"))
+                    .collect::<Vec<_>>()
+                    .join("\n")
             ),
         )
         .await?;
 
-        let tags = Configuration::get()
+    let original_message_link = interaction.get_interaction_message(&http).await?.link();
+    sessions.lock().insert(
+        channel_id,
+        super::Session::new(
+            http,
+            channel_id,
+            to_exilent_channel_id,
+            client,
+            hide_prompt,
+            generation_parameters,
+            original_message_link,
+        )?,
+    );
+    Ok(())
+}
+
+/// Fetches the tag list with the given name from the config, or, if it's a URL, downloads
+/// and parses the tag file it points to, within the configured limits.
+pub(super) async fn load_tag_list(user_id: UserId, tag_selection: &str) -> anyhow::Result<Tags> {
+    let config = Configuration::get();
+    let tag_selection = tag_selection.trim();
+    if !(tag_selection.starts_with("http://") || tag_selection.starts_with("https://")) {
+        return config
             .tags()
-            .get(&tag_selection)
-            .context("invalid tag selection")?
-            .iter()
+            .get(tag_selection)
             .cloned()
-            .collect();
-
-        let original_message_link = cmd.get_interaction_response(&http).await?.link();
-        sessions.lock().insert(
-            cmd.channel_id,
-            super::Session::new(
-                http,
-                cmd.channel_id,
-                to_exilent_channel_id,
-                client.clone(),
-                hide_prompt,
-                super::GenerationParameters {
-                    parameters,
-                    tags,
-                    prefix,
-                    suffix,
-                },
-                original_message_link,
-            )?,
+            .with_context(|| format!("invalid tag selection `{tag_selection}`"));
+    }
+
+    let limits = &config.limits;
+    let text = util::download_text(tag_selection, limits.tags_url_size_max).await?;
+    let tags = config::parse_tags(text.as_bytes());
+    if tags.is_empty() {
+        anyhow::bail!("<{tag_selection}> does not contain any tags.");
+    }
+    if tags.len() > limits.tags_url_count_max {
+        anyhow::bail!(
+            "<{tag_selection}> contains {} tags, more than the limit of {}.",
+            tags.len(),
+            limits.tags_url_count_max
         );
-        Ok(())
-    })
-    .await;
+    }
+    // unlike the configured tag lists, these come from users, so they're held to the same
+    // standard as prompts
+    for tag in &tags {
+        if let Some(term) = util::find_banned_term(tag, &config.general.banned_terms) {
+            tracing::warn!(user = %user_id, term, "tag file contained a banned term");
+            anyhow::bail!("<{tag_selection}> contains a disallowed term.");
+        }
+    }
+    Ok(tags)
+}
+
+/// Combines the main tag list with any weighted extra lists (`name[:weight], ...`).
+///
+/// Each list's weight is spread across its tags, so a list's weight determines how
+/// often it is drawn from regardless of its size. Tags that appear in several lists
+/// accumulate the weight of each.
+pub(super) fn combine_tag_lists(
+    main_list: &Tags,
+    extra_tags: Option<&str>,
+) -> anyhow::Result<(Vec<String>, Vec<f64>)> {
+    let extra_lists = extra_tags
+        .into_iter()
+        .flat_map(|s| s.split(','))
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|entry| match entry.split_once(':') {
+            Some((name, weight)) => {
+                let weight: f64 = weight
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("invalid weight for tag list `{}`", name.trim()))?;
+                if !weight.is_finite() || weight <= 0.0 {
+                    anyhow::bail!("the weight for tag list `{}` must be positive", name.trim());
+                }
+                Ok((name.trim(), weight))
+            }
+            None => Ok((entry, 1.0)),
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut tags: Vec<String> = vec![];
+    let mut tag_weights: Vec<f64> = vec![];
+    let mut tag_indices: HashMap<&str, usize> = HashMap::new();
+    let config = Configuration::get();
+    let extra_lists = extra_lists
+        .into_iter()
+        .map(|(name, weight)| {
+            let list = config
+                .tags()
+                .get(name)
+                .with_context(|| format!("invalid tag selection `{name}`"))?;
+            Ok((list, weight))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    for (list, weight) in std::iter::once((main_list, 1.0)).chain(extra_lists) {
+        let tag_weight = weight / list.len() as f64;
+        for tag in list {
+            match tag_indices.get(tag.as_str()) {
+                Some(&index) => tag_weights[index] += tag_weight,
+                None => {
+                    tag_indices.insert(tag, tags.len());
+                    tags.push(tag.clone());
+                    tag_weights.push(tag_weight);
+                }
+            }
+        }
+    }
+    if tags.is_empty() {
+        anyhow::bail!("the selected tag lists don't contain any tags");
+    }
+
+    Ok((tags, tag_weights))
 }
 
 async fn stop(
     http: &Http,
     cmd: &ApplicationCommandInteraction,
+    subcommand: &CommandDataOption,
     sessions: &Mutex<HashMap<ChannelId, Session>>,
 ) {
     cmd.create(http, "Attemping to stop Wirehead session...")
@@ -272,9 +545,141 @@ async fn stop(
             ),
         )
         .await?;
+
+        let include_parameters =
+            util::get_value(&subcommand.options, constant::value::INCLUDE_PARAMETERS)
+                .and_then(util::value_to_bool)
+                .unwrap_or(false);
+        post_best_prompt(http, cmd.channel_id, &session, include_parameters).await?;
         std::mem::drop(session);
 
         Ok(())
     })
     .await;
 }
+
+/// Stops the channel's session, if it's still running, and starts the refused start that
+/// the interaction with the given ID was turned away with.
+pub(super) async fn start_pending(
+    http: Arc<Http>,
+    interaction: &dyn DiscordInteraction,
+    sessions: &Mutex<HashMap<ChannelId, Session>>,
+    client: Arc<sd::Client>,
+    id: InteractionId,
+) -> anyhow::Result<()> {
+    let channel_id = interaction.channel_id();
+    let pending = {
+        let mut pending_starts = PENDING_STARTS.lock();
+        let Some(pending) = pending_starts
+            .get(&channel_id)
+            .filter(|p| p.interaction_id == id)
+        else {
+            anyhow::bail!(
+                "This start has already been restarted, or a newer one has taken its place."
+            );
+        };
+        if pending.user_id != interaction.user().id {
+            anyhow::bail!("Only the user who tried to start this session can restart it.");
+        }
+        pending_starts.remove(&channel_id).unwrap()
+    };
+
+    let existing = sessions.lock().remove(&channel_id);
+    if let Some(session) = existing {
+        stop_for_restart(&http, channel_id, session).await?;
+    }
+
+    start_session(
+        http,
+        interaction,
+        sessions,
+        client,
+        (pending.to_exilent_channel_id, pending.hide_prompt),
+        pending.generation_parameters,
+        pending.settings,
+    )
+    .await
+}
+
+/// Stops a session that's making way for a new one, letting its channel know
+async fn stop_for_restart(
+    http: &Http,
+    channel_id: ChannelId,
+    session: Session,
+) -> anyhow::Result<()> {
+    session.shutdown();
+    channel_id
+        .send_message(http, |m| {
+            m.content(format!(
+                "Wirehead session ({}) stopped to make way for a new one.",
+                session.original_message_link
+            ))
+        })
+        .await?;
+    post_best_prompt(http, channel_id, &session, false).await
+}
+
+/// Posts the session's best prompt separately, so that it can be easily copied
+async fn post_best_prompt(
+    http: &Http,
+    channel_id: ChannelId,
+    session: &Session,
+    include_parameters: bool,
+) -> anyhow::Result<()> {
+    let Some(prompt) = session.best_prompt() else {
+        return Ok(());
+    };
+    let parameters = if include_parameters {
+        session
+            .generation_parameters
+            .parameters
+            .settings()
+            .into_iter()
+            .map(|(key, value)| format!("\n- *{key}*: {value}"))
+            .collect()
+    } else {
+        String::new()
+    };
+
+    channel_id
+        .send_message(http, |m| {
+            m.content(format!("**Best prompt**:\n```\n{prompt}\n```{parameters}"))
+        })
+        .await?;
+    Ok(())
+}
+
+async fn set_paused(
+    http: &Http,
+    cmd: &ApplicationCommandInteraction,
+    sessions: &Mutex<HashMap<ChannelId, Session>>,
+    paused: bool,
+) {
+    cmd.create(http, "Updating Wirehead session...")
+        .await
+        .unwrap();
+
+    util::run_and_report_error(cmd, http, async {
+        let was_paused = sessions
+            .lock()
+            .get(&cmd.channel_id)
+            .map(|session| session.set_paused(paused))
+            .context("No Wirehead session running!")?;
+
+        cmd.edit(
+            http,
+            match (was_paused, paused) {
+                (false, true) => {
+                    "Wirehead session paused. Use resume to pick up where it left off."
+                }
+                (true, true) => "Wirehead session is already paused.",
+                (true, false) => "Wirehead session resumed.",
+                (false, false) => "Wirehead session is already running.",
+            },
+        )
+        .await?;
+
+        Ok(())
+    })
+    .await;
+}
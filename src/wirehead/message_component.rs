@@ -1,20 +1,30 @@
-use super::{simulation::AsPhenotype, GenerationParameters, Session, TextGenome};
+use super::{
+    simulation::{self, AsPhenotype},
+    GenerationParameters, Session, TextGenome,
+};
 use crate::{
-    custom_id as cid, exilent, store,
+    command,
+    config::{ButtonProfiles, Configuration},
+    constant, custom_id as cid, exilent, store,
     util::{self, DiscordInteraction},
 };
+use anyhow::Context;
 use parking_lot::Mutex;
 use serenity::{
     http::Http,
     model::prelude::{
         component::ButtonStyle,
         interaction::{message_component::MessageComponentInteraction, InteractionResponseType},
-        ChannelId,
+        ChannelId, InteractionId,
     },
     prelude::Mentionable,
 };
 use stable_diffusion_a1111_webui_client as sd;
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc};
+
+/// How much more likely the interrogated tags, taken together, are to be drawn for the
+/// initial population than the rest of the tag list
+const INTERROGATION_TAG_WEIGHT_FACTOR: f64 = 4.0;
 
 pub async fn to_exilent(
     sessions: &Mutex<HashMap<ChannelId, Session>>,
@@ -23,7 +33,8 @@ pub async fn to_exilent(
     http: &Http,
     mci: MessageComponentInteraction,
     genome: TextGenome,
-    seed: i64,
+    // `None` to generate with a fresh seed
+    seed: Option<i64>,
 ) {
     mci.create(http, "Generating Wirehead image with Exilent...")
         .await
@@ -32,13 +43,19 @@ pub async fn to_exilent(
         if !sessions.lock().contains_key(&mci.channel_id) {
             anyhow::bail!("There is no active Wirehead session.");
         };
-
         let (to_exilent_channel_id, parameters) = sessions
             .lock()
             .get(&mci.channel_id)
             .map(|s| (s.to_exilent_channel_id, s.generation_parameters.clone()))
             .unwrap();
 
+        let parameters = parameters.to_exilent_request(&genome, seed);
+        let reservation = util::reserve_quota(
+            store,
+            &mci,
+            parameters.base_generation().batch_count.unwrap_or(1) as usize,
+        )?;
+
         mci.edit(
             http,
             &format!(
@@ -50,31 +67,33 @@ pub async fn to_exilent(
         )
         .await?;
 
-        let GenerationParameters {
-            mut parameters,
-            tags,
-            prefix,
-            suffix,
-        } = parameters;
-
-        let (prompt, negative_prompt) = {
-            let base = parameters.base_generation_mut();
-            base.prompt = genome.as_text(&tags, prefix.as_deref(), suffix.as_deref());
-            base.seed = Some(seed);
-
-            (base.prompt.clone(), base.negative_prompt.clone())
+        let hires_denoising_strength = parameters.hires_denoising_strength();
+        let (prompt, negative_prompt, eta, denoising_strength) = {
+            let base = parameters.base_generation();
+            (
+                base.prompt.clone(),
+                base.negative_prompt.clone(),
+                base.eta,
+                base.denoising_strength,
+            )
         };
 
-        exilent::issuer::generation_task(
+        let outcome = exilent::issuer::generation_task(
             (client, models),
-            tokio::task::spawn(parameters.generate(client)),
+            util::spawn_generation(parameters.generate(client)),
             store,
             http,
-            (&mci, to_exilent_channel_id),
+            (&mci, to_exilent_channel_id, ButtonProfiles::WIREHEAD),
             (&prompt, negative_prompt.as_deref()),
-            None,
+            (
+                None,
+                eta,
+                (denoising_strength, hires_denoising_strength),
+                None,
+            ),
         )
         .await?;
+        reservation.settle(outcome);
 
         Ok(())
     })
@@ -106,7 +125,9 @@ pub async fn rate(
                         cid::WireheadValue::Zero => 50,
                         cid::WireheadValue::Positive1 => 75,
                         cid::WireheadValue::Positive2 => 100,
-                        cid::WireheadValue::ToExilent => unreachable!(),
+                        cid::WireheadValue::ToExilent | cid::WireheadValue::ToExilentNewSeed => {
+                            unreachable!()
+                        }
                     },
                 );
                 (
@@ -128,7 +149,8 @@ pub async fn rate(
                                 genome.as_text(
                                     &params.tags,
                                     params.prefix.as_deref(),
-                                    params.suffix.as_deref()
+                                    params.suffix.as_deref(),
+                                    &Configuration::get().wirehead.tag_separator,
                                 )
                             )
                         } else {
@@ -148,6 +170,14 @@ pub async fn rate(
                                     .label("To Exilent")
                                     .style(ButtonStyle::Primary)
                                 })
+                                .create_button(|b| {
+                                    b.custom_id(
+                                        cid::WireheadValue::ToExilentNewSeed
+                                            .to_id(genome.clone(), custom_id.seed),
+                                    )
+                                    .label("To Exilent (new seed)")
+                                    .style(ButtonStyle::Secondary)
+                                })
                             })
                         } else {
                             c
@@ -161,3 +191,219 @@ pub async fn rate(
     })
     .await;
 }
+
+pub async fn interrogation_to_wirehead(
+    store: &store::Store,
+    http: &Http,
+    mci: &MessageComponentInteraction,
+    id: i64,
+) {
+    util::run_and_report_error(mci, http, async {
+        store
+            .get_interrogation(id)?
+            .context("no interrogation found")?;
+
+        let mut tag_list_names: Vec<_> = Configuration::get().tags().keys().cloned().collect();
+        tag_list_names.sort();
+
+        mci.create_interaction_response(http, |r| {
+            r.kind(InteractionResponseType::ChannelMessageWithSource)
+                .interaction_response_data(|d| {
+                    d.ephemeral(true)
+                        .content("Which tag list should the interrogated tags be matched against?")
+                        .components(|c| {
+                            c.create_action_row(|row| {
+                                row.create_select_menu(|m| {
+                                    m.custom_id(
+                                        cid::Interrogation::SendToWireheadResponse.to_id(id),
+                                    )
+                                    .placeholder("Tag list")
+                                    .options(|o| {
+                                        for name in tag_list_names
+                                            .iter()
+                                            .take(constant::value::SELECT_MENU_OPTION_COUNT_MAX)
+                                        {
+                                            o.create_option(|opt| opt.label(name).value(name));
+                                        }
+                                        o
+                                    })
+                                })
+                            })
+                        })
+                })
+        })
+        .await?;
+
+        Ok(())
+    })
+    .await;
+}
+
+pub async fn interrogation_to_wirehead_response(
+    http: Arc<Http>,
+    mci: MessageComponentInteraction,
+    sessions: &Mutex<HashMap<ChannelId, Session>>,
+    (client, models): (Arc<sd::Client>, &[sd::Model]),
+    store: &store::Store,
+    id: i64,
+) {
+    util::run_and_report_error(&mci, http.clone().as_ref(), async {
+        mci.create_interaction_response(&http, |r| {
+            r.kind(InteractionResponseType::UpdateMessage)
+                .interaction_response_data(|d| {
+                    d.content("Starting Wirehead in this channel...")
+                        .components(|c| c)
+                })
+        })
+        .await?;
+
+        // the tag list was picked on an ephemeral message, which other users can't open,
+        // so the session is announced in a message of its own
+        let interaction = util::ChannelInteraction::new(
+            mci.channel_id,
+            util::require_guild(mci.guild_id)?,
+            mci.user.clone(),
+        );
+        interaction
+            .create(&http, "Starting Wirehead from interrogation...")
+            .await?;
+        util::run_and_report_error(
+            &interaction,
+            http.clone().as_ref(),
+            start_from_interrogation(
+                http.clone(),
+                &interaction,
+                &mci,
+                sessions,
+                (client, models),
+                store,
+                id,
+            ),
+        )
+        .await;
+
+        Ok(())
+    })
+    .await;
+}
+
+async fn start_from_interrogation(
+    http: Arc<Http>,
+    interaction: &util::ChannelInteraction,
+    mci: &MessageComponentInteraction,
+    sessions: &Mutex<HashMap<ChannelId, Session>>,
+    (client, models): (Arc<sd::Client>, &[sd::Model]),
+    store: &store::Store,
+    id: i64,
+) -> anyhow::Result<()> {
+    let tag_selection = mci
+        .data
+        .values
+        .first()
+        .context("no tag list selected")?
+        .clone();
+    let interrogation = store
+        .get_interrogation(id)?
+        .context("no interrogation found")?;
+
+    // DeepDanbooru may report tags with spaces where the tag lists use underscores
+    // (or vice versa), so compare them in a normalised form
+    fn normalize_tag(tag: &str) -> String {
+        tag.trim().to_lowercase().replace('_', " ")
+    }
+
+    let (tags, mut tag_weights) = super::command::combine_tag_lists(
+        &super::command::load_tag_list(mci.user.id, &tag_selection).await?,
+        None,
+    )?;
+    let tag_indices: HashMap<String, usize> = tags
+        .iter()
+        .enumerate()
+        .map(|(idx, tag)| (normalize_tag(tag), idx))
+        .collect();
+
+    let (matched, unmatched): (Vec<_>, Vec<_>) = interrogation
+        .result
+        .split(',')
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .partition(|t| tag_indices.contains_key(&normalize_tag(t)));
+    if matched.is_empty() {
+        anyhow::bail!("None of the interrogated tags are in the `{tag_selection}` tag list.");
+    }
+
+    let matched_weight =
+        tag_weights.iter().sum::<f64>() * INTERROGATION_TAG_WEIGHT_FACTOR / matched.len() as f64;
+    for tag in &matched {
+        tag_weights[tag_indices[&normalize_tag(tag)]] += matched_weight;
+    }
+
+    let parameters = command::GenerationParameters::load(
+        mci.user.id,
+        util::require_guild(mci.guild_id)?,
+        &[],
+        store,
+        models,
+        false,
+        false,
+    )
+    .await?;
+
+    let settings = [
+        ("Tags", Some(tag_selection.clone())),
+        ("Seeded with", Some(matched.join(", "))),
+        (
+            "Unmatched tags",
+            Some(unmatched.join(", ")).filter(|s| !s.is_empty()),
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(key, value)| Some((key, value?)))
+    .collect();
+
+    super::command::start_session(
+        http,
+        interaction,
+        sessions,
+        client,
+        (Some(mci.channel_id), false),
+        GenerationParameters {
+            preview_parameters: parameters.clone(),
+            parameters,
+            tags,
+            tag_weights,
+            operators: Default::default(),
+            ga_seed: simulation::random_seed(),
+            prefix: None,
+            suffix: None,
+            tag_separator: Configuration::get().wirehead.tag_separator.clone(),
+            empty_prompt_fallback: Configuration::get().wirehead.empty_prompt_fallback.clone(),
+            global_prefix: Configuration::get().general.prompt_prefix.clone(),
+            global_suffix: Configuration::get().general.prompt_suffix.clone(),
+        },
+        settings,
+    )
+    .await
+}
+
+/// Stops the channel's session and starts the one that was refused in its place
+pub async fn restart(
+    http: Arc<Http>,
+    mci: &MessageComponentInteraction,
+    sessions: &Mutex<HashMap<ChannelId, Session>>,
+    client: Arc<sd::Client>,
+    id: InteractionId,
+) {
+    util::run_and_report_error(mci, http.clone().as_ref(), async {
+        mci.create(&http, "Restarting Wirehead...").await?;
+        super::command::start_pending(http.clone(), mci, sessions, client, id).await?;
+
+        // the refused start can't be restarted twice
+        mci.message
+            .clone()
+            .edit(&http, |m| m.components(|c| c))
+            .await?;
+        Ok(())
+    })
+    .await;
+}
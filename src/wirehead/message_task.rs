@@ -1,9 +1,10 @@
 use super::{
-    simulation::{AsPhenotype, FitnessStore, TextGenome},
+    simulation::{FitnessStore, TextGenome},
     GenerationParameters,
 };
 use crate::{
-    command::GenerationParameters as CommandGenerationParameters, constant, custom_id as cid, util,
+    command::GenerationParameters as CommandGenerationParameters, config::Configuration, constant,
+    custom_id as cid, util,
 };
 use serenity::{
     http::Http,
@@ -16,6 +17,7 @@ use std::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
+    time::{Duration, Instant},
 };
 
 pub struct Parameters {
@@ -23,6 +25,7 @@ pub struct Parameters {
     pub channel_id: ChannelId,
 
     pub shutdown: Arc<AtomicBool>,
+    pub paused: Arc<AtomicBool>,
 
     pub fitness_store: Arc<FitnessStore>,
     pub result_rx: flume::Receiver<TextGenome>,
@@ -39,6 +42,7 @@ pub async fn task(parameters: Parameters) -> anyhow::Result<()> {
         http,
         channel_id,
         shutdown,
+        paused,
         fitness_store,
         result_rx,
         to_exilent_enabled,
@@ -47,17 +51,24 @@ pub async fn task(parameters: Parameters) -> anyhow::Result<()> {
         generation_parameters,
     } = parameters;
 
-    let GenerationParameters {
-        parameters,
-        tags,
-        prefix,
-        suffix,
-    } = generation_parameters;
+    // the best result changes often early on, so it's only posted when its fitness has
+    // improved on the last posted one, and no more often than the configured interval
+    let mut last_posted_best: Option<(usize, Instant)> = None;
+    let mut pending_best: Option<(TextGenome, usize)> = None;
 
     loop {
         if shutdown.load(Ordering::SeqCst) {
             break;
         }
+        // the simulation waits on ratings, so not issuing any new candidates is enough
+        // to pause it without losing the population
+        if paused.load(Ordering::SeqCst) {
+            tokio::time::sleep(std::time::Duration::from_millis(
+                Configuration::get().wirehead.generation_cooldown_ms,
+            ))
+            .await;
+            continue;
+        }
 
         fn to_attachment_type(value: &(Vec<u8>, Option<i64>)) -> AttachmentType {
             AttachmentType::Bytes {
@@ -66,9 +77,27 @@ pub async fn task(parameters: Parameters) -> anyhow::Result<()> {
             }
         }
 
-        if let Ok(genome) = result_rx.try_recv() {
-            let prompt = genome.as_text(&tags, prefix.as_deref(), suffix.as_deref());
-            let images = generate(&client, parameters.clone(), prompt.clone()).await?;
+        if let Some(genome) = result_rx.try_iter().last() {
+            let fitness = fitness_store.score(&genome).unwrap_or_default();
+            let min_delta = Configuration::get().wirehead.best_result_min_fitness_delta;
+            let improved =
+                !matches!(last_posted_best, Some((last, _)) if fitness <= last + min_delta);
+            if improved && !matches!(pending_best, Some((_, pending)) if fitness <= pending) {
+                pending_best = Some((genome, fitness));
+            }
+        }
+
+        let interval =
+            Duration::from_secs(Configuration::get().wirehead.best_result_interval_seconds);
+        let best = match last_posted_best {
+            Some((_, at)) if at.elapsed() < interval => None,
+            _ => pending_best.take(),
+        };
+        if let Some((genome, fitness)) = best {
+            last_posted_best = Some((fitness, Instant::now()));
+            let request = generation_parameters.preview_request(&genome);
+            let prompt = request.base_generation().prompt.clone();
+            let images = generate(&client, request).await?;
 
             channel_id
                 .send_files(http.as_ref(), images.iter().map(to_attachment_type), |m| {
@@ -86,11 +115,20 @@ pub async fn task(parameters: Parameters) -> anyhow::Result<()> {
                                 Some(seed) => c.create_action_row(|row| {
                                     row.create_button(|b| {
                                         b.custom_id(
-                                            cid::WireheadValue::ToExilent.to_id(genome, seed),
+                                            cid::WireheadValue::ToExilent
+                                                .to_id(genome.clone(), seed),
                                         )
                                         .label("To Exilent")
                                         .style(ButtonStyle::Primary)
                                     })
+                                    .create_button(|b| {
+                                        b.custom_id(
+                                            cid::WireheadValue::ToExilentNewSeed
+                                                .to_id(genome, seed),
+                                        )
+                                        .label("To Exilent (new seed)")
+                                        .style(ButtonStyle::Secondary)
+                                    })
                                 }),
                                 None => c,
                             }
@@ -105,12 +143,9 @@ pub async fn task(parameters: Parameters) -> anyhow::Result<()> {
         let pending_requests = std::mem::take(&mut *fitness_store.pending_requests.lock());
 
         for genome in pending_requests {
-            let images = generate(
-                &client,
-                parameters.clone(),
-                genome.as_text(&tags, prefix.as_deref(), suffix.as_deref()),
-            )
-            .await?;
+            let request = generation_parameters.preview_request(&genome);
+            let prompt = request.base_generation().prompt.clone();
+            let images = generate(&client, request).await?;
 
             channel_id
                 .send_files(http.as_ref(), images.iter().map(to_attachment_type), |m| {
@@ -156,10 +191,7 @@ pub async fn task(parameters: Parameters) -> anyhow::Result<()> {
                     }
 
                     if !hide_prompt {
-                        m.content(format!(
-                            "`{}`",
-                            genome.as_text(&tags, prefix.as_deref(), suffix.as_deref())
-                        ));
+                        m.content(format!("`{prompt}`"));
                     }
 
                     m
@@ -167,7 +199,10 @@ pub async fn task(parameters: Parameters) -> anyhow::Result<()> {
                 .await?;
         }
 
-        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        tokio::time::sleep(std::time::Duration::from_millis(
+            Configuration::get().wirehead.generation_cooldown_ms,
+        ))
+        .await;
     }
 
     Ok(())
@@ -176,11 +211,9 @@ pub async fn task(parameters: Parameters) -> anyhow::Result<()> {
 /// always guaranteed to return at least one image if it suceeds
 async fn generate(
     client: &sd::Client,
-    mut parameters: CommandGenerationParameters,
-    prompt: String,
+    parameters: CommandGenerationParameters,
 ) -> anyhow::Result<Vec<(Vec<u8>, Option<i64>)>> {
-    parameters.base_generation_mut().prompt = prompt;
-    let result = parameters.generate(client).await;
+    let result = util::with_generation_permit(parameters.generate(client)).await;
 
     Ok(match result {
         Ok(result) => result
@@ -189,11 +222,14 @@ async fn generate(
             .zip(result.info.seeds.into_iter().map(Some))
             .collect(),
         Err(err) => {
-            println!("generation failed: {err:?}");
+            tracing::warn!("generation failed: {err:?}");
             vec![(
-                util::encode_image_to_png_bytes(image::open(
-                    constant::resource::generation_failed_path(),
-                )?)?,
+                util::run_image_task(|| {
+                    util::encode_image_to_png_bytes(image::open(
+                        constant::resource::generation_failed_path(),
+                    )?)
+                })
+                .await?,
                 None,
             )]
         }
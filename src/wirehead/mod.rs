@@ -1,10 +1,14 @@
-use self::simulation::{FitnessStore, TextGenome};
-use crate::command::GenerationParameters as CommandGenerationParameters;
+use self::simulation::{AsPhenotype, FitnessStore, Operators, TextGenome};
+use crate::{command::GenerationParameters as CommandGenerationParameters, util};
+use parking_lot::Mutex;
 use serenity::{http::Http, model::prelude::ChannelId};
 use stable_diffusion_a1111_webui_client as sd;
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
 };
 
 pub mod command;
@@ -14,10 +18,83 @@ pub mod simulation;
 
 #[derive(Clone)]
 pub struct GenerationParameters {
+    /// The parameters used for To Exilent
     parameters: CommandGenerationParameters,
+    /// The parameters used for the previews that are rated, which may use fewer steps
+    preview_parameters: CommandGenerationParameters,
     tags: Vec<String>,
+    /// The relative likelihood of each tag being drawn for the initial population
+    tag_weights: Vec<f64>,
+    /// The genetic operators used to evolve the prompts
+    operators: Operators,
+    /// The seed of the genetic algorithm's random number generator
+    ga_seed: u64,
     prefix: Option<String>,
     suffix: Option<String>,
+    /// The separator the tags are joined with, as of when the session started
+    tag_separator: String,
+    /// The prompt used in place of an empty one, as of when the session started
+    empty_prompt_fallback: String,
+    /// The configured prefix and suffix of every prompt, as of when the session started
+    global_prefix: Option<String>,
+    global_suffix: Option<String>,
+}
+
+impl GenerationParameters {
+    /// Lowers the steps and guidance scale of the parameters for the rated previews
+    fn with_preview_overrides(
+        parameters: &CommandGenerationParameters,
+        (steps, guidance_scale): (Option<u32>, Option<f32>),
+    ) -> CommandGenerationParameters {
+        let mut preview_parameters = parameters.clone();
+        let base = preview_parameters.base_generation_mut();
+        base.steps = steps.or(base.steps);
+        base.cfg_scale = guidance_scale.or(base.cfg_scale);
+        preview_parameters
+    }
+
+    /// The prompt that the genome produces, with the session's prefix and suffix inside
+    /// the configured ones
+    fn prompt_for(&self, genome: &TextGenome) -> String {
+        let prompt = genome.as_text(
+            &self.tags,
+            self.prefix.as_deref(),
+            self.suffix.as_deref(),
+            &self.tag_separator,
+        );
+        let prompt = if prompt.trim().is_empty() {
+            self.empty_prompt_fallback.clone()
+        } else {
+            prompt
+        };
+        util::wrap_prompt(
+            &prompt,
+            self.global_prefix.as_deref(),
+            self.global_suffix.as_deref(),
+        )
+    }
+
+    /// The request for a preview of the genome to be rated
+    fn preview_request(&self, genome: &TextGenome) -> CommandGenerationParameters {
+        let mut parameters = self.preview_parameters.clone();
+        parameters.base_generation_mut().prompt = self.prompt_for(genome);
+        parameters
+    }
+
+    /// The request for sending the genome To Exilent, with `None` for a fresh seed. Other
+    /// than the seed, this only differs from the preview in the steps and guidance scale
+    /// that were lowered for previews, so that the delivered image matches the rated one.
+    fn to_exilent_request(
+        &self,
+        genome: &TextGenome,
+        seed: Option<i64>,
+    ) -> CommandGenerationParameters {
+        let mut parameters = self.parameters.clone();
+        let base = parameters.base_generation_mut();
+        base.prompt = self.prompt_for(genome);
+        base.seed = seed;
+        parameters
+    }
 }
 
 pub struct Session {
@@ -25,6 +102,7 @@ pub struct Session {
     _message_task: tokio::task::JoinHandle<anyhow::Result<()>>,
     fitness_store: Arc<FitnessStore>,
     shutdown: Arc<AtomicBool>,
+    paused: Arc<AtomicBool>,
     hide_prompt: bool,
     generation_parameters: GenerationParameters,
     to_exilent_channel_id: Option<ChannelId>,
@@ -41,6 +119,7 @@ impl Session {
         original_message_link: String,
     ) -> anyhow::Result<Self> {
         let shutdown = Arc::new(AtomicBool::new(false));
+        let paused = Arc::new(AtomicBool::new(false));
         let fitness_store = Arc::new(FitnessStore::new(shutdown.clone()));
 
         let (result_tx, result_rx) = flume::unbounded();
@@ -48,14 +127,26 @@ impl Session {
         let simulation_thread = std::thread::spawn({
             let fitness_store = fitness_store.clone();
             let shutdown = shutdown.clone();
-            let tags = generation_parameters.tags.clone();
-            move || simulation::thread(fitness_store, shutdown, tags, result_tx)
+            let tag_weights = generation_parameters.tag_weights.clone();
+            let operators = generation_parameters.operators;
+            let ga_seed = generation_parameters.ga_seed;
+            move || {
+                simulation::thread(
+                    fitness_store,
+                    shutdown,
+                    tag_weights,
+                    operators,
+                    ga_seed,
+                    result_tx,
+                )
+            }
         });
 
         let message_task = tokio::task::spawn(message_task::task(message_task::Parameters {
             http,
             channel_id,
             shutdown: shutdown.clone(),
+            paused: paused.clone(),
             fitness_store: fitness_store.clone(),
             result_rx,
             to_exilent_enabled: to_exilent_channel_id.is_some(),
@@ -69,6 +160,7 @@ impl Session {
             _message_task: message_task,
             fitness_store,
             shutdown,
+            paused,
             hide_prompt,
             generation_parameters,
             to_exilent_channel_id,
@@ -80,7 +172,151 @@ impl Session {
         self.fitness_store.rate(genome, fitness)
     }
 
+    /// The highest-rated prompt so far, if any have been rated
+    pub fn best_prompt(&self) -> Option<String> {
+        let (genome, _) = self.fitness_store.best()?;
+        Some(self.generation_parameters.prompt_for(&genome))
+    }
+
+    /// Sets whether new candidates are issued; returns whether the session was paused before
+    pub fn set_paused(&self, paused: bool) -> bool {
+        self.paused.swap(paused, Ordering::SeqCst)
+    }
+
     pub fn shutdown(&self) {
         self.shutdown.store(true, Ordering::SeqCst);
     }
 }
+
+/// Stops every running session, letting each channel know along with its best prompt so far
+pub async fn shutdown_all(http: &Http, sessions: &Mutex<HashMap<ChannelId, Session>>) {
+    let sessions = std::mem::take(&mut *sessions.lock());
+    for (channel_id, session) in sessions {
+        session.shutdown();
+
+        let best_prompt = session
+            .best_prompt()
+            .map(|prompt| format!("\n**Best prompt**:\n```\n{prompt}\n```"))
+            .unwrap_or_default();
+        if let Err(err) = channel_id
+            .send_message(http, |m| {
+                m.content(format!(
+                    "Wirehead session ({}) stopped as the bot is shutting down.{best_prompt}",
+                    session.original_message_link
+                ))
+            })
+            .await
+        {
+            tracing::warn!("failed to notify {channel_id} of Wirehead shutdown: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generation_parameters(preview: (Option<u32>, Option<f32>)) -> GenerationParameters {
+        let parameters = CommandGenerationParameters::Text(
+            sd::TextToImageGenerationRequest {
+                base: sd::BaseGenerationRequest {
+                    prompt: String::new(),
+                    negative_prompt: Some("blurry".to_string()),
+                    seed: Some(-1),
+                    width: Some(768),
+                    height: Some(512),
+                    cfg_scale: Some(7.0),
+                    steps: Some(30),
+                    sampler: Some(sd::Sampler::DpmPP2MKarras),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            None,
+        );
+        GenerationParameters {
+            preview_parameters: GenerationParameters::with_preview_overrides(&parameters, preview),
+            parameters,
+            tags: vec!["cat".to_string(), "hat".to_string()],
+            tag_weights: vec![1.0, 1.0],
+            operators: Default::default(),
+            ga_seed: 0,
+            prefix: Some("photo".to_string()),
+            suffix: None,
+            tag_separator: ", ".to_string(),
+            empty_prompt_fallback: "an image".to_string(),
+            global_prefix: None,
+            global_suffix: None,
+        }
+    }
+
+    #[test]
+    fn empty_genome_uses_fallback_prompt() {
+        let genome = TextGenome::new();
+        let mut parameters = generation_parameters((None, None));
+        assert_eq!(parameters.prompt_for(&genome), "photo");
+
+        parameters.prefix = None;
+        assert_eq!(parameters.prompt_for(&genome), "an image");
+        assert_eq!(
+            parameters.preview_request(&genome).base_generation().prompt,
+            "an image"
+        );
+    }
+
+    #[test]
+    fn global_affixes_wrap_the_session_prompt() {
+        let genome: TextGenome = [0].into_iter().collect();
+        let mut parameters = generation_parameters((None, None));
+        parameters.global_prefix = Some("masterpiece".to_string());
+        parameters.global_suffix = Some("detailed".to_string());
+
+        let expected = "masterpiece, photo, cat, detailed";
+        assert_eq!(
+            parameters.preview_request(&genome).base_generation().prompt,
+            expected
+        );
+        assert_eq!(
+            parameters
+                .to_exilent_request(&genome, None)
+                .base_generation()
+                .prompt,
+            expected
+        );
+    }
+
+    #[test]
+    fn to_exilent_request_matches_preview() {
+        let genome: TextGenome = [0, 1].into_iter().collect();
+        let parameters = generation_parameters((None, None));
+
+        let preview = parameters.preview_request(&genome);
+        let mut to_exilent = parameters.to_exilent_request(&genome, Some(1234));
+        assert_eq!(to_exilent.base_generation().seed, Some(1234));
+        to_exilent.base_generation_mut().seed = preview.base_generation().seed;
+        assert_eq!(
+            format!("{:?}", preview.base_generation()),
+            format!("{:?}", to_exilent.base_generation())
+        );
+    }
+
+    #[test]
+    fn preview_overrides_only_change_steps_and_guidance_scale() {
+        let genome: TextGenome = [1, 0].into_iter().collect();
+        let parameters = generation_parameters((Some(10), Some(5.0)));
+
+        let mut preview = parameters.preview_request(&genome);
+        let to_exilent = parameters.to_exilent_request(&genome, Some(-1));
+        {
+            let base = preview.base_generation();
+            assert_eq!((base.steps, base.cfg_scale), (Some(10), Some(5.0)));
+        }
+        let base = preview.base_generation_mut();
+        base.steps = Some(30);
+        base.cfg_scale = Some(7.0);
+        assert_eq!(
+            format!("{:?}", preview.base_generation()),
+            format!("{:?}", to_exilent.base_generation())
+        );
+    }
+}
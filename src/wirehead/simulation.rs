@@ -1,12 +1,17 @@
 use genevo::{
     operator::prelude::*,
-    population::ValueEncodedGenomeBuilder,
+    population::GenomeBuilder,
     prelude::*,
     simulation::State,
     termination::{StopFlag, Termination},
 };
 use once_cell::sync::Lazy;
 use parking_lot::Mutex;
+use rand::{
+    distributions::{Distribution, WeightedIndex},
+    rngs::StdRng,
+    Rng, SeedableRng,
+};
 use smallvec::SmallVec;
 use std::{
     collections::{HashMap, HashSet},
@@ -35,24 +40,167 @@ const TIME_BETWEEN_BLOCK_CHECKS: u64 = 100;
 
 /// How do the genes of the genotype show up in the phenotype
 pub trait AsPhenotype {
-    fn as_text(&self, tags: &[String], prefix: Option<&str>, suffix: Option<&str>) -> Text;
+    fn as_text(
+        &self,
+        tags: &[String],
+        prefix: Option<&str>,
+        suffix: Option<&str>,
+        separator: &str,
+    ) -> Text;
 }
 
 impl AsPhenotype for TextGenome {
-    fn as_text(&self, tags: &[String], prefix: Option<&str>, suffix: Option<&str>) -> Text {
+    fn as_text(
+        &self,
+        tags: &[String],
+        prefix: Option<&str>,
+        suffix: Option<&str>,
+        separator: &str,
+    ) -> Text {
         prefix
             .into_iter()
             .chain(self.iter().map(|i| tags[*i as usize].as_str()))
             .chain(suffix)
             .collect::<Vec<_>>()
-            .join(", ")
+            .join(separator)
+    }
+}
+
+/// How the genes of two parents are recombined into their children
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Crossover {
+    SinglePoint,
+    #[default]
+    MultiPoint,
+    Uniform,
+}
+impl Crossover {
+    pub const VALUES: &[Crossover] = &[Self::SinglePoint, Self::MultiPoint, Self::Uniform];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::SinglePoint => "single_point",
+            Self::MultiPoint => "multi_point",
+            Self::Uniform => "uniform",
+        }
+    }
+}
+impl TryFrom<&str> for Crossover {
+    type Error = ();
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Self::VALUES
+            .iter()
+            .copied()
+            .find(|v| v.as_str() == value)
+            .ok_or(())
+    }
+}
+
+/// How the genes of a child are randomly altered
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Mutation {
+    #[default]
+    RandomValue,
+    Swap,
+}
+impl Mutation {
+    pub const VALUES: &[Mutation] = &[Self::RandomValue, Self::Swap];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::RandomValue => "random_value",
+            Self::Swap => "swap",
+        }
+    }
+}
+impl TryFrom<&str> for Mutation {
+    type Error = ();
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Self::VALUES
+            .iter()
+            .copied()
+            .find(|v| v.as_str() == value)
+            .ok_or(())
+    }
+}
+
+/// The genetic operators used to evolve the population
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct Operators {
+    pub crossover: Crossover,
+    pub mutation: Mutation,
+}
+impl Operators {
+    /// Every combination of crossover and mutation
+    pub fn all() -> impl Iterator<Item = Operators> {
+        Crossover::VALUES.iter().flat_map(|&crossover| {
+            Mutation::VALUES.iter().map(move |&mutation| Operators {
+                crossover,
+                mutation,
+            })
+        })
+    }
+
+    /// The value used to identify this combination in commands (`crossover/mutation`)
+    pub fn value(&self) -> String {
+        format!("{}/{}", self.crossover.as_str(), self.mutation.as_str())
+    }
+}
+impl std::fmt::Display for Operators {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} crossover, {} mutation",
+            self.crossover.as_str(),
+            self.mutation.as_str()
+        )
     }
 }
+impl TryFrom<&str> for Operators {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let (crossover, mutation) = value
+            .split_once('/')
+            .ok_or_else(|| anyhow::anyhow!("invalid genetic operators `{value}`"))?;
+        Ok(Self {
+            crossover: Crossover::try_from(crossover)
+                .map_err(|_| anyhow::anyhow!("invalid crossover `{crossover}`"))?,
+            mutation: Mutation::try_from(mutation)
+                .map_err(|_| anyhow::anyhow!("invalid mutation `{mutation}`"))?,
+        })
+    }
+}
+
+/// Builds genomes by drawing each gene according to the weight of its tag
+struct WeightedGenomeBuilder {
+    distribution: WeightedIndex<f64>,
+}
+impl GenomeBuilder<TextGenome> for WeightedGenomeBuilder {
+    fn build_genome<R>(&self, _index: usize, rng: &mut R) -> TextGenome
+    where
+        R: Rng + Sized,
+    {
+        (0..TARGET_LEN)
+            .map(|_| self.distribution.sample(rng) as u16)
+            .collect()
+    }
+}
+
+/// Picks a seed for a session's genetic algorithm. It's kept within the range of Discord's
+/// integer options so that it can be passed back in to rerun the session.
+pub fn random_seed() -> u64 {
+    rand::random::<u32>().into()
+}
 
 pub fn thread(
     fitness_store: Arc<FitnessStore>,
     shutdown: Arc<AtomicBool>,
-    tags: Vec<String>,
+    tag_weights: Vec<f64>,
+    operators: Operators,
+    seed: u64,
     result_tx: flume::Sender<TextGenome>,
 ) -> anyhow::Result<()> {
     struct NeverTerminate;
@@ -63,61 +211,87 @@ pub fn thread(
     }
 
     let min_value = 0;
-    let max_value = u16::try_from(tags.len())?;
+    let max_value = u16::try_from(tag_weights.len())?;
+
+    // both the initial population and the simulation draw from the seed, so that the same
+    // ratings produce the same evolution
+    let seed: genevo::random::Seed = StdRng::seed_from_u64(seed).gen();
 
     let initial_population: Population<TextGenome> = build_population()
-        .with_genome_builder(ValueEncodedGenomeBuilder::new(
-            TARGET_LEN, min_value, max_value,
-        ))
+        .with_genome_builder(WeightedGenomeBuilder {
+            distribution: WeightedIndex::new(&tag_weights)?,
+        })
         .of_size(*POPULATION_SIZE)
-        .uniform_at_random();
+        .using_seed(seed);
 
-    let mut simulator = simulate(
-        genetic_algorithm()
-            .with_evaluation(FitnessCalc {
-                store: fitness_store.clone(),
-            })
-            .with_selection(MaximizeSelector::new(
-                *SELECTION_RATIO,
-                *NUM_INDIVIDUALS_PER_PARENTS,
-            ))
-            .with_crossover(MultiPointCrossBreeder::new(*NUM_CROSSOVER_POINTS))
-            .with_mutation(RandomValueMutator::new(
-                *MUTATION_RATE,
-                min_value,
-                max_value,
-            ))
-            .with_reinsertion(ElitistReinserter::new(
-                FitnessCalc {
-                    store: fitness_store,
-                },
-                true,
-                *REINSERTION_RATIO,
-            ))
-            .with_initial_population(initial_population)
-            .build(),
-    )
-    .until(NeverTerminate)
-    .build();
-
-    loop {
-        let result = simulator.step();
-        if shutdown.load(Ordering::SeqCst) {
-            break;
-        }
+    // Each combination of operators produces a different algorithm type, so the
+    // simulation is built and run separately for each of them
+    macro_rules! run {
+        ($crossover:expr, $mutation:expr) => {{
+            let mut simulator = simulate(
+                genetic_algorithm()
+                    .with_evaluation(FitnessCalc {
+                        store: fitness_store.clone(),
+                    })
+                    .with_selection(MaximizeSelector::new(
+                        *SELECTION_RATIO,
+                        *NUM_INDIVIDUALS_PER_PARENTS,
+                    ))
+                    .with_crossover($crossover)
+                    .with_mutation($mutation)
+                    .with_reinsertion(ElitistReinserter::new(
+                        FitnessCalc {
+                            store: fitness_store,
+                        },
+                        true,
+                        *REINSERTION_RATIO,
+                    ))
+                    .with_initial_population(initial_population)
+                    .build(),
+            )
+            .until(NeverTerminate)
+            .build_with_seed(seed);
 
-        match result {
-            Ok(SimResult::Intermediate(step)) => {
-                result_tx.send(step.result.best_solution.solution.genome.clone())?;
-            }
-            Ok(SimResult::Final(..)) => {
-                break;
-            }
-            Err(error) => {
-                println!("{error}");
-                break;
+            loop {
+                let result = simulator.step();
+                if shutdown.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                match result {
+                    Ok(SimResult::Intermediate(step)) => {
+                        result_tx.send(step.result.best_solution.solution.genome.clone())?;
+                    }
+                    Ok(SimResult::Final(..)) => {
+                        break;
+                    }
+                    Err(error) => {
+                        tracing::error!("simulation failed: {error}");
+                        break;
+                    }
+                }
             }
+        }};
+    }
+
+    let random_value = || RandomValueMutator::new(*MUTATION_RATE, min_value, max_value);
+    let swap = || SwapOrderMutator::new(*MUTATION_RATE);
+    match (operators.crossover, operators.mutation) {
+        (Crossover::SinglePoint, Mutation::RandomValue) => {
+            run!(SinglePointCrossBreeder::new(), random_value())
+        }
+        (Crossover::SinglePoint, Mutation::Swap) => run!(SinglePointCrossBreeder::new(), swap()),
+        (Crossover::MultiPoint, Mutation::RandomValue) => run!(
+            MultiPointCrossBreeder::new(*NUM_CROSSOVER_POINTS),
+            random_value()
+        ),
+        (Crossover::MultiPoint, Mutation::Swap) => {
+            run!(MultiPointCrossBreeder::new(*NUM_CROSSOVER_POINTS), swap())
+        }
+        (Crossover::Uniform, Mutation::RandomValue) => {
+            run!(UniformCrossBreeder::new(), random_value())
         }
+        (Crossover::Uniform, Mutation::Swap) => run!(UniformCrossBreeder::new(), swap()),
     }
 
     Ok(())
@@ -148,6 +322,26 @@ impl FitnessStore {
         self.store.lock().insert(genome, Score::Ready(fitness));
     }
 
+    /// The rating of the genome, if it has been rated
+    pub fn score(&self, genome: &TextGenome) -> Option<usize> {
+        match self.store.lock().get(genome) {
+            Some(Score::Ready(score)) => Some(*score),
+            _ => None,
+        }
+    }
+
+    /// The highest-rated genome so far, if any have been rated
+    pub fn best(&self) -> Option<(TextGenome, usize)> {
+        self.store
+            .lock()
+            .iter()
+            .filter_map(|(genome, score)| match score {
+                Score::Ready(score) => Some((genome.clone(), *score)),
+                Score::Requested => None,
+            })
+            .max_by_key(|(_, score)| *score)
+    }
+
     fn block_on_result(&self, genome: &TextGenome) -> usize {
         loop {
             if self.shutdown.load(Ordering::SeqCst) {